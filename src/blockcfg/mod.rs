@@ -26,12 +26,14 @@ custom_error! {pub Block0Malformed
     NoDiscrimination = "missing `discrimination' value in the block0",
     NoSlotDuration = "missing `slot_duration' value in the block0",
     NoSlotsPerEpoch = "missing `slots_per_epoch' value in the block0",
+    NoEpochStabilityDepth = "missing `epoch_stability_depth' value in the block0",
 }
 
 pub trait Block0DataSource {
     fn slot_duration(&self) -> Result<Duration, Block0Error>;
     fn slots_per_epoch(&self) -> Result<u32, Block0Error>;
     fn start_time(&self) -> Result<SystemTime, Block0Error>;
+    fn epoch_stability_depth(&self) -> Result<u32, Block0Error>;
 }
 
 impl Block0DataSource for Block {
@@ -61,6 +63,15 @@ impl Block0DataSource for Block {
         }
         Err(Block0Malformed::NoStartTime.into())
     }
+
+    fn epoch_stability_depth(&self) -> Result<u32, Block0Error> {
+        for config in initial(self)?.iter() {
+            if let ConfigParam::EpochStabilityDepth(depth) = config {
+                return Ok(*depth);
+            }
+        }
+        Err(Block0Malformed::NoEpochStabilityDepth.into())
+    }
 }
 
 fn initial(block: &Block) -> Result<&ConfigParams, Block0Malformed> {
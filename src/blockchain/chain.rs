@@ -1,6 +1,11 @@
 use crate::{
-    blockcfg::{Block, Epoch, Header, HeaderHash, Ledger, Multiverse},
-    blockchain::{Branch, Tip, TipGetError, TipReplaceError},
+    blockcfg::{Block, BlockDate, Epoch, Header, HeaderHash, Ledger, Multiverse},
+    blockchain::{
+        fork_choice::{ForkChoice, TipSelection},
+        snapshot::{Snapshot, Snapshots},
+        unconnected::{BlockOrigin, Insertion, UnconnectedBlocks, UnconnectedBlocksLimits},
+        Branch, Tip, TipGetError, TipReplaceError,
+    },
     leadership::{EpochParameters, Leadership, Leaderships},
     start_up::NodeStorage,
     utils::borrow::Borrow,
@@ -11,10 +16,15 @@ use chain_impl_mockchain::{
     ledger, multiverse,
 };
 use chain_storage::{error as storage, store::BlockInfo};
-use chain_time::{SlotDuration, TimeFrame, Timeline};
+use chain_time::{
+    era::{EpochPosition, EpochSlotOffset, TimeEra},
+    Slot, SlotDuration, TimeFrame, Timeline,
+};
 use slog::Logger;
-use std::collections::BTreeMap;
-use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::{
+    sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard},
+    time::{Duration, SystemTime},
+};
 use tokio::sync::mpsc;
 
 pub struct Blockchain {
@@ -28,16 +38,29 @@ pub struct Blockchain {
     /// the Tip of the blockchain. This is update as the consensus goes
     pub tip: Tip,
 
+    /// the competing branches and the rules to select the tip among them
+    pub fork_choice: ForkChoice,
+
     pub time_frame: TimeFrame,
 
+    /// how far in the future a block may be dated before being
+    /// rejected, to accommodate the peers whose clock is ahead of ours
+    pub clock_skew_tolerance: Duration,
+
+    /// how many blocks behind the tip a tip candidate may be before
+    /// being rejected
+    pub stability_depth: u32,
+
     pub epoch_event: mpsc::Sender<EpochParameters>,
 
-    /// Incoming blocks whose parent does not exist yet. Sorted by
-    /// parent hash to allow quick look up of the children of a
-    /// parent.
-    ///
-    /// FIXME: need some way to GC unconnected blocks after a while.
-    pub unconnected_blocks: BTreeMap<HeaderHash, BTreeMap<HeaderHash, Block>>,
+    /// Incoming blocks whose parent does not exist yet.
+    pub unconnected_blocks: UnconnectedBlocks,
+
+    /// the ledger snapshots, `None` if the blockchain is only stored
+    /// in memory
+    pub snapshots: Option<Snapshots>,
+
+    logger: Logger,
 }
 
 #[derive(Clone)]
@@ -83,12 +106,17 @@ custom_error! {pub LoadError
     Storage{source: storage::Error} = "Error in the blockchain storage: {source}",
     Ledger{source: ledger::Error} = "Invalid blockchain state: {source}",
     Block0 { source: crate::blockcfg::Block0Error } = "Initial setting of the blockchain are invalid",
+    Tip { source: TipGetError } = "Cannot access the tip of the restored blockchain",
 }
 
 impl Blockchain {
     pub fn load(
         block_0: Block,
         mut storage: NodeStorage,
+        snapshots: Option<Snapshots>,
+        clock_skew_tolerance: Duration,
+        unconnected_blocks: UnconnectedBlocksLimits,
+        stability_depth: Option<u32>,
         epoch_event: mpsc::Sender<EpochParameters>,
         logger: &Logger,
     ) -> Result<Self, LoadError> {
@@ -97,6 +125,7 @@ impl Blockchain {
 
         let start_time = block_0.start_time()?;
         let slot_duration = block_0.slot_duration()?;
+        let epoch_stability_depth = block_0.epoch_stability_depth()?;
 
         let time_frame = TimeFrame::new(
             Timeline::new(start_time),
@@ -107,23 +136,53 @@ impl Blockchain {
             if let Some(tip_hash) = storage.get_tag(LOCAL_BLOCKCHAIN_TIP_TAG)? {
                 info!(logger, "restoring state at tip {}", tip_hash);
 
-                let mut tip = None;
-
-                let block_0_id = block_0.id(); // TODO: get this from the parameter
-                let (block_0, _block_0_info) = storage.get_block(&block_0_id)?;
-                let mut state = Ledger::new(block_0_id, block_0.messages())?;
+                let snapshot = match snapshots {
+                    None => None,
+                    Some(ref snapshots) => snapshots
+                        .load_latest(&storage, &tip_hash)
+                        .unwrap_or_else(|err| {
+                            warn!(logger, "cannot load the ledger snapshots: {}", err);
+                            None
+                        }),
+                };
+
+                let (from_hash, mut state, mut leaderships) = match snapshot {
+                    Some(snapshot) => {
+                        info!(
+                            logger,
+                            "restoring state from snapshot {}", snapshot.block_hash
+                        );
+                        let (block, _) = storage.get_block(&snapshot.block_hash)?;
+                        let leadership =
+                            Leadership::new(block.date().epoch, &snapshot.leadership_ledger);
+                        let leaderships = Leaderships::new(&block.header, leadership);
+                        (snapshot.block_hash, snapshot.ledger, leaderships)
+                    }
+                    None => {
+                        let block_0_id = block_0.id(); // TODO: get this from the parameter
+                        let (block_0, _block_0_info) = storage.get_block(&block_0_id)?;
+                        info!(logger, "restoring state from block0 {}", block_0_id);
+                        let state = Ledger::new(block_0_id, block_0.messages())?;
+                        let initial_leadership = Leadership::new(block_0.date().epoch, &state);
+                        let leaderships = Leaderships::new(&block_0.header, initial_leadership);
+                        (block_0_id, state, leaderships)
+                    }
+                };
 
-                let mut epoch = block_0.date().epoch;
-                let initial_leadership = Leadership::new(epoch, &state);
-                let mut leaderships = Leaderships::new(&block_0.header, initial_leadership);
+                let (from_block, _) = storage.get_block(&from_hash)?;
+                let mut epoch = from_block.date().epoch;
+                let mut tip = Tip::new(Branch::new(
+                    multiverse.add(from_hash, state.clone()),
+                    from_block.chain_length(),
+                    state.clone(),
+                ));
 
-                // FIXME: should restore from serialized chain state once we have it.
-                info!(logger, "restoring state from block0 {}", block_0_id);
-                for info in storage.iterate_range(&block_0_id, &tip_hash)? {
+                for info in storage.iterate_range(&from_hash, &tip_hash)? {
                     let info = info?;
                     let parameters = state.get_ledger_parameters();
                     let block = &storage.get_block(&info.block_hash)?.0;
                     let block_header = &block.header;
+                    let parent_state = state.clone();
                     state = state.apply_block(
                         &parameters,
                         block.messages(),
@@ -132,7 +191,13 @@ impl Blockchain {
                     let gc_root = multiverse.add(info.block_hash.clone(), state.clone());
                     if block_header.date().epoch > epoch {
                         epoch = block_header.date().epoch;
-                        let leadership = Leadership::new(block_header.date().epoch, &state);
+                        // the leadership of an epoch is built from the
+                        // ledger of the last block of the previous epoch,
+                        // as `process_block` does when the node is running
+                        // and as the snapshots store it: building it from
+                        // the ledger of the epoch's first block would give
+                        // a restarted node another leader schedule.
+                        let leadership = Leadership::new(block_header.date().epoch, &parent_state);
                         let _gc_root = leaderships.add(
                             block_header.date().epoch,
                             block_header.chain_length(),
@@ -140,14 +205,14 @@ impl Blockchain {
                             leadership,
                         );
                     }
-                    tip = Some(Tip::new(Branch::new(
+                    tip = Tip::new(Branch::new(
                         gc_root,
                         block_header.chain_length(),
                         state.clone(),
-                    )));
+                    ));
                 }
 
-                (tip.unwrap(), leaderships)
+                (tip, leaderships)
             } else {
                 let state = Ledger::new(block_0.id(), block_0.messages())?;
                 storage.put_block(&block_0)?;
@@ -161,14 +226,24 @@ impl Blockchain {
 
         multiverse.gc();
 
+        // the header triage and the fork choice use the same depth, so
+        // a header is accepted if and only if its block can become the tip
+        let stability_depth = stability_depth.unwrap_or(epoch_stability_depth);
+        let fork_choice = ForkChoice::new(stability_depth, tip.branch()?);
+
         Ok(Blockchain {
             storage: Arc::new(RwLock::new(storage)),
             multiverse,
             leaderships,
             tip,
-            unconnected_blocks: BTreeMap::default(),
+            fork_choice,
+            unconnected_blocks: UnconnectedBlocks::new(unconnected_blocks),
+            snapshots,
             epoch_event,
             time_frame,
+            clock_skew_tolerance,
+            stability_depth,
+            logger: logger.clone(),
         })
     }
 
@@ -213,10 +288,13 @@ impl Blockchain {
         self.storage.write().unwrap().put_block(block)
     }
 
-    pub fn put_tip(&mut self, branch: Branch, block: &Block) -> Result<(), HandleBlockError> {
-        let mut storage = self.storage.write().unwrap();
-        storage.put_block(block)?;
-        storage.put_tag(LOCAL_BLOCKCHAIN_TIP_TAG, &block.id())?;
+    /// set the given branch as the new tip, the branch's head block
+    /// must already be in the storage.
+    pub fn put_tip(&mut self, branch: Branch) -> Result<(), HandleBlockError> {
+        self.storage
+            .write()
+            .unwrap()
+            .put_tag(LOCAL_BLOCKCHAIN_TIP_TAG, &branch.hash())?;
         self.tip.replace_with(branch)?;
         Ok(())
     }
@@ -228,7 +306,30 @@ impl Blockchain {
         self.storage.read().unwrap().get_block(hash)
     }
 
-    fn block_exists(&self, block_hash: &HeaderHash) -> Result<bool, storage::Error> {
+    /// list of block hashes of the main chain, from the tip backward
+    /// with exponentially increasing gaps, down to the block0.
+    ///
+    /// These are the checkpoints given to a peer when requesting a chain:
+    /// the peer can find the most recent block we have in common.
+    pub fn get_checkpoints(&self) -> Result<Vec<HeaderHash>, storage::Error> {
+        let tip = self.get_tip().unwrap();
+        let storage = self.storage.read().unwrap();
+        let (_, tip_info) = storage.get_block(&tip)?;
+
+        let mut checkpoints = vec![tip];
+        let mut distance = 1;
+        while distance < tip_info.depth {
+            checkpoints.push(storage.get_nth_ancestor(&tip, distance)?.block_hash);
+            distance *= 2;
+        }
+        if tip_info.depth > 0 {
+            // the block0
+            checkpoints.push(storage.get_nth_ancestor(&tip, tip_info.depth)?.block_hash);
+        }
+        Ok(checkpoints)
+    }
+
+    pub fn block_exists(&self, block_hash: &HeaderHash) -> Result<bool, storage::Error> {
         // TODO: we assume as an invariant that if a block exists on
         // disk, its ancestors exist on disk as well. Need to make
         // sure that this invariant is preserved everywhere
@@ -255,6 +356,24 @@ impl Blockchain {
             .or_else(|| self.build_leadership(epoch, parent_hash).map(Borrow::Owned))
     }
 
+    /// the epoch of the current wall clock time.
+    ///
+    /// The era is taken from the leadership of the given epoch,
+    /// returns `None` if the leadership is not known or if the current
+    /// time is not within the blockchain's time frame.
+    pub fn current_epoch(&self, leadership_epoch: Epoch) -> Option<Epoch> {
+        let leadership = self.get_leadership(leadership_epoch)?;
+        let slot = self.time_frame.slot_at(&std::time::SystemTime::now())?;
+        let position = leadership.era().from_slot_to_era(slot)?;
+        Some(position.epoch.0)
+    }
+
+    /// the time at which the slot of the given date starts, `None` if
+    /// the date is not within the blockchain's time frame.
+    pub fn date_to_systemtime(&self, era: &TimeEra, date: &BlockDate) -> Option<SystemTime> {
+        self.time_frame.slot_to_systemtime(date_to_slot(era, date))
+    }
+
     pub fn build_leadership(&self, epoch: Epoch, parent_hash: &HeaderHash) -> Option<Leadership> {
         self.get_ledger(parent_hash)
             .map(|ledger| Leadership::new(epoch, ledger))
@@ -268,10 +387,18 @@ impl Blockchain {
     }
 }
 
+fn date_to_slot(era: &TimeEra, date: &BlockDate) -> Slot {
+    era.from_era_to_slot(EpochPosition {
+        epoch: chain_time::Epoch(date.epoch),
+        slot: EpochSlotOffset(date.slot_id),
+    })
+}
+
 custom_error! {pub HandleBlockError
     Storage{source: storage::Error} = "Error in the blockchain storage",
     Ledger{source: ledger::Error} = "Invalid blockchain state",
     InternalTip { source: TipReplaceError } = "Cannot update the blockchain's TIP",
+    InternalTipGet { source: TipGetError } = "Cannot read the blockchain's TIP",
 }
 
 pub enum HandledBlock {
@@ -293,6 +420,10 @@ pub enum HandledBlock {
 custom_error! {pub RejectionReason
     AlreadyPresent = "Block already present in the blockchain",
     BeyondStabilityDepth = "the block is beyond the stability depth, we reject it",
+    UnconnectedQuotaExceeded = "the sender has too many blocks waiting for their parent",
+    ImplausibleEpoch { epoch: Epoch, current_epoch: Epoch } = "the block is dated of epoch {epoch} while we are in epoch {current_epoch}",
+    FromTheFuture { date: BlockDate } = "the block is dated {date}, too far in the future",
+    MissingParentState = "the ledger state of the parent block is not available anymore",
     Consensus { error: leadership::Error } = "{error}",
 }
 
@@ -332,6 +463,7 @@ pub fn handle_end_of_epoch_event(blockchain: &Blockchain) -> Result<(), HandleBl
 pub fn handle_block(
     blockchain: &mut Blockchain,
     block: Block,
+    origin: BlockOrigin,
     is_tip_candidate: bool,
 ) -> Result<HandledBlock, HandleBlockError> {
     match header_triage(blockchain, &block.header(), is_tip_candidate)? {
@@ -341,12 +473,14 @@ pub fn handle_block(
             // in the node blockchain
             // we need to signal the network more blocks are required
 
-            blockchain
-                .unconnected_blocks
-                .entry(block.parent_id())
-                .or_insert(BTreeMap::new())
-                .insert(block.id(), block);
-            Ok(HandledBlock::MissingBranchToBlock { to })
+            match blockchain.unconnected_blocks.insert(block, origin) {
+                Insertion::Added | Insertion::AlreadyPresent => {
+                    Ok(HandledBlock::MissingBranchToBlock { to })
+                }
+                Insertion::QuotaExceeded => Ok(HandledBlock::Rejected {
+                    reason: RejectionReason::UnconnectedQuotaExceeded,
+                }),
+            }
         }
         BlockHeaderTriage::ProcessBlockToState => {
             //
@@ -355,14 +489,39 @@ pub fn handle_block(
     }
 }
 
+/// process the blocks that were waiting for the given block to
+/// arrive, and then the blocks waiting for them, and so on.
+///
+/// Returns the outcome of handling each of these blocks, in the order
+/// they were processed.
+pub fn process_unconnected_blocks(
+    blockchain: &mut Blockchain,
+    parent: &HeaderHash,
+) -> Result<Vec<HandledBlock>, HandleBlockError> {
+    let mut handled = Vec::new();
+    let mut parents = vec![*parent];
+
+    while let Some(parent) = parents.pop() {
+        for (block, origin) in blockchain.unconnected_blocks.take_children(&parent) {
+            let id = block.id();
+            let result = handle_block(blockchain, block, origin, true)?;
+            if let HandledBlock::Acquired { .. } = result {
+                parents.push(id);
+            }
+            handled.push(result);
+        }
+    }
+
+    Ok(handled)
+}
+
 fn process_block(
     blockchain: &mut Blockchain,
     block: Block,
 ) -> Result<HandledBlock, HandleBlockError> {
-    let (block_tip, _block_tip_info) = blockchain.get_block(&block.parent_id())?;
+    let (parent, _parent_info) = blockchain.get_block(&block.parent_id())?;
 
-    let tip_chain_length = block_tip.chain_length();
-    let parent_epoch = block_tip.date().epoch;
+    let parent_epoch = parent.date().epoch;
 
     let state = {
         let parent_state = blockchain.get_ledger(&block.parent_id()).unwrap();
@@ -382,7 +541,7 @@ fn process_block(
         let _gc_root = blockchain.leaderships.add(
             block.header.date().epoch,
             block.header.chain_length(),
-            block_tip.id(),
+            parent.id(),
             leadership,
         );
     }
@@ -391,18 +550,55 @@ fn process_block(
     // corresponding states, but to prevent a DoS, we may
     // want to store only sufficiently long chains.
 
-    let new_chain_length = block.chain_length();
-
     let branch = Branch::new(
         blockchain.multiverse.add(block.id(), state.clone()),
-        new_chain_length,
+        block.chain_length(),
         state,
     );
 
-    if new_chain_length > tip_chain_length {
-        blockchain.put_tip(branch, &block)?;
-    } else {
-        blockchain.put_block(&block)?;
+    blockchain.put_block(&block)?;
+    blockchain
+        .fork_choice
+        .add_branch(&block.parent_id(), branch.clone());
+
+    let tip = blockchain.tip.branch()?;
+    let selection = {
+        let storage = blockchain.storage.read().unwrap();
+        blockchain.fork_choice.select(&storage, &tip, &branch)?
+    };
+
+    let tip_updated = match selection {
+        TipSelection::Extend => {
+            blockchain.put_tip(branch)?;
+            true
+        }
+        TipSelection::Switch(rollback) => {
+            info!(blockchain.logger, "switching the tip to a competing branch";
+                "new_tip" => block.id().to_string(),
+                "common_ancestor" => rollback.common_ancestor.to_string(),
+                "rolled_back" => rollback.removed.len(),
+                "branches" => blockchain.fork_choice.branches().count(),
+            );
+            blockchain.put_tip(branch)?;
+            blockchain.fork_choice.notify(&rollback);
+            true
+        }
+        TipSelection::Keep => false,
+        TipSelection::BeyondRollbackDepth { depth } => {
+            warn!(blockchain.logger, "not switching to a competing branch, it forks too deep";
+                "head" => block.id().to_string(),
+                "depth" => depth,
+                "max_depth" => blockchain.fork_choice.max_rollback_depth(),
+            );
+            false
+        }
+    };
+
+    if tip_updated {
+        blockchain.fork_choice.prune(&block.chain_length());
+        if block.header.date().epoch > parent_epoch {
+            save_snapshot(blockchain, &block);
+        }
     }
 
     Ok(HandledBlock::Acquired {
@@ -410,6 +606,37 @@ fn process_block(
     })
 }
 
+/// save a snapshot of the ledger state at the given block, the block's
+/// state and its parent's state must be in the multiverse.
+///
+/// Failing to save a snapshot is not fatal: it only means the next
+/// restart will have to replay more blocks.
+fn save_snapshot(blockchain: &Blockchain, block: &Block) {
+    let snapshots = match blockchain.snapshots {
+        None => return,
+        Some(ref snapshots) => snapshots,
+    };
+    let snapshot = Snapshot {
+        epoch: block.date().epoch,
+        block_hash: block.id(),
+        ledger: blockchain.get_ledger(&block.id()).unwrap().clone(),
+        leadership_ledger: blockchain.get_ledger(&block.parent_id()).unwrap().clone(),
+    };
+    match snapshots.save(&snapshot) {
+        Ok(()) => info!(
+            blockchain.logger,
+            "ledger snapshot saved at block {}",
+            block.id()
+        ),
+        Err(err) => warn!(
+            blockchain.logger,
+            "cannot save the ledger snapshot at block {}: {}",
+            block.id(),
+            err
+        ),
+    }
+}
+
 pub fn header_triage(
     blockchain: &Blockchain,
     header: &Header,
@@ -427,32 +654,54 @@ pub fn header_triage(
 
     let (block_tip, _) = blockchain.get_block_tip()?;
 
-    if let Some(leadership) = blockchain.get_leadership_or_build(block_date.epoch, &parent_id) {
+    if let Some(current_epoch) = blockchain.current_epoch(block_tip.date().epoch) {
+        // a block of the next epoch is acceptable as the clocks of the
+        // nodes may not be exactly synchronized
+        if block_date.epoch > current_epoch + 1 {
+            return Ok(BlockHeaderTriage::NotOfInterest {
+                reason: RejectionReason::ImplausibleEpoch {
+                    epoch: block_date.epoch,
+                    current_epoch,
+                },
+            });
+        }
+    }
+
+    let leadership = if let Some(leadership) =
+        blockchain.get_leadership_or_build(block_date.epoch, &parent_id)
+    {
         match leadership.verify(header) {
-            Verification::Success => {}
+            Verification::Success => leadership,
             Verification::Failure(err) => {
                 return Ok(BlockHeaderTriage::NotOfInterest {
                     reason: RejectionReason::Consensus { error: err },
                 });
             }
         }
+    } else if !blockchain.block_exists(&parent_id)? {
+        // No leadership found for the epoch and the parent's ledger
+        // is not known: the header is on a branch we don't have yet.
+        // The header will be verified once its ancestors are retrieved,
+        // the leadership is then built from the parent's ledger.
+        return Ok(BlockHeaderTriage::MissingParentOrBranch { to: parent_id });
     } else {
-        // Error No leadership found for the epoch
-        //
-        // That the leadership is missing may not be a problem, we might simply
-        // need to try to retrieve it (this could be linked with the missing
-        // parent or branch (`BlockHeaderTriage::MissingParentOrBranch`)
-        unimplemented!()
-    }
-
-    // TODO: this is a wrong check, we need to get something more
-    //       dynamic than this dummy comparison
-    // hint: it might be worth utilising the Clock to know exactly
-    // how many blocks there is between the 2 given dates
-    // then to use the stability depth to compare if the block
-    // is not too far from the blockchain
-    //
-    if is_tip_candidate && block_date.epoch < block_tip.date().epoch.checked_sub(2).unwrap_or(0) {
+        // the parent is known but its ledger has been discarded: the
+        // header is on a branch that forked too long ago.
+        return Ok(BlockHeaderTriage::NotOfInterest {
+            reason: RejectionReason::MissingParentState,
+        });
+    };
+    let era = leadership.era();
+
+    if let Some(block_time) = blockchain.date_to_systemtime(era, &block_date) {
+        if block_time > SystemTime::now() + blockchain.clock_skew_tolerance {
+            return Ok(BlockHeaderTriage::NotOfInterest {
+                reason: RejectionReason::FromTheFuture { date: block_date },
+            });
+        }
+    }
+
+    if is_tip_candidate && is_beyond_stability_depth(blockchain, era, header, &block_tip)? {
         return Ok(BlockHeaderTriage::NotOfInterest {
             reason: RejectionReason::BeyondStabilityDepth,
         });
@@ -464,3 +713,145 @@ pub fn header_triage(
 
     Ok(BlockHeaderTriage::ProcessBlockToState)
 }
+
+/// check if the given header is further behind the tip than the
+/// configured stability depth.
+///
+/// There is at most one block per slot, so the number of slots between
+/// the header and the tip is an upper bound of the number of blocks
+/// between them. The header is only beyond the stability depth if both
+/// this bound and the difference of chain length exceed the depth.
+///
+/// The chain length claimed by the header is not trusted: it is taken
+/// from the stored parent instead. If the parent is not known yet the
+/// header cannot be proven too old, the check is done again once the
+/// block itself is handled.
+fn is_beyond_stability_depth(
+    blockchain: &Blockchain,
+    era: &TimeEra,
+    header: &Header,
+    tip: &Block,
+) -> Result<bool, HandleBlockError> {
+    let stability_depth = u64::from(blockchain.stability_depth);
+
+    let header_slot = u64::from(date_to_slot(era, &header.date()));
+    let tip_slot = u64::from(date_to_slot(era, &tip.date()));
+    let slots_behind = tip_slot.saturating_sub(header_slot);
+    if slots_behind <= stability_depth {
+        return Ok(false);
+    }
+
+    let parent_id = header.parent_id();
+    if !blockchain.block_exists(&parent_id)? {
+        return Ok(false);
+    }
+    let (parent, _) = blockchain.get_block(&parent_id)?;
+
+    let tip_chain_length = u64::from(u32::from(tip.chain_length()));
+    let header_chain_length = u64::from(u32::from(parent.chain_length())) + 1;
+    let blocks_behind = tip_chain_length.saturating_sub(header_chain_length);
+
+    Ok(blocks_behind > stability_depth)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate mktemp;
+
+    use super::*;
+    use crate::blockcfg::BlockBuilder;
+    use chain_addr::Discrimination;
+    use chain_core::property::Serialize as _;
+    use chain_crypto::{Ed25519Extended, KeyPair};
+    use chain_impl_mockchain::{
+        block::ConsensusVersion,
+        config::{Block0Date, ConfigParam},
+        leadership::bft::LeaderId,
+        message::{ConfigParams, Message},
+        milli::Milli,
+    };
+    use chain_storage::memory::MemoryBlockStore;
+    use rand::SeedableRng;
+    use rand_chacha::ChaChaRng;
+
+    fn block0_messages() -> Vec<Message> {
+        let leader: KeyPair<Ed25519Extended> =
+            KeyPair::generate(&mut ChaChaRng::from_seed([1; 32]));
+        let mut params = ConfigParams::new();
+        params.push(ConfigParam::Block0Date(Block0Date(0)));
+        params.push(ConfigParam::Discrimination(Discrimination::Test));
+        params.push(ConfigParam::ConsensusVersion(ConsensusVersion::Bft));
+        params.push(ConfigParam::SlotDuration(10));
+        params.push(ConfigParam::SlotsPerEpoch(10));
+        params.push(ConfigParam::EpochStabilityDepth(10));
+        params.push(ConfigParam::AddBftLeader(LeaderId::from(
+            leader.public_key().clone(),
+        )));
+        params.push(ConfigParam::ConsensusGenesisPraosActiveSlotsCoeff(
+            Milli::from_millis(500),
+        ));
+        params.push(ConfigParam::KESUpdateSpeed(3600));
+        vec![Message::Initial(params)]
+    }
+
+    #[test]
+    fn load_resumes_from_a_snapshot() {
+        let mut builder = BlockBuilder::new();
+        builder.messages(block0_messages());
+        let block0 = builder.make_genesis_block();
+        let mut builder = BlockBuilder::new();
+        builder
+            .date(BlockDate {
+                epoch: 1,
+                slot_id: 0,
+            })
+            .parent(block0.id())
+            .chain_length(block0.chain_length().next());
+        let block1 = builder.make_genesis_block();
+
+        let mut storage: NodeStorage = Box::new(MemoryBlockStore::new());
+        storage.put_block(&block0).unwrap();
+        storage.put_block(&block1).unwrap();
+        storage
+            .put_tag(LOCAL_BLOCKCHAIN_TIP_TAG, &block1.id())
+            .unwrap();
+
+        // replaying the blocks from block0 cannot give this ledger: it
+        // is the one of another block0
+        let ledger = Ledger::new(HeaderHash::hash_bytes(&[1]), &block0_messages()).unwrap();
+        let directory = mktemp::Temp::new_dir().unwrap();
+        let snapshots = Snapshots::new(directory.to_path_buf());
+        snapshots
+            .save(&Snapshot {
+                epoch: 1,
+                block_hash: block1.id(),
+                ledger: ledger.clone(),
+                leadership_ledger: ledger.clone(),
+            })
+            .unwrap();
+
+        let (epoch_event, _) = mpsc::channel(1);
+        let limits = UnconnectedBlocksLimits {
+            max_age: Duration::from_secs(600),
+            max_blocks: 16,
+            max_blocks_per_peer: 16,
+        };
+        let blockchain = Blockchain::load(
+            block0,
+            storage,
+            Some(snapshots),
+            Duration::from_secs(10),
+            limits,
+            None,
+            epoch_event,
+            &Logger::root(slog::Discard, o!()),
+        )
+        .unwrap();
+
+        assert_eq!(blockchain.tip.hash().unwrap(), block1.id());
+        assert_eq!(
+            blockchain.tip.ledger().unwrap().serialize_as_vec().unwrap(),
+            ledger.serialize_as_vec().unwrap()
+        );
+    }
+}
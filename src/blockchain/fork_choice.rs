@@ -0,0 +1,433 @@
+//! Fork choice: selection of the blockchain's tip among the competing
+//! branches.
+//!
+//! Every block that is applied to the ledger is the head of a
+//! [`Branch`]. The [`ForkChoice`] keeps track of the heads of the
+//! branches that are still candidates to become the tip and decides,
+//! every time a new block is applied, whether the tip needs to be
+//! updated:
+//!
+//! * the longest chain wins;
+//! * between 2 branches of the same chain length, the branch whose head
+//!   has the lowest hash wins. It is arbitrary but deterministic, every
+//!   node having received the same blocks will select the same tip;
+//! * we never roll back more than the stability depth of the blockchain
+//!   (the `epoch_stability_depth` of the block0, unless the node's
+//!   settings override it). Branches that fork deeper than this are
+//!   ignored, and forgotten once the tip is that far ahead of them.
+//!
+//! When the tip is switched to another branch, a [`Rollback`] event is
+//! sent to the subscribers so they can react to the blocks that have
+//! been removed from (and added to) the main chain. The events are
+//! never dropped: a subscriber missing one would keep acting on blocks
+//! that are no longer in the main chain.
+//!
+//! [`Branch`]: ../struct.Branch.html
+//! [`ForkChoice`]: ./struct.ForkChoice.html
+//! [`Rollback`]: ./struct.Rollback.html
+
+use crate::{
+    blockcfg::{ChainLength, HeaderHash},
+    blockchain::Branch,
+    start_up::NodeStorage,
+};
+use chain_core::property::Block as _;
+use chain_storage::error as storage;
+use futures::sync::mpsc;
+use std::collections::BTreeMap;
+
+/// the tip has been switched to a branch that does not extend the
+/// previous tip.
+#[derive(Clone, Debug)]
+pub struct Rollback {
+    /// the most recent block shared by the previous and the new tip
+    pub common_ancestor: HeaderHash,
+    /// the blocks that are no longer in the main chain, from the
+    /// oldest to the most recent (the previous tip)
+    pub removed: Vec<HeaderHash>,
+    /// the blocks that are now in the main chain, from the oldest to
+    /// the most recent (the new tip)
+    pub added: Vec<HeaderHash>,
+}
+
+/// outcome of the fork choice for a newly applied block
+pub enum TipSelection {
+    /// the new block directly extends the current tip
+    Extend,
+    /// the new block is the head of a branch that overtakes the
+    /// current tip
+    Switch(Rollback),
+    /// the current tip is still preferred
+    Keep,
+    /// the new block is the head of a branch that would be preferred
+    /// but switching to it would roll back `depth` blocks, more than
+    /// the allowed maximum
+    BeyondRollbackDepth { depth: u32 },
+}
+
+pub struct ForkChoice {
+    /// maximum number of blocks of the main chain we accept to roll
+    /// back, this is the stability depth of the blockchain
+    max_rollback_depth: u32,
+
+    /// the heads of the branches that are still candidates to be
+    /// selected as the tip, indexed by the head's hash
+    branches: BTreeMap<HeaderHash, Branch>,
+
+    subscribers: Vec<mpsc::UnboundedSender<Rollback>>,
+}
+
+impl ForkChoice {
+    /// create the fork choice with the given initial tip
+    pub fn new(max_rollback_depth: u32, tip: Branch) -> Self {
+        let mut branches = BTreeMap::new();
+        branches.insert(tip.hash(), tip);
+        ForkChoice {
+            max_rollback_depth,
+            branches,
+            subscribers: Vec::new(),
+        }
+    }
+
+    #[inline]
+    pub fn max_rollback_depth(&self) -> u32 {
+        self.max_rollback_depth
+    }
+
+    /// list the heads of the branches that are still tip candidates
+    pub fn branches<'a>(&'a self) -> impl Iterator<Item = &'a Branch> {
+        self.branches.values()
+    }
+
+    /// record the given branch, replacing the branch of its parent
+    /// if it was one of the heads.
+    pub fn add_branch(&mut self, parent: &HeaderHash, branch: Branch) {
+        self.branches.remove(parent);
+        self.branches.insert(branch.hash(), branch);
+    }
+
+    /// forget about the branches that are too far behind the tip to
+    /// ever be selected: switching to them, or to a branch forking
+    /// from them, would roll back more than the maximum depth
+    pub fn prune(&mut self, tip_chain_length: &ChainLength) {
+        let tip_chain_length = u32::from(*tip_chain_length);
+        let max_rollback_depth = self.max_rollback_depth;
+        let stale = self
+            .branches
+            .iter()
+            .filter(|(_, branch)| {
+                let chain_length = u32::from(*branch.chain_length());
+                tip_chain_length.saturating_sub(chain_length) > max_rollback_depth
+            })
+            .map(|(hash, _)| *hash)
+            .collect::<Vec<_>>();
+        for hash in stale {
+            self.branches.remove(&hash);
+        }
+    }
+
+    /// subscribe to the [`Rollback`] events.
+    ///
+    /// The events are queued until the subscriber reads them, in the
+    /// order the tip was switched: the rollbacks are rare (and bounded
+    /// by the rate of the blocks) but none of them can be missed.
+    ///
+    /// [`Rollback`]: ./struct.Rollback.html
+    pub fn subscribe(&mut self) -> mpsc::UnboundedReceiver<Rollback> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    /// select the tip between the current `tip` and the `candidate`.
+    ///
+    /// The candidate and its ancestors must be in the storage already.
+    pub fn select(
+        &self,
+        storage: &NodeStorage,
+        tip: &Branch,
+        candidate: &Branch,
+    ) -> Result<TipSelection, storage::Error> {
+        if !is_preferred(candidate, tip) {
+            return Ok(TipSelection::Keep);
+        }
+
+        let (block, _) = storage.get_block(&candidate.hash())?;
+        if block.parent_id() == tip.hash() {
+            return Ok(TipSelection::Extend);
+        }
+
+        match find_common_ancestor(storage, tip, candidate, self.max_rollback_depth)? {
+            Ok(rollback) => Ok(TipSelection::Switch(rollback)),
+            Err(depth) => Ok(TipSelection::BeyondRollbackDepth { depth }),
+        }
+    }
+
+    /// notify the subscribers the tip has been rolled back, the
+    /// subscribers that went away are forgotten
+    pub fn notify(&mut self, rollback: &Rollback) {
+        self.subscribers
+            .retain(|subscriber| subscriber.unbounded_send(rollback.clone()).is_ok());
+    }
+}
+
+/// longest chain first, then the lowest head's hash
+fn is_preferred(candidate: &Branch, tip: &Branch) -> bool {
+    if candidate.chain_length() != tip.chain_length() {
+        candidate.chain_length() > tip.chain_length()
+    } else {
+        candidate.hash() < tip.hash()
+    }
+}
+
+/// walk back both branches until we find the block they share
+///
+/// Returns `Err(depth)` if the common ancestor is more than
+/// `max_depth` blocks behind the tip.
+fn find_common_ancestor(
+    storage: &NodeStorage,
+    tip: &Branch,
+    candidate: &Branch,
+    max_depth: u32,
+) -> Result<Result<Rollback, u32>, storage::Error> {
+    let mut removed = Vec::new();
+    let mut added = Vec::new();
+
+    let mut tip_side = (tip.hash(), *tip.chain_length());
+    let mut candidate_side = (candidate.hash(), *candidate.chain_length());
+
+    while tip_side.0 != candidate_side.0 {
+        if removed.len() as u32 > max_depth {
+            return Ok(Err(removed.len() as u32));
+        }
+
+        if candidate_side.1 >= tip_side.1 {
+            let (block, _) = storage.get_block(&candidate_side.0)?;
+            added.push(candidate_side.0);
+            candidate_side = parent_of(storage, &block.parent_id())?;
+        } else {
+            let (block, _) = storage.get_block(&tip_side.0)?;
+            removed.push(tip_side.0);
+            tip_side = parent_of(storage, &block.parent_id())?;
+        }
+    }
+
+    if removed.len() as u32 > max_depth {
+        return Ok(Err(removed.len() as u32));
+    }
+
+    removed.reverse();
+    added.reverse();
+    Ok(Ok(Rollback {
+        common_ancestor: tip_side.0,
+        removed,
+        added,
+    }))
+}
+
+fn parent_of(
+    storage: &NodeStorage,
+    parent_id: &HeaderHash,
+) -> Result<(HeaderHash, ChainLength), storage::Error> {
+    let (parent, _) = storage.get_block(parent_id)?;
+    Ok((*parent_id, parent.chain_length()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockcfg::{Block, BlockBuilder, BlockDate, Ledger, Multiverse};
+    use chain_addr::Discrimination;
+    use chain_core::property::Block as _;
+    use chain_crypto::{Ed25519Extended, KeyPair};
+    use chain_impl_mockchain::{
+        block::ConsensusVersion,
+        config::{Block0Date, ConfigParam},
+        leadership::bft::LeaderId,
+        message::{ConfigParams, Message},
+        milli::Milli,
+    };
+    use chain_storage::memory::MemoryBlockStore;
+    use futures::Stream as _;
+    use rand::SeedableRng;
+    use rand_chacha::ChaChaRng;
+
+    struct Chain {
+        storage: NodeStorage,
+        multiverse: Multiverse<Ledger>,
+        ledger: Ledger,
+    }
+
+    impl Chain {
+        fn new() -> (Self, Block) {
+            let leader: KeyPair<Ed25519Extended> =
+                KeyPair::generate(&mut ChaChaRng::from_seed([1; 32]));
+            let mut params = ConfigParams::new();
+            params.push(ConfigParam::Block0Date(Block0Date(0)));
+            params.push(ConfigParam::Discrimination(Discrimination::Test));
+            params.push(ConfigParam::ConsensusVersion(ConsensusVersion::Bft));
+            params.push(ConfigParam::SlotDuration(10));
+            params.push(ConfigParam::SlotsPerEpoch(10));
+            params.push(ConfigParam::EpochStabilityDepth(10));
+            params.push(ConfigParam::AddBftLeader(LeaderId::from(
+                leader.public_key().clone(),
+            )));
+            params.push(ConfigParam::ConsensusGenesisPraosActiveSlotsCoeff(
+                Milli::from_millis(500),
+            ));
+            params.push(ConfigParam::KESUpdateSpeed(3600));
+            let block0 = BlockBuilder::new().make_genesis_block();
+            let ledger = Ledger::new(block0.id(), &[Message::Initial(params)]).unwrap();
+            let mut chain = Chain {
+                storage: Box::new(MemoryBlockStore::new()),
+                multiverse: Multiverse::new(),
+                ledger,
+            };
+            chain.storage.put_block(&block0).unwrap();
+            (chain, block0)
+        }
+
+        /// a block after `parent`, blocks of different slots are on
+        /// different branches
+        fn child(&mut self, parent: &Block, slot_id: u32) -> Block {
+            let mut builder = BlockBuilder::new();
+            builder
+                .date(BlockDate { epoch: 0, slot_id })
+                .parent(parent.id())
+                .chain_length(parent.chain_length().next());
+            let block = builder.make_genesis_block();
+            self.storage.put_block(&block).unwrap();
+            block
+        }
+
+        fn branch(&mut self, block: &Block) -> Branch {
+            Branch::new(
+                self.multiverse.add(block.id(), self.ledger.clone()),
+                block.chain_length(),
+                self.ledger.clone(),
+            )
+        }
+    }
+
+    #[test]
+    fn extend_the_tip() {
+        let (mut chain, block0) = Chain::new();
+        let a1 = chain.child(&block0, 1);
+        let tip = chain.branch(&block0);
+        let fork_choice = ForkChoice::new(10, tip.clone());
+
+        match fork_choice.select(&chain.storage, &tip, &chain.branch(&a1)) {
+            Ok(TipSelection::Extend) => {}
+            _ => panic!("the tip is not extended"),
+        }
+    }
+
+    #[test]
+    fn keep_the_longest_chain() {
+        let (mut chain, block0) = Chain::new();
+        let a1 = chain.child(&block0, 1);
+        let a2 = chain.child(&a1, 2);
+        let f3 = chain.child(&block0, 3);
+        let tip = chain.branch(&a2);
+        let fork_choice = ForkChoice::new(10, tip.clone());
+
+        match fork_choice.select(&chain.storage, &tip, &chain.branch(&f3)) {
+            Ok(TipSelection::Keep) => {}
+            _ => panic!("the tip is not kept"),
+        }
+    }
+
+    #[test]
+    fn switch_to_a_longer_branch() {
+        let (mut chain, block0) = Chain::new();
+        let a1 = chain.child(&block0, 1);
+        let f2 = chain.child(&block0, 2);
+        let f3 = chain.child(&f2, 3);
+        let tip = chain.branch(&a1);
+        let mut fork_choice = ForkChoice::new(10, tip.clone());
+        let rollbacks = fork_choice.subscribe();
+
+        let rollback = match fork_choice.select(&chain.storage, &tip, &chain.branch(&f3)) {
+            Ok(TipSelection::Switch(rollback)) => rollback,
+            _ => panic!("the tip is not switched"),
+        };
+        assert_eq!(rollback.common_ancestor, block0.id());
+        assert_eq!(rollback.removed, vec![a1.id()]);
+        assert_eq!(rollback.added, vec![f2.id(), f3.id()]);
+
+        // the subscribers get the removed and added blocks
+        fork_choice.notify(&rollback);
+        let event = rollbacks.wait().next().unwrap().unwrap();
+        assert_eq!(event.common_ancestor, block0.id());
+        assert_eq!(event.removed, vec![a1.id()]);
+        assert_eq!(event.added, vec![f2.id(), f3.id()]);
+    }
+
+    #[test]
+    fn tie_break_on_the_lowest_hash() {
+        let (mut chain, block0) = Chain::new();
+        let a1 = chain.child(&block0, 1);
+        let f1 = chain.child(&block0, 2);
+        let (a1, f1) = (chain.branch(&a1), chain.branch(&f1));
+        let (lowest, highest) = if a1.hash() < f1.hash() {
+            (a1, f1)
+        } else {
+            (f1, a1)
+        };
+        let fork_choice = ForkChoice::new(10, highest.clone());
+
+        // whichever the current tip, the lowest hash is selected
+        match fork_choice.select(&chain.storage, &highest, &lowest) {
+            Ok(TipSelection::Switch(rollback)) => {
+                assert_eq!(rollback.added, vec![lowest.hash()]);
+            }
+            _ => panic!("the tip is not switched to the lowest hash"),
+        }
+        match fork_choice.select(&chain.storage, &lowest, &highest) {
+            Ok(TipSelection::Keep) => {}
+            _ => panic!("the tip with the lowest hash is not kept"),
+        }
+    }
+
+    #[test]
+    fn refuse_to_roll_back_too_deep() {
+        let (mut chain, block0) = Chain::new();
+        let a1 = chain.child(&block0, 1);
+        let a2 = chain.child(&a1, 2);
+        let f3 = chain.child(&block0, 3);
+        let f4 = chain.child(&f3, 4);
+        let f5 = chain.child(&f4, 5);
+        let tip = chain.branch(&a2);
+        let fork_choice = ForkChoice::new(1, tip.clone());
+
+        match fork_choice.select(&chain.storage, &tip, &chain.branch(&f5)) {
+            Ok(TipSelection::BeyondRollbackDepth { depth }) => assert_eq!(depth, 2),
+            _ => panic!("the rollback is not refused"),
+        }
+    }
+
+    #[test]
+    fn branches_are_pruned_behind_the_rollback_depth() {
+        let (mut chain, block0) = Chain::new();
+        let a1 = chain.child(&block0, 1);
+        let a2 = chain.child(&a1, 2);
+        let a3 = chain.child(&a2, 3);
+        let f4 = chain.child(&block0, 4);
+        let mut fork_choice = ForkChoice::new(1, chain.branch(&block0));
+
+        let f4_branch = chain.branch(&f4);
+        fork_choice.add_branch(&block0.id(), f4_branch);
+        for (parent, block) in &[(&block0, &a1), (&a1, &a2), (&a2, &a3)] {
+            let branch = chain.branch(block);
+            fork_choice.add_branch(&parent.id(), branch);
+        }
+        let heads: Vec<HeaderHash> = fork_choice.branches().map(|b| b.hash()).collect();
+        assert_eq!(heads.len(), 2);
+        assert!(heads.contains(&a3.id()));
+        assert!(heads.contains(&f4.id()));
+
+        fork_choice.prune(&a3.chain_length());
+        let heads: Vec<HeaderHash> = fork_choice.branches().map(|b| b.hash()).collect();
+        assert_eq!(heads, vec![a3.id()]);
+    }
+}
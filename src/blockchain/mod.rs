@@ -1,11 +1,20 @@
 mod branch;
 mod chain;
+pub mod fork_choice;
 mod process;
+mod snapshot;
 mod tip;
+mod unconnected;
 
 pub use self::branch::Branch;
 pub use self::chain::{
-    handle_block, Blockchain, BlockchainR, HandleBlockError, HandledBlock, LoadError,
+    handle_block, process_unconnected_blocks, Blockchain, BlockchainR, HandleBlockError,
+    HandledBlock, LoadError,
 };
+pub use self::fork_choice::{ForkChoice, Rollback, TipSelection};
 pub use self::process::handle_input;
+pub use self::snapshot::{Snapshot, SnapshotError, Snapshots};
 pub use self::tip::{Tip, TipGetError, TipReplaceError};
+pub use self::unconnected::{
+    BlockOrigin, UnconnectedBlocks, UnconnectedBlocksLimits, UnconnectedBlocksStats,
+};
@@ -1,5 +1,9 @@
-use crate::blockchain::chain::{self, BlockHeaderTriage, BlockchainR, HandledBlock};
+use crate::blockcfg::{Header, HeaderHash};
+use crate::blockchain::chain::{
+    self, BlockHeaderTriage, Blockchain, BlockchainR, HandledBlock, RejectionReason,
+};
 use crate::intercom::{BlockMsg, NetworkMsg, PropagateMsg};
+use crate::network::p2p::{misbehaviour::Misbehaviour, topology::NodeId};
 use crate::rest::v0::node::stats::StatsCounter;
 use crate::utils::{
     async_msg::MessageBox,
@@ -7,11 +11,12 @@ use crate::utils::{
 };
 
 use chain_core::property::Header as _;
+use slog::Logger;
 
 pub fn handle_input(
     info: &TokioServiceInfo,
     blockchain: &BlockchainR,
-    _stats_counter: &StatsCounter,
+    stats_counter: &StatsCounter,
     network_msg_box: &mut MessageBox<NetworkMsg>,
     input: Input<BlockMsg>,
 ) {
@@ -33,7 +38,7 @@ pub fn handle_input(
         }
         BlockMsg::LeadershipBlock(block) => {
             let mut blockchain = blockchain.lock_write();
-            match chain::handle_block(&mut blockchain, block, true).unwrap() {
+            match chain::handle_block(&mut blockchain, block, None, true).unwrap() {
                 HandledBlock::Rejected { reason } => {
                     warn!(logger,
                         "rejecting node's created block" ;
@@ -61,34 +66,33 @@ pub fn handle_input(
                         "date" => header.date().to_string()
                     );
                     debug!(logger, "Header: {:?}", header);
-                    network_msg_box
-                        .try_send(NetworkMsg::Propagate(PropagateMsg::Block(header)))
-                        .unwrap_or_else(|err| {
-                            error!(logger, "cannot propagate block to network: {}", err)
-                        });
+                    let id = header.id();
+                    propagate_block(network_msg_box, header, &logger);
+                    process_unconnected_blocks(&mut blockchain, &id, network_msg_box, &logger);
                 }
             }
+            stats_counter.set_unconnected_blocks(blockchain.unconnected_blocks.stats());
         }
-        BlockMsg::NetworkBlock(block) => {
+        BlockMsg::NetworkBlock(block, node_id) => {
             let mut blockchain = blockchain.lock_write();
-            match chain::handle_block(&mut blockchain, block, true).unwrap() {
+            match chain::handle_block(&mut blockchain, block, node_id, true).unwrap() {
                 HandledBlock::Rejected { reason } => {
-                    // TODO: drop the network peer that has sent
-                    // an invalid block.
                     warn!(logger, "rejecting block from the network: {:?}", reason);
+                    if let Some(node_id) = node_id {
+                        report_rejection(network_msg_box, node_id, &reason, &logger);
+                    }
                 }
                 HandledBlock::MissingBranchToBlock { to } => {
                     // This is abnormal because we have received a block
                     // that is not connected to preceding blocks, which
                     // should not happen as we solicit blocks in descending
-                    // order.
-                    //
-                    // TODO: drop the network peer that has sent
-                    // the wrong block.
+                    // order. The peer is only held responsible if the
+                    // branch pulled from it does not connect either.
                     warn!(
                         logger,
                         "disconnected block received, missing intermediate blocks to {}", to
                     );
+                    pull_headers(&blockchain, node_id, to, network_msg_box, &logger);
                 }
                 HandledBlock::Acquired { header } => {
                     info!(logger,
@@ -98,29 +102,34 @@ pub fn handle_input(
                     );
                     debug!(logger, "Header: {:?}", header);
                     // Propagate the block to other nodes
-                    network_msg_box
-                        .try_send(NetworkMsg::Propagate(PropagateMsg::Block(header)))
-                        .unwrap_or_else(|err| {
-                            error!(logger, "cannot propagate block to network: {}", err)
-                        });
+                    let id = header.id();
+                    propagate_block(network_msg_box, header, &logger);
+                    process_unconnected_blocks(&mut blockchain, &id, network_msg_box, &logger);
                 }
             }
+            stats_counter.set_unconnected_blocks(blockchain.unconnected_blocks.stats());
         }
         BlockMsg::AnnouncedBlock(header, node_id) => {
             let blockchain = blockchain.lock_read();
             match chain::header_triage(&blockchain, &header, false).unwrap() {
                 BlockHeaderTriage::NotOfInterest { reason } => {
                     info!(logger, "rejecting block announcement: {:?}", reason);
+                    report_rejection(network_msg_box, node_id, &reason, &logger);
                 }
                 BlockHeaderTriage::MissingParentOrBranch { to } => {
                     // blocks are missing between the received header and the
-                    // common ancestor.
-                    //
-                    // TODO reply to the network to ask for more blocks
+                    // common ancestor, ask the peer for the chain.
                     info!(
                         logger,
                         "received a loose block ({}), missing parent(s) block(s)", to
                     );
+                    pull_headers(
+                        &blockchain,
+                        Some(node_id),
+                        header.id(),
+                        network_msg_box,
+                        &logger,
+                    );
                 }
                 BlockHeaderTriage::ProcessBlockToState => {
                     info!(logger, "Block announcement is interesting, fetch block");
@@ -132,5 +141,167 @@ pub fn handle_input(
                 }
             }
         }
+        BlockMsg::ChainHeaders {
+            headers,
+            to,
+            node_id,
+        } => {
+            let blockchain = blockchain.lock_read();
+            let last = headers.last().map(|header| header.id());
+
+            if !branch_connects(&blockchain, &headers, &to) {
+                warn!(logger,
+                    "the branch pulled from the peer does not connect to {}", to ;
+                    "node_id" => node_id.to_string(),
+                );
+                report_misbehaviour(
+                    network_msg_box,
+                    node_id,
+                    Misbehaviour::DisconnectedBlock,
+                    &logger,
+                );
+                return;
+            }
+
+            let mut missing = Vec::new();
+            for header in headers {
+                if !blockchain.block_exists(&header.id()).unwrap() {
+                    missing.push(header.id());
+                }
+            }
+
+            if !missing.is_empty() {
+                debug!(logger, "fetching {} missing blocks", missing.len());
+                network_msg_box
+                    .try_send(NetworkMsg::GetBlocks(node_id, missing))
+                    .unwrap_or_else(|err| {
+                        error!(logger, "cannot request blocks from network: {}", err)
+                    });
+            }
+
+            // the peer sends at most a limited number of headers, continue
+            // from the last one received until we reach the requested block
+            match last {
+                Some(last) if last != to => {
+                    network_msg_box
+                        .try_send(NetworkMsg::PullHeaders {
+                            node_id: Some(node_id),
+                            from: vec![last],
+                            to,
+                        })
+                        .unwrap_or_else(|err| {
+                            error!(logger, "cannot pull headers from network: {}", err)
+                        });
+                }
+                _ => {}
+            }
+        }
     }
 }
+
+/// check the headers pulled from a peer form a chain starting from a
+/// block we already have. An empty answer only connects if the requested
+/// block is already known.
+fn branch_connects(blockchain: &Blockchain, headers: &[Header], to: &HeaderHash) -> bool {
+    let first = match headers.first() {
+        Some(first) => first,
+        None => return blockchain.block_exists(to).unwrap(),
+    };
+    if !blockchain.block_exists(&first.parent_id()).unwrap() {
+        return false;
+    }
+    headers
+        .windows(2)
+        .all(|pair| pair[1].parent_id() == pair[0].id())
+}
+
+fn propagate_block(network_msg_box: &mut MessageBox<NetworkMsg>, header: Header, logger: &Logger) {
+    network_msg_box
+        .try_send(NetworkMsg::Propagate(PropagateMsg::Block(header)))
+        .unwrap_or_else(|err| error!(logger, "cannot propagate block to network: {}", err));
+}
+
+/// process the blocks that were waiting for the newly acquired block
+/// and propagate the ones that are now part of the blockchain.
+fn process_unconnected_blocks(
+    blockchain: &mut Blockchain,
+    parent: &HeaderHash,
+    network_msg_box: &mut MessageBox<NetworkMsg>,
+    logger: &Logger,
+) {
+    for handled in chain::process_unconnected_blocks(blockchain, parent).unwrap() {
+        match handled {
+            HandledBlock::Acquired { header } => {
+                info!(logger,
+                    "previously unconnected block added to Node's blockchain";
+                    "id" => header.id().to_string(),
+                    "date" => header.date().to_string()
+                );
+                propagate_block(network_msg_box, header, logger);
+            }
+            HandledBlock::Rejected { reason } => {
+                warn!(
+                    logger,
+                    "rejecting previously unconnected block: {:?}", reason
+                );
+            }
+            HandledBlock::MissingBranchToBlock { to } => {
+                // the parent has just been added, this cannot happen
+                // unless the storage is corrupted
+                error!(logger, "unconnected block still missing its parent {}", to);
+            }
+        }
+    }
+}
+
+/// the misbehaviour of a peer that sent a block rejected for the
+/// given reason, `None` if the peer is not at fault.
+fn misbehaviour_of(reason: &RejectionReason) -> Option<Misbehaviour> {
+    match reason {
+        RejectionReason::Consensus { .. } => Some(Misbehaviour::InvalidBlock),
+        RejectionReason::ImplausibleEpoch { .. } | RejectionReason::FromTheFuture { .. } => {
+            Some(Misbehaviour::ImplausibleDate)
+        }
+        RejectionReason::UnconnectedQuotaExceeded => Some(Misbehaviour::TooManyUnconnectedBlocks),
+        // the peer may simply be behind us, or on a branch we gave up
+        RejectionReason::AlreadyPresent
+        | RejectionReason::BeyondStabilityDepth
+        | RejectionReason::MissingParentState => None,
+    }
+}
+
+fn report_rejection(
+    network_msg_box: &mut MessageBox<NetworkMsg>,
+    node_id: NodeId,
+    reason: &RejectionReason,
+    logger: &Logger,
+) {
+    if let Some(misbehaviour) = misbehaviour_of(reason) {
+        report_misbehaviour(network_msg_box, node_id, misbehaviour, logger);
+    }
+}
+
+fn report_misbehaviour(
+    network_msg_box: &mut MessageBox<NetworkMsg>,
+    node_id: NodeId,
+    misbehaviour: Misbehaviour,
+    logger: &Logger,
+) {
+    network_msg_box
+        .try_send(NetworkMsg::PeerMisbehaved(node_id, misbehaviour))
+        .unwrap_or_else(|err| error!(logger, "cannot report peer misbehaviour: {}", err));
+}
+
+/// ask the network for the headers between our tip and the given block
+fn pull_headers(
+    blockchain: &Blockchain,
+    node_id: Option<NodeId>,
+    to: HeaderHash,
+    network_msg_box: &mut MessageBox<NetworkMsg>,
+    logger: &Logger,
+) {
+    let from = blockchain.get_checkpoints().unwrap();
+    network_msg_box
+        .try_send(NetworkMsg::PullHeaders { node_id, from, to })
+        .unwrap_or_else(|err| error!(logger, "cannot pull headers from network: {}", err));
+}
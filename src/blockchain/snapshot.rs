@@ -0,0 +1,319 @@
+//! Ledger state snapshots
+//!
+//! Restoring the blockchain state by re-applying every block since
+//! block0 becomes slower every day the blockchain grows. Instead, the
+//! node writes a snapshot of the ledger at every epoch boundary in the
+//! storage directory. On restart we look for the newest snapshot that
+//! is an ancestor of the stored tip and only replay the blocks that
+//! follow it.
+//!
+//! A snapshot is taken at the first block of an epoch, it contains:
+//!
+//! * the hash of the block the snapshot was taken at;
+//! * the ledger state *after* applying this block;
+//! * the ledger state of the parent block, the one the epoch's
+//!   [`Leadership`] is built from.
+//!
+//! [`Leadership`]: ../../leadership/struct.Leadership.html
+
+use crate::blockcfg::{Epoch, HeaderHash, Ledger};
+use crate::start_up::NodeStorage;
+use chain_core::property::{Block as _, Deserialize as _, Serialize as _};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// name of the directory, within the node's storage directory, where
+/// the snapshots are written
+const SNAPSHOT_DIRECTORY: &'static str = "snapshots";
+const SNAPSHOT_EXTENSION: &'static str = "snapshot";
+
+/// number of snapshots to keep on disk. We keep more than one in
+/// case the newest one is on a branch that has been abandoned
+/// since. The snapshots of the latest epochs are kept, the epoch is
+/// part of the file name: the modification times of the files may be
+/// too coarse to order them.
+const SNAPSHOTS_TO_KEEP: usize = 4;
+
+custom_error! {pub SnapshotError
+    Io { source: io::Error } = "I/O error while accessing the snapshot: {source}",
+    Storage { source: chain_storage::error::Error } = "Error in the blockchain storage: {source}",
+}
+
+/// a ledger state snapshot, see the [module documentation](./index.html)
+pub struct Snapshot {
+    /// the epoch of the block the snapshot was taken at
+    pub epoch: Epoch,
+    pub block_hash: HeaderHash,
+    pub ledger: Ledger,
+    pub leadership_ledger: Ledger,
+}
+
+/// the snapshots saved in the node's storage directory
+pub struct Snapshots {
+    directory: PathBuf,
+}
+
+impl Snapshots {
+    /// the snapshots will be saved within the given storage directory
+    pub fn new<P: AsRef<Path>>(storage_directory: P) -> Self {
+        Snapshots {
+            directory: storage_directory.as_ref().join(SNAPSHOT_DIRECTORY),
+        }
+    }
+
+    /// write the snapshot on disk, and remove the oldest snapshots
+    /// if there are more than `SNAPSHOTS_TO_KEEP`.
+    pub fn save(&self, snapshot: &Snapshot) -> Result<(), SnapshotError> {
+        fs::create_dir_all(&self.directory)?;
+
+        // write in a temporary file first so a crash while writing
+        // does not leave a partial snapshot behind
+        let path = self.path_of(snapshot.epoch, &snapshot.block_hash);
+        let tmp_path = path.with_extension("tmp");
+        {
+            let mut file = io::BufWriter::new(fs::File::create(&tmp_path)?);
+            snapshot.block_hash.serialize(&mut file)?;
+            snapshot.ledger.serialize(&mut file)?;
+            snapshot.leadership_ledger.serialize(&mut file)?;
+            io::Write::flush(&mut file)?;
+        }
+        fs::rename(tmp_path, path)?;
+
+        self.prune()
+    }
+
+    /// load the newest snapshot that is an ancestor of (or is) the
+    /// given `tip`.
+    ///
+    /// Snapshots that cannot be read are ignored: we can always fall
+    /// back to replaying the blocks from an older snapshot or from
+    /// block0.
+    pub fn load_latest(
+        &self,
+        storage: &NodeStorage,
+        tip: &HeaderHash,
+    ) -> Result<Option<Snapshot>, SnapshotError> {
+        let mut candidates = Vec::new();
+        for path in self.list()? {
+            let snapshot = match load_file(&path) {
+                Ok(snapshot) => snapshot,
+                Err(_) => continue,
+            };
+            if !storage.block_exists(&snapshot.block_hash)? {
+                continue;
+            }
+            if storage.is_ancestor(&snapshot.block_hash, tip)?.is_none() {
+                continue;
+            }
+            let (block, _) = storage.get_block(&snapshot.block_hash)?;
+            candidates.push((block.chain_length(), snapshot));
+        }
+
+        Ok(candidates
+            .into_iter()
+            .max_by(|(a, _), (b, _)| a.cmp(b))
+            .map(|(_, snapshot)| snapshot))
+    }
+
+    // the epoch is padded so the file names sort by epoch too
+    fn path_of(&self, epoch: Epoch, block_hash: &HeaderHash) -> PathBuf {
+        self.directory.join(format!(
+            "{:010}-{}.{}",
+            epoch, block_hash, SNAPSHOT_EXTENSION
+        ))
+    }
+
+    fn list(&self) -> Result<Vec<PathBuf>, SnapshotError> {
+        if !self.directory.exists() {
+            return Ok(Vec::new());
+        }
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(&self.directory)? {
+            let path = entry?.path();
+            if path
+                .extension()
+                .map_or(false, |ext| ext == SNAPSHOT_EXTENSION)
+            {
+                entries.push(path);
+            }
+        }
+        Ok(entries)
+    }
+
+    /// remove the snapshots of the oldest epochs. The files whose name
+    /// has no epoch, written by an older version of the node, are
+    /// removed first.
+    fn prune(&self) -> Result<(), SnapshotError> {
+        let mut entries = self
+            .list()?
+            .into_iter()
+            .map(|path| (epoch_of(&path), path))
+            .collect::<Vec<_>>();
+        if entries.len() <= SNAPSHOTS_TO_KEEP {
+            return Ok(());
+        }
+        entries.sort_unstable_by(|a, b| b.cmp(a));
+        for (_, path) in entries.into_iter().skip(SNAPSHOTS_TO_KEEP) {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+/// the epoch in the name of the snapshot file
+fn epoch_of(path: &Path) -> Option<Epoch> {
+    let name = path.file_stem()?.to_str()?;
+    let (epoch, _) = name.split_at(name.find('-')?);
+    epoch.parse().ok()
+}
+
+fn load_file(path: &Path) -> Result<Snapshot, io::Error> {
+    let epoch = epoch_of(path).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "no epoch in the snapshot file name",
+        )
+    })?;
+    let mut file = io::BufReader::new(fs::File::open(path)?);
+    let block_hash = HeaderHash::deserialize(&mut file)?;
+    let ledger = Ledger::deserialize(&mut file)?;
+    let leadership_ledger = Ledger::deserialize(&mut file)?;
+    Ok(Snapshot {
+        epoch,
+        block_hash,
+        ledger,
+        leadership_ledger,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate mktemp;
+
+    use super::*;
+    use crate::blockcfg::{Block, BlockBuilder, BlockDate};
+    use chain_addr::Discrimination;
+    use chain_core::property::{Block as _, Serialize as _};
+    use chain_crypto::{Ed25519Extended, KeyPair};
+    use chain_impl_mockchain::{
+        block::ConsensusVersion,
+        config::{Block0Date, ConfigParam},
+        leadership::bft::LeaderId,
+        message::{ConfigParams, Message},
+        milli::Milli,
+    };
+    use chain_storage::memory::MemoryBlockStore;
+    use rand::SeedableRng;
+    use rand_chacha::ChaChaRng;
+
+    fn ledger() -> Ledger {
+        let leader: KeyPair<Ed25519Extended> =
+            KeyPair::generate(&mut ChaChaRng::from_seed([1; 32]));
+        let mut params = ConfigParams::new();
+        params.push(ConfigParam::Block0Date(Block0Date(0)));
+        params.push(ConfigParam::Discrimination(Discrimination::Test));
+        params.push(ConfigParam::ConsensusVersion(ConsensusVersion::Bft));
+        params.push(ConfigParam::SlotDuration(10));
+        params.push(ConfigParam::AddBftLeader(LeaderId::from(
+            leader.public_key().clone(),
+        )));
+        params.push(ConfigParam::ConsensusGenesisPraosActiveSlotsCoeff(
+            Milli::from_millis(500),
+        ));
+        params.push(ConfigParam::KESUpdateSpeed(3600));
+        Ledger::new(HeaderHash::hash_bytes(&[0]), &[Message::Initial(params)]).unwrap()
+    }
+
+    fn snapshot(n: u8) -> Snapshot {
+        Snapshot {
+            epoch: n as Epoch,
+            block_hash: HeaderHash::hash_bytes(&[n]),
+            ledger: ledger(),
+            leadership_ledger: ledger(),
+        }
+    }
+
+    fn block(parent: &Block, epoch: Epoch) -> Block {
+        let mut builder = BlockBuilder::new();
+        builder
+            .date(BlockDate { epoch, slot_id: 0 })
+            .parent(parent.id())
+            .chain_length(parent.chain_length().next());
+        builder.make_genesis_block()
+    }
+
+    fn snapshot_at(block: &Block) -> Snapshot {
+        Snapshot {
+            epoch: block.date().epoch,
+            block_hash: block.id(),
+            ledger: ledger(),
+            leadership_ledger: ledger(),
+        }
+    }
+
+    #[test]
+    fn save_and_load() {
+        let directory = mktemp::Temp::new_dir().unwrap();
+        let snapshots = Snapshots::new(directory.to_path_buf());
+        let saved = snapshot(1);
+
+        snapshots.save(&saved).unwrap();
+        let loaded = load_file(&snapshots.path_of(saved.epoch, &saved.block_hash)).unwrap();
+
+        assert_eq!(loaded.epoch, saved.epoch);
+        assert_eq!(loaded.block_hash, saved.block_hash);
+        assert_eq!(
+            loaded.ledger.serialize_as_vec().unwrap(),
+            saved.ledger.serialize_as_vec().unwrap()
+        );
+        assert_eq!(
+            loaded.leadership_ledger.serialize_as_vec().unwrap(),
+            saved.leadership_ledger.serialize_as_vec().unwrap()
+        );
+        // no temporary file is left behind
+        assert_eq!(fs::read_dir(&snapshots.directory).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn oldest_snapshots_are_pruned() {
+        let directory = mktemp::Temp::new_dir().unwrap();
+        let snapshots = Snapshots::new(directory.to_path_buf());
+
+        // saved out of order: the epochs decide which ones are kept
+        let epochs = [3, 0, 5, 1, 4, 2];
+        for n in epochs.iter() {
+            snapshots.save(&snapshot(*n)).unwrap();
+        }
+
+        let mut kept = snapshots.list().unwrap();
+        kept.sort();
+        let newest: Vec<PathBuf> = (2..6)
+            .map(|n| snapshots.path_of(n as Epoch, &HeaderHash::hash_bytes(&[n])))
+            .collect();
+        assert_eq!(kept, newest);
+    }
+
+    #[test]
+    fn snapshot_off_the_tip_branch_is_skipped() {
+        let directory = mktemp::Temp::new_dir().unwrap();
+        let snapshots = Snapshots::new(directory.to_path_buf());
+        let mut storage: NodeStorage = Box::new(MemoryBlockStore::new());
+
+        // block0 <- a1 <- a2 (tip)
+        //            ^--- f3
+        let block0 = BlockBuilder::new().make_genesis_block();
+        let a1 = block(&block0, 1);
+        let a2 = block(&a1, 2);
+        let f3 = block(&a1, 3);
+        for block in &[&block0, &a1, &a2, &f3] {
+            storage.put_block(block).unwrap();
+        }
+        snapshots.save(&snapshot_at(&a1)).unwrap();
+        snapshots.save(&snapshot_at(&f3)).unwrap();
+
+        let loaded = snapshots.load_latest(&storage, &a2.id()).unwrap().unwrap();
+        assert_eq!(loaded.block_hash, a1.id());
+    }
+}
@@ -69,6 +69,18 @@ impl Tip {
         Ok(self.branch.read()?.chain_length().clone())
     }
 
+    /// get a copy of the branch of the Tip
+    ///
+    /// # Error
+    ///
+    /// This function might return an error if the underlying lock is
+    /// poisoned.
+    ///
+    #[inline]
+    pub fn branch(&self) -> Result<Branch, TipGetError> {
+        Ok(self.branch.read()?.clone())
+    }
+
     /// get the ledger of the Tip
     ///
     /// # Error
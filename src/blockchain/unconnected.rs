@@ -0,0 +1,338 @@
+//! Pool of the blocks that are not (yet) connected to the blockchain.
+//!
+//! A block whose parent is not known is kept here until the parent
+//! arrives, the block is then processed as any other incoming block.
+//! Because any peer can send us such blocks, the pool is bounded:
+//!
+//! * entries expire after a given age;
+//! * a peer may not have more than a given number of blocks in the pool,
+//!   the new blocks of a peer above its quota are rejected;
+//! * the pool may not hold more than a given number of blocks in total,
+//!   the oldest blocks are evicted to make room for the new ones.
+
+use crate::{
+    blockcfg::{Block, HeaderHash},
+    network::p2p::topology::NodeId,
+};
+use chain_core::property::Block as _;
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::{Duration, Instant},
+};
+
+/// Identify who sent us the block, `None` if we don't know
+/// (e.g. blocks received during the bootstrap).
+pub type BlockOrigin = Option<NodeId>;
+
+/// the capacity of the pool
+#[derive(Debug, Clone, Copy)]
+pub struct UnconnectedBlocksLimits {
+    /// maximum time a block stays in the pool
+    pub max_age: Duration,
+    /// maximum number of blocks in the pool
+    pub max_blocks: usize,
+    /// maximum number of blocks one peer may have in the pool
+    pub max_blocks_per_peer: usize,
+}
+
+/// the arrival time of a block, the sequence number tells apart the
+/// blocks received at the same instant
+type TimeKey = (Instant, u64);
+
+struct Entry {
+    block: Block,
+    origin: BlockOrigin,
+    received_at: TimeKey,
+}
+
+/// the outcome of adding a block in the pool
+#[derive(Debug, PartialEq, Eq)]
+pub enum Insertion {
+    /// the block has been added in the pool
+    Added,
+    /// the block was already in the pool
+    AlreadyPresent,
+    /// the peer that sent the block has reached its quota
+    QuotaExceeded,
+}
+
+/// counters of the activity of the pool, since the node started
+#[derive(Clone, Copy, Debug, Default)]
+pub struct UnconnectedBlocksStats {
+    /// the number of blocks currently in the pool
+    pub blocks: usize,
+    /// the blocks removed because they were too old
+    pub expired: u64,
+    /// the blocks removed to make room for new blocks
+    pub evicted: u64,
+    /// the blocks refused because their sender exceeded its quota
+    pub rejected: u64,
+    /// the blocks connected to the blockchain once their parent arrived
+    pub connected: u64,
+}
+
+pub struct UnconnectedBlocks {
+    /// Incoming blocks whose parent does not exist yet. Sorted by
+    /// parent hash to allow quick look up of the children of a
+    /// parent.
+    by_parent: BTreeMap<HeaderHash, BTreeMap<HeaderHash, Entry>>,
+
+    /// the blocks in order of arrival, as `(parent, block)`
+    by_time: BTreeMap<TimeKey, (HeaderHash, HeaderHash)>,
+    /// the sequence number of the next block
+    next_seq: u64,
+
+    per_peer: HashMap<BlockOrigin, usize>,
+
+    limits: UnconnectedBlocksLimits,
+
+    stats: UnconnectedBlocksStats,
+}
+
+impl UnconnectedBlocks {
+    pub fn new(limits: UnconnectedBlocksLimits) -> Self {
+        UnconnectedBlocks {
+            by_parent: BTreeMap::new(),
+            by_time: BTreeMap::new(),
+            next_seq: 0,
+            per_peer: HashMap::new(),
+            limits,
+            stats: UnconnectedBlocksStats::default(),
+        }
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.stats.blocks
+    }
+
+    #[inline]
+    pub fn stats(&self) -> UnconnectedBlocksStats {
+        self.stats
+    }
+
+    /// add a block whose parent is not known in the pool
+    pub fn insert(&mut self, block: Block, origin: BlockOrigin) -> Insertion {
+        let now = Instant::now();
+        self.purge_expired(now);
+
+        let parent = block.parent_id();
+        let id = block.id();
+
+        if self.contains(&parent, &id) {
+            return Insertion::AlreadyPresent;
+        }
+
+        if self.per_peer.get(&origin).cloned().unwrap_or(0) >= self.limits.max_blocks_per_peer {
+            self.stats.rejected += 1;
+            return Insertion::QuotaExceeded;
+        }
+
+        while self.stats.blocks >= self.limits.max_blocks {
+            let (parent, id) = match self.by_time.values().next() {
+                None => break,
+                Some(oldest) => *oldest,
+            };
+            self.remove(&parent, &id);
+            self.stats.evicted += 1;
+        }
+
+        let received_at = (now, self.next_seq);
+        self.next_seq += 1;
+        self.by_parent
+            .entry(parent)
+            .or_insert(BTreeMap::new())
+            .insert(
+                id,
+                Entry {
+                    block,
+                    origin,
+                    received_at,
+                },
+            );
+        self.by_time.insert(received_at, (parent, id));
+        *self.per_peer.entry(origin).or_insert(0) += 1;
+        self.stats.blocks += 1;
+
+        Insertion::Added
+    }
+
+    /// remove and return the blocks whose parent is the given block
+    pub fn take_children(&mut self, parent: &HeaderHash) -> Vec<(Block, BlockOrigin)> {
+        let children = match self.by_parent.remove(parent) {
+            None => return Vec::new(),
+            Some(children) => children,
+        };
+
+        children
+            .into_iter()
+            .map(|(_, entry)| {
+                self.by_time.remove(&entry.received_at);
+                self.release(&entry.origin);
+                self.stats.connected += 1;
+                (entry.block, entry.origin)
+            })
+            .collect()
+    }
+
+    /// remove the blocks that have been in the pool for too long
+    pub fn purge_expired(&mut self, now: Instant) {
+        while let Some((&(received_at, _), &(parent, id))) = self.by_time.iter().next() {
+            if now.duration_since(received_at) < self.limits.max_age {
+                break;
+            }
+            self.remove(&parent, &id);
+            self.stats.expired += 1;
+        }
+    }
+
+    fn contains(&self, parent: &HeaderHash, id: &HeaderHash) -> bool {
+        self.by_parent
+            .get(parent)
+            .map_or(false, |children| children.contains_key(id))
+    }
+
+    fn remove(&mut self, parent: &HeaderHash, id: &HeaderHash) -> Option<Block> {
+        let (entry, now_empty) = {
+            let children = self.by_parent.get_mut(parent)?;
+            let entry = children.remove(id)?;
+            (entry, children.is_empty())
+        };
+        if now_empty {
+            self.by_parent.remove(parent);
+        }
+        self.by_time.remove(&entry.received_at);
+        self.release(&entry.origin);
+        Some(entry.block)
+    }
+
+    fn release(&mut self, origin: &BlockOrigin) {
+        let now_empty = match self.per_peer.get_mut(origin) {
+            None => false,
+            Some(count) => {
+                *count -= 1;
+                *count == 0
+            }
+        };
+        if now_empty {
+            self.per_peer.remove(origin);
+        }
+        self.stats.blocks -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockcfg::{BlockBuilder, BlockDate};
+
+    fn limits() -> UnconnectedBlocksLimits {
+        UnconnectedBlocksLimits {
+            max_age: Duration::from_secs(600),
+            max_blocks: 4,
+            max_blocks_per_peer: 3,
+        }
+    }
+
+    fn child(parent: &Block, slot_id: u32) -> Block {
+        let mut builder = BlockBuilder::new();
+        builder
+            .date(BlockDate { epoch: 0, slot_id })
+            .parent(parent.id())
+            .chain_length(parent.chain_length().next());
+        builder.make_genesis_block()
+    }
+
+    fn peer() -> BlockOrigin {
+        Some(NodeId::generate())
+    }
+
+    #[test]
+    fn insert_and_take_children() {
+        let mut pool = UnconnectedBlocks::new(limits());
+        let parent = BlockBuilder::new().make_genesis_block();
+        let a = child(&parent, 1);
+        let b = child(&parent, 2);
+        let peer_1 = peer();
+
+        assert_eq!(pool.insert(a.clone(), peer_1), Insertion::Added);
+        assert_eq!(pool.insert(b.clone(), None), Insertion::Added);
+        assert_eq!(pool.insert(a.clone(), peer_1), Insertion::AlreadyPresent);
+        assert_eq!(pool.len(), 2);
+
+        let mut children: Vec<HeaderHash> = pool
+            .take_children(&parent.id())
+            .into_iter()
+            .map(|(block, _)| block.id())
+            .collect();
+        children.sort();
+        let mut expected = vec![a.id(), b.id()];
+        expected.sort();
+        assert_eq!(children, expected);
+
+        assert_eq!(pool.len(), 0);
+        assert_eq!(pool.stats().connected, 2);
+        assert!(pool.by_time.is_empty());
+        assert!(pool.per_peer.is_empty());
+        assert!(pool.take_children(&parent.id()).is_empty());
+    }
+
+    #[test]
+    fn quota_per_peer() {
+        let mut pool = UnconnectedBlocks::new(limits());
+        let parent = BlockBuilder::new().make_genesis_block();
+        let peer_1 = peer();
+
+        for slot_id in 0..3 {
+            assert_eq!(
+                pool.insert(child(&parent, slot_id), peer_1),
+                Insertion::Added
+            );
+        }
+        assert_eq!(
+            pool.insert(child(&parent, 3), peer_1),
+            Insertion::QuotaExceeded
+        );
+        assert_eq!(pool.insert(child(&parent, 3), peer()), Insertion::Added);
+        assert_eq!(pool.stats().rejected, 1);
+    }
+
+    #[test]
+    fn oldest_blocks_are_evicted() {
+        let mut pool = UnconnectedBlocks::new(limits());
+        let parent = BlockBuilder::new().make_genesis_block();
+        let other_parent = child(&parent, 100);
+
+        pool.insert(child(&parent, 0), peer());
+        let peer_2 = peer();
+        for slot_id in 1..4 {
+            pool.insert(child(&other_parent, slot_id), peer_2);
+        }
+        // the children taken out must not be evicted again
+        pool.take_children(&other_parent.id());
+        let peer_3 = peer();
+        for slot_id in 4..7 {
+            pool.insert(child(&other_parent, slot_id), peer_3);
+        }
+        assert_eq!(pool.len(), 4);
+        assert_eq!(pool.stats().evicted, 0);
+
+        pool.insert(child(&other_parent, 7), None);
+        assert_eq!(pool.len(), 4);
+        assert_eq!(pool.stats().evicted, 1);
+        assert!(pool.take_children(&parent.id()).is_empty());
+        assert_eq!(pool.by_time.len(), 4);
+    }
+
+    #[test]
+    fn old_blocks_expire() {
+        let mut pool = UnconnectedBlocks::new(limits());
+        let parent = BlockBuilder::new().make_genesis_block();
+        pool.insert(child(&parent, 0), peer());
+
+        pool.purge_expired(Instant::now() + Duration::from_secs(601));
+        assert_eq!(pool.len(), 0);
+        assert_eq!(pool.stats().expired, 1);
+        assert!(pool.by_parent.is_empty());
+    }
+}
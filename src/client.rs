@@ -1,35 +1,145 @@
+//! Handling of the queries of the peers on the blockchain.
+//!
+//! The chain ranges are served as follow:
+//!
+//! * a range pulled from checkpoints (`GetHeadersRange`, `PullBlocks`,
+//!   `PullHeaders` and the `*ToTip` variants) starts right after the
+//!   newest of the checkpoints that is an ancestor of the requested end
+//!   of the range, and includes the end of the range. Checkpoints we do
+//!   not know about (blocks of a fork we never saw) are ignored. A peer
+//!   sending no checkpoint at all receives the chain from block0
+//!   included;
+//! * a range between two given blocks (`GetBlocksRange`) includes both
+//!   ends.
+//!
+//! The blocks of a range are resolved at once, under the storage lock,
+//! so the peer receives a consistent chain even if the tip changes in
+//! the meantime. The blocks are then read lazily, as the peer consumes
+//! the reply: neither the blockchain nor the storage is kept locked
+//! while streaming, and a slow peer does not have the chain buffered in
+//! memory.
+//!
+//! Every access to the storage is run with `tokio_threadpool::blocking`,
+//! so the reads of the disk do not stall the runtime's worker thread.
+
 use crate::blockcfg::{Block, Header, HeaderHash};
 use crate::blockchain::BlockchainR;
-use crate::intercom::{do_stream_reply, ClientMsg, Error, ReplyStreamHandle};
-use crate::utils::task::{Input, ThreadServiceInfo};
-use chain_core::property::{Block as _, HasHeader as _};
+use crate::intercom::{ClientMsg, Error, ReplyHandle, ReplyStreamHandle};
+use crate::start_up::NodeStorage;
+use crate::utils::task::{Input, TokioServiceInfo};
+use chain_core::property::HasHeader as _;
+use futures::future;
+use futures::prelude::*;
+use futures::stream;
+use std::sync::{Arc, RwLock};
+use tokio_threadpool::blocking;
 
-pub fn handle_input(_info: &ThreadServiceInfo, blockchain: &BlockchainR, input: Input<ClientMsg>) {
+pub fn handle_input(
+    info: &TokioServiceInfo,
+    blockchain: &BlockchainR,
+    input: Input<ClientMsg>,
+) -> Box<Future<Item = (), Error = ()> + Send> {
     let cquery = match input {
-        Input::Shutdown => return,
+        Input::Shutdown => return Box::new(future::ok(())),
         Input::Input(msg) => msg,
     };
+    let blockchain = blockchain.clone();
 
     match cquery {
-        ClientMsg::GetBlockTip(handler) => handler.reply(handle_get_block_tip(&blockchain)),
-        ClientMsg::GetHeaders(ids, handler) => do_stream_reply(handler, |handler| {
-            handle_get_headers(&blockchain, ids, handler)
-        }),
-        ClientMsg::GetHeadersRange(checkpoints, to, handler) => {
-            handler.reply(handle_get_headers_range(&blockchain, checkpoints, to))
+        ClientMsg::GetBlockTip(handler) => Box::new(reply(
+            handler,
+            run_blocking(move || handle_get_block_tip(&blockchain)),
+        )),
+        ClientMsg::GetHeaders(ids, handler) => {
+            let storage = blockchain.lock_read().storage.clone();
+            info.spawn(handler.forward(header_stream(storage, ids)));
+            Box::new(future::ok(()))
+        }
+        ClientMsg::GetHeadersRange(checkpoints, to, handler) => Box::new(reply(
+            handler,
+            run_blocking(move || handle_get_headers_range(&blockchain, checkpoints, to)),
+        )),
+        ClientMsg::GetBlocks(ids, handler) => {
+            let storage = blockchain.lock_read().storage.clone();
+            info.spawn(handler.forward(block_stream(storage, ids)));
+            Box::new(future::ok(()))
         }
-        ClientMsg::GetBlocks(ids, handler) => do_stream_reply(handler, |handler| {
-            handle_get_blocks(&blockchain, ids, handler)
-        }),
-        ClientMsg::GetBlocksRange(from, to, handler) => do_stream_reply(handler, |handler| {
-            handle_get_blocks_range(&blockchain, from, to, handler)
-        }),
-        ClientMsg::PullBlocksToTip(from, handler) => do_stream_reply(handler, |handler| {
-            handle_pull_blocks_to_tip(&blockchain, from, handler)
-        }),
+        ClientMsg::GetBlocksRange(from, to, handler) => Box::new(spawn_stream_reply(
+            handler,
+            run_blocking(move || handle_get_blocks_range(&blockchain, from, to)),
+        )),
+        ClientMsg::PullBlocksToTip(from, handler) => Box::new(spawn_stream_reply(
+            handler,
+            run_blocking(move || {
+                let to = blockchain.lock_read().get_tip().unwrap();
+                handle_pull_blocks(&blockchain, from, to)
+            }),
+        )),
+        ClientMsg::PullBlocks(from, to, handler) => Box::new(spawn_stream_reply(
+            handler,
+            run_blocking(move || handle_pull_blocks(&blockchain, from, to)),
+        )),
+        ClientMsg::PullHeaders(from, to, handler) => Box::new(spawn_stream_reply(
+            handler,
+            run_blocking(move || handle_pull_headers(&blockchain, from, to)),
+        )),
+        ClientMsg::PullHeadersToTip(from, handler) => Box::new(spawn_stream_reply(
+            handler,
+            run_blocking(move || {
+                let to = blockchain.lock_read().get_tip().unwrap();
+                handle_pull_headers(&blockchain, from, to)
+            }),
+        )),
     }
 }
 
+fn reply<T, F>(handler: ReplyHandle<T>, res: F) -> impl Future<Item = (), Error = ()> + Send
+where
+    F: Future<Item = T, Error = Error> + Send,
+    T: Send,
+{
+    res.then(move |res| {
+        handler.reply(res);
+        Ok(())
+    })
+}
+
+/// once the range is resolved, stream the reply in a task of its own
+/// so the next queries do not wait for the peer to consume it
+fn spawn_stream_reply<T, S, F>(
+    handler: ReplyStreamHandle<T>,
+    range: F,
+) -> impl Future<Item = (), Error = ()> + Send
+where
+    F: Future<Item = S, Error = Error> + Send,
+    S: Stream<Item = T, Error = Error> + Send + 'static,
+    T: Send + 'static,
+{
+    range.then(move |res| {
+        match res {
+            Ok(stream) => tokio::spawn(handler.forward(stream)),
+            Err(e) => tokio::spawn(handler.reply_error(e)),
+        };
+        Ok(())
+    })
+}
+
+/// run the given storage access on the blocking threads of the runtime
+fn run_blocking<T, F>(f: F) -> impl Future<Item = T, Error = Error> + Send
+where
+    F: FnOnce() -> Result<T, Error> + Send,
+    T: Send,
+{
+    // `blocking` only calls the function once there is a blocking
+    // thread available for it, until then the future is polled again
+    let mut f = Some(f);
+    future::poll_fn(move || {
+        let res = try_ready!(blocking(|| (f.take().unwrap())())
+            .map_err(|e| Error::failed(format!("cannot access the storage: {}", e))));
+        res.map(Async::Ready)
+    })
+}
+
 fn handle_get_block_tip(blockchain: &BlockchainR) -> Result<Header, Error> {
     let blockchain = blockchain.lock_read();
     let tip = blockchain.get_tip().unwrap();
@@ -44,151 +154,316 @@ fn handle_get_block_tip(blockchain: &BlockchainR) -> Result<Header, Error> {
 }
 
 const MAX_HEADERS: usize = 2000;
+const MAX_BLOCKS: usize = 500;
+
+/// Find the newest of the `checkpoints` on the chain ending at `to`.
+///
+/// `to` itself is a valid checkpoint, the resulting range is then empty.
+fn find_latest_checkpoint(
+    storage: &NodeStorage,
+    checkpoints: &[HeaderHash],
+    to: &HeaderHash,
+) -> Result<HeaderHash, Error> {
+    let mut best: Option<(u64, HeaderHash)> = None;
+    for checkpoint in checkpoints {
+        // the peer may be on a fork we do not know about
+        if !storage.block_exists(checkpoint)? {
+            continue;
+        }
+        if let Some(distance) = storage.is_ancestor(checkpoint, to)? {
+            if best.map_or(true, |(best_distance, _)| distance < best_distance) {
+                best = Some((distance, *checkpoint));
+            }
+        }
+    }
+
+    best.map(|(_, checkpoint)| checkpoint).ok_or_else(|| {
+        Error::not_found(format!(
+            "none of the checkpoints is an ancestor of block '{}'",
+            to
+        ))
+    })
+}
+
+/// Resolve the hashes of the blocks to serve for a request pulling the
+/// chain ending at `to` from the given checkpoints.
+///
+/// At most `max_len` hashes are returned, the peer is expected to pull
+/// the rest of the chain with a new request.
+fn resolve_pull_range(
+    storage: &NodeStorage,
+    checkpoints: &[HeaderHash],
+    to: &HeaderHash,
+    max_len: usize,
+) -> Result<Vec<HeaderHash>, Error> {
+    let mut range = Vec::new();
+    let from = if checkpoints.is_empty() {
+        let to_info = storage.get_block_info(to)?;
+        let block0 = storage.get_nth_ancestor(to, to_info.depth)?.block_hash;
+        range.push(block0);
+        block0
+    } else {
+        find_latest_checkpoint(storage, checkpoints, to)?
+    };
+
+    for info in storage.iterate_range(&from, to)? {
+        if range.len() >= max_len {
+            break;
+        }
+        range.push(info?.block_hash);
+    }
+    range.truncate(max_len);
+
+    Ok(range)
+}
+
+/// Resolve the hashes of the blocks from `from` to `to`, both included.
+fn resolve_range(
+    storage: &NodeStorage,
+    from: &HeaderHash,
+    to: &HeaderHash,
+) -> Result<Vec<HeaderHash>, Error> {
+    if storage.is_ancestor(from, to)?.is_none() {
+        return Err(Error::not_found(format!(
+            "block '{}' is not an ancestor of block '{}'",
+            from, to
+        )));
+    }
+
+    let mut range = vec![*from];
+    for info in storage.iterate_range(from, to)? {
+        range.push(info?.block_hash);
+    }
+
+    Ok(range)
+}
+
+/// read the given blocks one at a time, as the stream is polled
+fn block_stream(
+    storage: Arc<RwLock<NodeStorage>>,
+    ids: Vec<HeaderHash>,
+) -> impl Stream<Item = Block, Error = Error> + Send {
+    stream::iter_ok(ids).and_then(move |id| {
+        let storage = storage.clone();
+        run_blocking(move || {
+            let (blk, _) = storage.read().unwrap().get_block(&id)?;
+            Ok(blk)
+        })
+    })
+}
+
+fn header_stream(
+    storage: Arc<RwLock<NodeStorage>>,
+    ids: Vec<HeaderHash>,
+) -> impl Stream<Item = Header, Error = Error> + Send {
+    block_stream(storage, ids).map(|blk| blk.header())
+}
 
 fn handle_get_headers_range(
     blockchain: &BlockchainR,
     checkpoints: Vec<HeaderHash>,
     to: HeaderHash,
 ) -> Result<Vec<Header>, Error> {
-    let blockchain = blockchain.lock_read();
-
-    /* Filter out the checkpoints that don't exist and sort them by
-     * block date. */
-    let mut checkpoints = checkpoints
-        .iter()
-        .filter_map(
-            |checkpoint| match blockchain.storage.read().unwrap().get_block(&checkpoint) {
-                Err(_) => None,
-                Ok((blk, _)) => Some((blk.date(), checkpoint)),
-            },
-        )
-        .collect::<Vec<_>>();
-
-    if !checkpoints.is_empty() {
-        /* Start at the newest checkpoint. */
-        checkpoints.sort_unstable();
-        let from = checkpoints.last().unwrap().1;
-
-        // FIXME: handle checkpoint == genesis
-
-        /* Send headers up to the maximum. */
-        let mut headers = vec![];
-        for x in blockchain
-            .storage
-            .read()
-            .unwrap()
-            .iterate_range(&from, &to)?
-        {
-            match x {
-                Err(err) => return Err(Error::from(err)),
-                Ok(info) => {
-                    let (block, _) = blockchain
-                        .storage
-                        .read()
-                        .unwrap()
-                        .get_block(&info.block_hash)?;
-                    headers.push(block.header());
-                    if headers.len() >= MAX_HEADERS {
-                        break;
-                    }
-                }
-            };
-        }
+    let storage = blockchain.lock_read().storage.clone();
+    let range = resolve_pull_range(&storage.read().unwrap(), &checkpoints, &to, MAX_HEADERS)?;
 
-        Ok(headers)
-    } else {
-        Ok(vec![])
+    let mut headers = Vec::with_capacity(range.len());
+    for id in range {
+        let (blk, _) = storage.read().unwrap().get_block(&id)?;
+        headers.push(blk.header());
     }
+
+    Ok(headers)
 }
 
 fn handle_get_blocks_range(
     blockchain: &BlockchainR,
     from: HeaderHash,
     to: HeaderHash,
-    reply: &mut ReplyStreamHandle<Block>,
-) -> Result<(), Error> {
-    let blockchain = blockchain.lock_read();
+) -> Result<impl Stream<Item = Block, Error = Error> + Send, Error> {
+    let storage = blockchain.lock_read().storage.clone();
+    let range = resolve_range(&storage.read().unwrap(), &from, &to)?;
+    Ok(block_stream(storage, range))
+}
 
-    // FIXME: include the from block
-    for x in blockchain
-        .storage
-        .read()
-        .unwrap()
-        .iterate_range(&from, &to)?
-    {
-        let info = x?;
-        let (blk, _) = blockchain
-            .storage
-            .read()
-            .unwrap()
-            .get_block(&info.block_hash)?;
-        reply.send(blk);
-    }
-
-    Ok(())
+fn handle_pull_blocks(
+    blockchain: &BlockchainR,
+    from: Vec<HeaderHash>,
+    to: HeaderHash,
+) -> Result<impl Stream<Item = Block, Error = Error> + Send, Error> {
+    let storage = blockchain.lock_read().storage.clone();
+    let range = resolve_pull_range(&storage.read().unwrap(), &from, &to, MAX_BLOCKS)?;
+    Ok(block_stream(storage, range))
 }
 
-fn handle_get_blocks(
+fn handle_pull_headers(
     blockchain: &BlockchainR,
-    ids: Vec<HeaderHash>,
-    reply: &mut ReplyStreamHandle<Block>,
-) -> Result<(), Error> {
-    let blockchain = blockchain.lock_read();
+    from: Vec<HeaderHash>,
+    to: HeaderHash,
+) -> Result<impl Stream<Item = Header, Error = Error> + Send, Error> {
+    let storage = blockchain.lock_read().storage.clone();
+    let range = resolve_pull_range(&storage.read().unwrap(), &from, &to, MAX_HEADERS)?;
+    Ok(header_stream(storage, range))
+}
 
-    for id in ids.into_iter() {
-        let (blk, _) = blockchain.storage.read().unwrap().get_block(&id)?;
-        reply.send(blk);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockcfg::{BlockBuilder, BlockDate};
+    use chain_core::property::Block as _;
+    use chain_storage::memory::MemoryBlockStore;
+    use network_core::error::Code;
+
+    fn child(parent: &Block, slot_id: u32) -> Block {
+        let mut builder = BlockBuilder::new();
+        builder
+            .date(BlockDate { epoch: 0, slot_id })
+            .parent(parent.id())
+            .chain_length(parent.chain_length().next());
+        builder.make_genesis_block()
     }
 
-    Ok(())
-}
+    /// the blocks of the main chain `b0 - a1 - a2 - a3 - a4` and of the
+    /// fork `a2 - f3 - f4`, stored in the given storage.
+    struct Chain {
+        main: Vec<HeaderHash>,
+        fork: Vec<HeaderHash>,
+    }
 
-fn handle_get_headers(
-    blockchain: &BlockchainR,
-    ids: Vec<HeaderHash>,
-    reply: &mut ReplyStreamHandle<Header>,
-) -> Result<(), Error> {
-    let blockchain = blockchain.lock_read();
+    fn populate(storage: &mut NodeStorage) -> Chain {
+        let block0 = BlockBuilder::new().make_genesis_block();
+        storage.put_block(&block0).unwrap();
+
+        let mut main = vec![block0];
+        for slot_id in 1..5 {
+            let block = child(main.last().unwrap(), slot_id);
+            storage.put_block(&block).unwrap();
+            main.push(block);
+        }
 
-    for id in ids.into_iter() {
-        let (blk, _) = blockchain.storage.read().unwrap().get_block(&id)?;
-        reply.send(blk.header());
+        let mut fork = vec![main[2].clone()];
+        for slot_id in 3..5 {
+            // shifted date, so the fork's blocks differ from the main chain's
+            let block = child(fork.last().unwrap(), slot_id + 10);
+            storage.put_block(&block).unwrap();
+            fork.push(block);
+        }
+        fork.remove(0);
+
+        Chain {
+            main: main.iter().map(|block| block.id()).collect(),
+            fork: fork.iter().map(|block| block.id()).collect(),
+        }
     }
 
-    Ok(())
-}
+    fn setup() -> (NodeStorage, Chain) {
+        let mut storage: NodeStorage = Box::new(MemoryBlockStore::new());
+        let chain = populate(&mut storage);
+        (storage, chain)
+    }
 
-fn handle_pull_blocks_to_tip(
-    blockchain: &BlockchainR,
-    mut from: Vec<HeaderHash>,
-    reply: &mut ReplyStreamHandle<Block>,
-) -> Result<(), Error> {
-    let blockchain = blockchain.lock_read();
+    fn expect_not_found(result: Result<Vec<HeaderHash>, Error>) {
+        match result {
+            Err(err) => match err.code() {
+                Code::NotFound => {}
+                _ => panic!("unexpected error: {}", err),
+            },
+            Ok(range) => panic!("unexpected range: {:?}", range),
+        }
+    }
+
+    #[test]
+    fn pull_range_excludes_the_checkpoint_and_includes_the_end() {
+        let (storage, chain) = setup();
+        let range = resolve_pull_range(&storage, &chain.main[1..2], &chain.main[4], 100);
+        assert_eq!(range.unwrap(), &chain.main[2..]);
+    }
+
+    #[test]
+    fn pull_range_from_block0() {
+        let (storage, chain) = setup();
+        let range = resolve_pull_range(&storage, &chain.main[0..1], &chain.main[4], 100);
+        assert_eq!(range.unwrap(), &chain.main[1..]);
+    }
 
-    // FIXME: handle multiple from addresses
-    if from.len() != 1 {
-        return Err(Error::unimplemented(
-            "only one checkpoint address is currently supported",
+    #[test]
+    fn pull_range_without_checkpoints_includes_block0() {
+        let (storage, chain) = setup();
+        let range = resolve_pull_range(&storage, &[], &chain.main[4], 100);
+        assert_eq!(range.unwrap(), chain.main);
+    }
+
+    #[test]
+    fn pull_range_to_a_checkpoint_is_empty() {
+        let (storage, chain) = setup();
+        let range = resolve_pull_range(&storage, &chain.main[4..], &chain.main[4], 100);
+        assert!(range.unwrap().is_empty());
+    }
+
+    #[test]
+    fn pull_range_uses_the_newest_checkpoint_on_the_chain() {
+        let (storage, chain) = setup();
+        // the fork's tip is newer than a2 but is not on the main chain
+        let checkpoints = vec![chain.main[0], chain.fork[1], chain.main[2], chain.main[1]];
+        let range = resolve_pull_range(&storage, &checkpoints, &chain.main[4], 100);
+        assert_eq!(range.unwrap(), &chain.main[3..]);
+    }
+
+    #[test]
+    fn pull_range_to_a_fork() {
+        let (storage, chain) = setup();
+        let checkpoints = vec![chain.main[4], chain.main[3], chain.main[1]];
+        let range = resolve_pull_range(&storage, &checkpoints, &chain.fork[1], 100);
+        let mut expected = vec![chain.main[2]];
+        expected.extend_from_slice(&chain.fork);
+        assert_eq!(range.unwrap(), expected);
+    }
+
+    #[test]
+    fn pull_range_ignores_unknown_checkpoints() {
+        let (storage, chain) = setup();
+        let checkpoints = vec![HeaderHash::hash_bytes(&[42]), chain.main[3]];
+        let range = resolve_pull_range(&storage, &checkpoints, &chain.main[4], 100);
+        assert_eq!(range.unwrap(), &chain.main[4..]);
+    }
+
+    #[test]
+    fn pull_range_without_common_checkpoint() {
+        let (storage, chain) = setup();
+        let checkpoints = vec![HeaderHash::hash_bytes(&[42]), chain.fork[0]];
+        expect_not_found(resolve_pull_range(
+            &storage,
+            &checkpoints,
+            &chain.main[4],
+            100,
         ));
     }
-    let from = from.remove(0);
 
-    let tip = blockchain.get_tip().unwrap();
+    #[test]
+    fn pull_range_is_bounded() {
+        let (storage, chain) = setup();
+        let range = resolve_pull_range(&storage, &chain.main[0..1], &chain.main[4], 2);
+        assert_eq!(range.unwrap(), &chain.main[1..3]);
+        let range = resolve_pull_range(&storage, &[], &chain.main[4], 2);
+        assert_eq!(range.unwrap(), &chain.main[0..2]);
+    }
 
-    for x in blockchain
-        .storage
-        .read()
-        .unwrap()
-        .iterate_range(&from, &tip)?
-    {
-        let info = x?;
-        let (blk, _) = blockchain
-            .storage
-            .read()
-            .unwrap()
-            .get_block(&info.block_hash)?;
-        reply.send(blk);
-    }
-
-    Ok(())
+    #[test]
+    fn range_includes_both_ends() {
+        let (storage, chain) = setup();
+        let range = resolve_range(&storage, &chain.main[0], &chain.main[3]);
+        assert_eq!(range.unwrap(), &chain.main[0..4]);
+        let range = resolve_range(&storage, &chain.main[2], &chain.main[2]);
+        assert_eq!(range.unwrap(), &chain.main[2..3]);
+    }
+
+    #[test]
+    fn range_across_a_fork() {
+        let (storage, chain) = setup();
+        let range = resolve_range(&storage, &chain.main[1], &chain.fork[1]);
+        let mut expected = chain.main[1..3].to_vec();
+        expected.extend_from_slice(&chain.fork);
+        assert_eq!(range.unwrap(), expected);
+        expect_not_found(resolve_range(&storage, &chain.fork[0], &chain.main[4]));
+    }
 }
@@ -1,7 +1,8 @@
 use crate::{
-    blockcfg::{Value, ValueError},
-    fragment::{Fragment, FragmentId},
+    blockcfg::{LedgerParameters, Message, Value, ValueError},
+    fragment::{validation::ValidationError, Fragment, FragmentId, Origin},
 };
+use chain_impl_mockchain::transaction::{AccountIdentifier, InputEnum, TransactionId};
 use std::time::SystemTime;
 
 pub struct PoolEntry {
@@ -14,6 +15,8 @@ pub struct PoolEntry {
     fragment_size: usize,
     /// time when the entry was added to the pool
     received_at: SystemTime,
+    /// where the fragment comes from
+    received_from: Origin,
     /// the fee of the accumulated descendant fragments
     /// does not include the fee of this entry
     descendants_fee: Value,
@@ -29,29 +32,33 @@ pub struct PoolEntry {
 }
 
 impl PoolEntry {
-    pub fn new(fragment: &Fragment) -> Self {
+    /// create the entry of the fragment, fails if its fee overflows
+    pub fn new(
+        fragment: &Fragment,
+        origin: Origin,
+        ledger_params: &LedgerParameters,
+    ) -> Result<Self, ValidationError> {
+        let fee = compute_fee(fragment, ledger_params)?;
+        Ok(Self::with_fee(fragment, origin, fee))
+    }
+
+    /// create the entry of a fragment paying the given fee
+    pub(super) fn with_fee(fragment: &Fragment, origin: Origin, fee: Value) -> Self {
         let raw = fragment.to_raw();
         let fragment_size = raw.size_bytes_plus_size();
         let fragment_ref = raw.id();
-        // TODO: the fragment fee is not yet computed. Yet we should
-        // have an explicit fee in the message. So we need to be able
-        // to extract this information without the need to compute the
-        // fee from the ledger's fee settings.
-        let fragment_fee = Value::zero();
 
         PoolEntry {
             fragment_ref: fragment_ref,
-            fragment_fee: fragment_fee,
+            fragment_fee: fee,
             fragment_size: fragment_size,
             received_at: SystemTime::now(),
+            received_from: origin,
 
-            // when this entry is added in the pool, it has no
-            // descendant
+            // the ancestors and the descendants are accounted for by
+            // the pool once the entry is inserted
             descendants_fee: Value::zero(),
             descendants_size: 0usize,
-
-            // when this entry is added to the pool, we need to know
-            // about the different entries in order to compute the following:
             ancestors_fee: Value::zero(),
             ancestors_size: 0usize,
         }
@@ -74,6 +81,26 @@ impl PoolEntry {
         &self.received_at
     }
     #[inline]
+    pub fn received_from(&self) -> &Origin {
+        &self.received_from
+    }
+    #[inline]
+    pub fn descendants_fee(&self) -> &Value {
+        &self.descendants_fee
+    }
+    #[inline]
+    pub fn descendants_size(&self) -> usize {
+        self.descendants_size
+    }
+    #[inline]
+    pub fn ancestors_fee(&self) -> &Value {
+        &self.ancestors_fee
+    }
+    #[inline]
+    pub fn ancestors_size(&self) -> usize {
+        self.ancestors_size
+    }
+    #[inline]
     pub fn with_descendants_fee(&self) -> Result<Value, ValueError> {
         self.descendants_fee + self.fragment_fee
     }
@@ -89,4 +116,121 @@ impl PoolEntry {
     pub fn with_ancestors_size(&self) -> usize {
         self.ancestors_size + self.fragment_size
     }
+
+    /// set the time the fragment was received at, when the entry is
+    /// restored from the saved pool
+    pub(super) fn set_received_at(&mut self, received_at: SystemTime) {
+        self.received_at = received_at;
+    }
+
+    pub(super) fn set_ancestors(&mut self, fee: Value, size: usize) {
+        self.ancestors_fee = fee;
+        self.ancestors_size = size;
+    }
+
+    pub(super) fn set_descendants(&mut self, fee: Value, size: usize) {
+        self.descendants_fee = fee;
+        self.descendants_size = size;
+    }
+}
+
+/// compute the fee of the fragment with the fee algorithm of the
+/// ledger. The fragments that are not transactions do not pay any fee.
+fn compute_fee(
+    fragment: &Fragment,
+    ledger_params: &LedgerParameters,
+) -> Result<Value, ValidationError> {
+    use chain_impl_mockchain::fee::FeeAlgorithm as _;
+
+    let fee = match fragment {
+        Message::Transaction(tx) => ledger_params.fees.calculate(&tx.transaction),
+        Message::Certificate(tx) => ledger_params.fees.calculate(&tx.transaction),
+        _ => Some(Value::zero()),
+    };
+    fee.ok_or(ValidationError::FeeOverflow)
+}
+
+/// the identifier of the transaction carried by the fragment, the
+/// outputs of the fragment are referenced by this identifier
+pub(super) fn transaction_id(fragment: &Fragment) -> Option<TransactionId> {
+    match fragment {
+        Message::Transaction(tx) => Some(tx.transaction.hash()),
+        Message::Certificate(tx) => Some(tx.transaction.hash()),
+        _ => None,
+    }
+}
+
+/// the identifiers of the transactions whose outputs are spent by the
+/// fragment.
+///
+/// The spendings from accounts do not reference a transaction, see
+/// `spent_accounts`.
+pub(super) fn spent_transactions(fragment: &Fragment) -> Vec<TransactionId> {
+    let inputs = match fragment {
+        Message::Transaction(tx) => &tx.transaction.inputs,
+        Message::Certificate(tx) => &tx.transaction.inputs,
+        _ => return Vec::new(),
+    };
+    inputs
+        .iter()
+        .filter_map(|input| match input.to_enum() {
+            InputEnum::UtxoInput(pointer) => Some(pointer.transaction_id),
+            InputEnum::AccountInput(..) => None,
+        })
+        .collect()
+}
+
+/// an input spent by a fragment, the fragments spending the same input
+/// conflict: only one of them can be added to the blockchain.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub(super) enum SpentInput {
+    /// an output of a transaction, identified by the transaction and
+    /// the index of the output
+    Utxo(TransactionId, u8),
+    /// a spending from an account at the given spending counter. The
+    /// ledger accepts one spending from an account at each counter.
+    Account(AccountIdentifier, u32),
+}
+
+/// the inputs spent by the fragment.
+///
+/// The spending counter is not part of the transaction, the counter of
+/// each account spending is given by `counter`.
+pub(super) fn spent_inputs<F>(fragment: &Fragment, mut counter: F) -> Vec<SpentInput>
+where
+    F: FnMut(&AccountIdentifier) -> u32,
+{
+    let inputs = match fragment {
+        Message::Transaction(tx) => &tx.transaction.inputs,
+        Message::Certificate(tx) => &tx.transaction.inputs,
+        _ => return Vec::new(),
+    };
+    inputs
+        .iter()
+        .map(|input| match input.to_enum() {
+            InputEnum::UtxoInput(pointer) => {
+                SpentInput::Utxo(pointer.transaction_id, pointer.output_index)
+            }
+            InputEnum::AccountInput(account, _) => {
+                let counter = counter(&account);
+                SpentInput::Account(account, counter)
+            }
+        })
+        .collect()
+}
+
+/// the accounts the fragment spends from
+pub(super) fn spent_accounts(fragment: &Fragment) -> Vec<AccountIdentifier> {
+    let inputs = match fragment {
+        Message::Transaction(tx) => &tx.transaction.inputs,
+        Message::Certificate(tx) => &tx.transaction.inputs,
+        _ => return Vec::new(),
+    };
+    inputs
+        .iter()
+        .filter_map(|input| match input.to_enum() {
+            InputEnum::UtxoInput(_) => None,
+            InputEnum::AccountInput(account, _) => Some(account),
+        })
+        .collect()
 }
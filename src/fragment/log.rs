@@ -1,26 +1,66 @@
-use crate::{blockcfg::BlockDate, fragment::FragmentId};
+use crate::{
+    blockcfg::{BlockDate, Value},
+    fragment::{FragmentId, PoolEntry},
+    network::p2p::topology::NodeId,
+};
 use jormungandr_utils::serde;
 use serde::Serialize;
-use std::time::SystemTime;
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::SystemTime,
+};
 
 /// identify the source of a fragment
-#[derive(Copy, Clone, Serialize, Debug)]
+#[derive(Copy, Clone, Serialize, Debug, PartialEq, Eq, Hash)]
 pub enum Origin {
     /// trace back the origin of a fragment to a given
     /// network node. This will allow tracking back the
     /// origins of the fragment and eventually blacklisting
     /// the senders from sending us more fragment (in case
     /// they are invalids or so)
-    ///
-    /// TODO: add the network identifier/IP Address
-    Network,
+    Network {
+        #[serde(with = "serde::as_string")]
+        node_id: NodeId,
+        addr: SocketAddr,
+    },
     /// This marks the fragment is coming from the REST interface
-    /// (a client wallet or another service).
-    Rest,
+    /// (a client wallet or another service). The address of the
+    /// client is not known if the connection is closed already.
+    Rest { addr: Option<SocketAddr> },
+    /// the fragment was in a block removed from the main chain by a
+    /// switch of the tip, the node did not receive it by itself
+    Block,
+    /// the node has no record of receiving the fragment: it was only
+    /// seen in a block, or its log expired or was lost on a restart
+    Unknown,
+}
+
+/// the sender of fragments: the quotas and the rate limits are shared
+/// by all the fragments coming from the same sender, whichever the
+/// connection they come through
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Sender {
+    Node(NodeId),
+    /// the REST clients are identified by their IP address only, they
+    /// use a new port for every connection
+    Client(Option<IpAddr>),
+    Block,
+}
+
+impl Origin {
+    pub fn sender(&self) -> Sender {
+        match self {
+            Origin::Network { node_id, .. } => Sender::Node(*node_id),
+            Origin::Rest { addr } => Sender::Client(addr.map(|addr| addr.ip())),
+            // the fragments of unknown origin are only logged, they
+            // are not submitted to the pool
+            Origin::Block | Origin::Unknown => Sender::Block,
+        }
+    }
 }
 
 /// status of the fragment within the blockchain or the pool
-#[derive(Clone, Serialize)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub enum Status {
     /// the fragment is yet to be processed
     Pending,
@@ -29,6 +69,71 @@ pub enum Status {
     /// The fragment has been added in a block
     #[serde(with = "serde::as_string")]
     InABlock { date: BlockDate },
+    /// the fragment has been replaced in the pool by a conflicting
+    /// fragment paying a higher fee
+    #[serde(with = "serde::as_string")]
+    Replaced { by: FragmentId },
+}
+
+impl Status {
+    /// whether the status of a fragment may change from this one to
+    /// `next`. A pending fragment is added to a block, rejected or
+    /// replaced; the block of a fragment is rolled back and the fragment
+    /// is returned to the pool.
+    ///
+    /// The other transitions follow a switch of the tip or the blocks
+    /// of the other nodes:
+    ///
+    /// * the block of a fragment is rolled back and the fragment is no
+    ///   longer valid (`InABlock` to `Rejected`), or is in a block of the
+    ///   new branch (`InABlock` to `InABlock`);
+    /// * a fragment rejected or replaced by the node is added to a block
+    ///   by another node (`Rejected` or `Replaced` to `InABlock`).
+    ///
+    /// A fragment rejected or replaced and submitted again gets a new
+    /// log, see `Pool::insert`.
+    pub fn can_become(&self, next: &Status) -> bool {
+        match (self, next) {
+            (Status::Pending, Status::InABlock { .. })
+            | (Status::Pending, Status::Rejected { .. })
+            | (Status::Pending, Status::Replaced { .. })
+            | (Status::InABlock { .. }, Status::Pending) => true,
+            (Status::InABlock { .. }, Status::Rejected { .. })
+            | (Status::InABlock { .. }, Status::InABlock { .. })
+            | (Status::Rejected { .. }, Status::InABlock { .. })
+            | (Status::Replaced { .. }, Status::InABlock { .. }) => true,
+            _ => false,
+        }
+    }
+}
+
+/// the fee and the size of a fragment, and the accumulated fee and size
+/// of the pending fragments whose outputs it spends (the ancestors) and
+/// of the pending fragments spending its outputs (the descendants)
+#[derive(Clone, Serialize)]
+pub struct PoolEntryStats {
+    #[serde(with = "serde::value")]
+    pub fee: Value,
+    pub size: usize,
+    #[serde(with = "serde::value")]
+    pub ancestors_fee: Value,
+    pub ancestors_size: usize,
+    #[serde(with = "serde::value")]
+    pub descendants_fee: Value,
+    pub descendants_size: usize,
+}
+
+impl<'a> From<&'a PoolEntry> for PoolEntryStats {
+    fn from(entry: &'a PoolEntry) -> Self {
+        PoolEntryStats {
+            fee: *entry.fragment_fee(),
+            size: *entry.fragment_size(),
+            ancestors_fee: *entry.ancestors_fee(),
+            ancestors_size: entry.ancestors_size(),
+            descendants_fee: *entry.descendants_fee(),
+            descendants_size: entry.descendants_size(),
+        }
+    }
 }
 
 /// the log associated to a given fragment
@@ -42,4 +147,92 @@ pub struct Log {
     pub received_at: SystemTime,
     pub received_from: Origin,
     pub status: Status,
+    /// the stats of the fragment's entry in the pool, as of the last
+    /// time the fragment or the fragments it is chained with changed
+    pub entry: PoolEntryStats,
+}
+
+impl Log {
+    /// a new log of the fragment of the pool entry
+    pub fn new(entry: &PoolEntry, status: Status) -> Self {
+        Log {
+            fragment_id: entry.fragment_ref().clone(),
+            last_updated_at: SystemTime::now(),
+            received_at: *entry.received_at(),
+            received_from: *entry.received_from(),
+            status,
+            entry: PoolEntryStats::from(entry),
+        }
+    }
+
+    /// a new log of a fragment whose pool entry is not known: the
+    /// fragment is reported as coming from an unknown origin, with no
+    /// fee nor size
+    pub fn without_entry(fragment_id: FragmentId, status: Status) -> Self {
+        let now = SystemTime::now();
+        Log {
+            fragment_id,
+            last_updated_at: now,
+            received_at: now,
+            received_from: Origin::Unknown,
+            status,
+            entry: PoolEntryStats {
+                fee: Value::zero(),
+                size: 0,
+                ancestors_fee: Value::zero(),
+                ancestors_size: 0,
+                descendants_fee: Value::zero(),
+                descendants_size: 0,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn in_a_block() -> Status {
+        Status::InABlock {
+            date: BlockDate {
+                epoch: 0,
+                slot_id: 0,
+            },
+        }
+    }
+
+    fn rejected() -> Status {
+        Status::Rejected {
+            reason: "invalid".to_owned(),
+        }
+    }
+
+    fn replaced() -> Status {
+        Status::Replaced {
+            by: FragmentId::hash_bytes(&[0]),
+        }
+    }
+
+    #[test]
+    fn status_transitions() {
+        assert!(Status::Pending.can_become(&in_a_block()));
+        assert!(Status::Pending.can_become(&rejected()));
+        assert!(Status::Pending.can_become(&replaced()));
+        assert!(in_a_block().can_become(&Status::Pending));
+
+        // after a switch of the tip or the block of another node
+        assert!(in_a_block().can_become(&rejected()));
+        assert!(in_a_block().can_become(&in_a_block()));
+        assert!(rejected().can_become(&in_a_block()));
+        assert!(replaced().can_become(&in_a_block()));
+
+        assert!(!Status::Pending.can_become(&Status::Pending));
+        assert!(!rejected().can_become(&Status::Pending));
+        assert!(!rejected().can_become(&rejected()));
+        assert!(!rejected().can_become(&replaced()));
+        assert!(!replaced().can_become(&Status::Pending));
+        assert!(!replaced().can_become(&rejected()));
+        assert!(!replaced().can_become(&replaced()));
+        assert!(!in_a_block().can_become(&replaced()));
+    }
 }
@@ -1,4 +1,5 @@
 use crate::fragment::{FragmentId, Log, Status};
+use slog::Logger;
 use std::time::Duration;
 use tokio::{
     prelude::*,
@@ -10,8 +11,8 @@ use tokio::{
 pub struct Logs(Lock<internal::Logs>);
 
 impl Logs {
-    pub fn new(ttl: Duration) -> Self {
-        Logs(Lock::new(internal::Logs::new(ttl)))
+    pub fn new(ttl: Duration, logger: Logger) -> Self {
+        Logs(Lock::new(internal::Logs::new(ttl, logger)))
     }
 
     pub fn insert(&mut self, log: Log) -> impl Future<Item = (), Error = ()> {
@@ -63,14 +64,17 @@ impl Logs {
             .and_then(|guard| future::ok(guard.logs().cloned().collect()))
     }
 
-    pub(super) fn inner(&self) -> impl Future<Item = LockGuard<internal::Logs>, Error = ()> {
+    pub(super) fn inner<E>(&self) -> impl Future<Item = LockGuard<internal::Logs>, Error = E> {
         let mut lock = self.0.clone();
         future::poll_fn(move || Ok(lock.poll_lock()))
     }
 }
 
 pub(super) mod internal {
-    use crate::fragment::{FragmentId, Log, Status};
+    use crate::fragment::{
+        persistence::remaining_ttl, FragmentId, Log, PoolEntry, PoolEntryStats, Status,
+    };
+    use slog::Logger;
     use std::{
         collections::HashMap,
         time::{Duration, Instant, SystemTime},
@@ -84,14 +88,16 @@ pub(super) mod internal {
         entries: HashMap<FragmentId, (Log, delay_queue::Key)>,
         expirations: DelayQueue<FragmentId>,
         ttl: Duration,
+        logger: Logger,
     }
 
     impl Logs {
-        pub fn new(ttl: Duration) -> Self {
+        pub fn new(ttl: Duration, logger: Logger) -> Self {
             Logs {
                 entries: HashMap::new(),
                 expirations: DelayQueue::new(),
                 ttl,
+                logger,
             }
         }
 
@@ -102,21 +108,78 @@ pub(super) mod internal {
                 .collect()
         }
 
+        pub fn get(&self, fragment_id: &FragmentId) -> Option<&Log> {
+            self.entries.get(fragment_id).map(|(log, _)| log)
+        }
+
+        /// insert the log, replacing the log of the same fragment if
+        /// any: it expires `ttl` from now
         pub fn insert(&mut self, log: Log) {
             let fragment_id = log.fragment_id.clone();
             let delay = self.expirations.insert(fragment_id.clone(), self.ttl);
 
+            if let Some((_, replaced_key)) = self.entries.insert(fragment_id, (log, delay)) {
+                self.expirations.remove(&replaced_key);
+            }
+        }
+
+        /// insert a log loaded from the saved pool, it expires `ttl`
+        /// after it was last updated. The logs expired already are
+        /// ignored.
+        pub fn restore(&mut self, log: Log) {
+            let ttl = match remaining_ttl(self.ttl, &log.last_updated_at) {
+                Some(ttl) => ttl,
+                None => return,
+            };
+            let fragment_id = log.fragment_id.clone();
+            let delay = self.expirations.insert(fragment_id.clone(), ttl);
+
             self.entries.insert(fragment_id, (log, delay));
         }
 
+        /// set the status of the fragment's log, if the transition is
+        /// allowed (see `Status::can_become`). The illegal transitions
+        /// are reported and ignored. The log may have expired, or been
+        /// lost when the node restarted: a new log is then created with
+        /// the status.
         pub fn modify(&mut self, fragment_id: &FragmentId, status: Status) {
+            if !self.entries.contains_key(fragment_id) {
+                self.insert(Log::without_entry(fragment_id.clone(), status));
+                return;
+            }
+
             if let Some((ref mut log, ref key)) = self.entries.get_mut(fragment_id) {
+                if !log.status.can_become(&status) {
+                    warn!(self.logger, "illegal fragment status transition ignored" ;
+                        "fragment_id" => fragment_id.to_string(),
+                        "from" => format!("{:?}", log.status),
+                        "to" => format!("{:?}", status),
+                    );
+                    return;
+                }
                 log.status = status;
                 log.last_updated_at = SystemTime::now();
 
                 self.expirations.reset_at(key, Instant::now() + self.ttl);
+            }
+        }
+
+        /// set the status of the log of the pool entry's fragment. The
+        /// log may have expired before the entry, or been lost when the
+        /// node restarted: a new log is then created with the status.
+        pub fn modify_entry(&mut self, entry: &PoolEntry, status: Status) {
+            let fragment_id = entry.fragment_ref();
+            if self.entries.contains_key(fragment_id) {
+                self.modify(fragment_id, status);
             } else {
-                unimplemented!()
+                self.insert(Log::new(entry, status));
+            }
+        }
+
+        /// update the pool entry stats of the fragment's log
+        pub fn update_entry(&mut self, fragment_id: &FragmentId, entry: PoolEntryStats) {
+            if let Some((ref mut log, _)) = self.entries.get_mut(fragment_id) {
+                log.entry = entry;
             }
         }
 
@@ -127,7 +190,8 @@ pub(super) mod internal {
         }
 
         pub fn poll_purge(&mut self) -> Poll<(), timer::Error> {
-            while let Some(entry) = try_ready!(self.expirations.poll()) {
+            // stop at the first log not expired yet
+            while let Async::Ready(Some(entry)) = self.expirations.poll()? {
                 self.entries.remove(entry.get_ref());
             }
 
@@ -139,3 +203,41 @@ pub(super) mod internal {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::internal::Logs;
+    use crate::{
+        blockcfg::BlockDate,
+        fragment::{FragmentId, Origin, Status},
+    };
+    use slog::{Discard, Logger};
+    use std::time::Duration;
+    use tokio::{prelude::*, runtime::current_thread::Runtime};
+
+    #[test]
+    fn status_update_creates_a_missing_log() {
+        // the logs register their expiration with the timer of the
+        // runtime
+        Runtime::new()
+            .unwrap()
+            .block_on(future::lazy(|| {
+                let mut logs = Logs::new(Duration::from_secs(60), Logger::root(Discard, o!()));
+                let fragment_id = FragmentId::hash_bytes(&[0]);
+                let status = Status::InABlock {
+                    date: BlockDate {
+                        epoch: 1,
+                        slot_id: 2,
+                    },
+                };
+
+                logs.modify(&fragment_id, status.clone());
+
+                let log = logs.get(&fragment_id).expect("no log created");
+                assert_eq!(log.status, status);
+                assert_eq!(log.received_from, Origin::Unknown);
+                Ok::<(), ()>(())
+            }))
+            .unwrap();
+    }
+}
@@ -1,15 +1,22 @@
 mod entry;
 mod log;
 mod logs;
+mod persistence;
 mod pool;
 mod process;
+mod rate_limit;
 pub mod selection;
+#[cfg(test)]
+mod testing;
+mod validation;
 
 pub use self::entry::PoolEntry;
-pub use self::log::{Log, Origin, Status};
+pub use self::log::{Log, Origin, PoolEntryStats, Sender, Status};
 pub use self::logs::Logs;
-pub use self::pool::Pool;
+pub use self::persistence::{PersistenceError, PoolFile};
+pub use self::pool::{ConflictPolicy, InsertError, Pool, PoolLimits};
 pub use self::process::Process;
+pub use self::rate_limit::RateLimit;
 
 use crate::blockcfg::{Message, MessageId};
 
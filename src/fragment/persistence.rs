@@ -0,0 +1,301 @@
+//! Saving the fragment pool across restarts
+//!
+//! The pending fragments and the fragment logs are written in the
+//! storage directory periodically and when the node shuts down. When the
+//! node restarts they are loaded back with the time they were received
+//! at, so they expire as if the node had not been stopped. The pending
+//! fragments are checked again against the ledger of the restored tip:
+//! the blocks added while the node was down may have spent their inputs.
+//!
+//! The file contains, encoded with bincode after a format version byte:
+//!
+//! * the pending fragments, oldest first, with their origin and the
+//!   time they were received at;
+//! * the fragment logs.
+
+use crate::{
+    blockcfg::{BlockDate, Value},
+    fragment::{Fragment, FragmentId, Log, Origin, PoolEntryStats, Status},
+    network::p2p::topology::NodeId,
+};
+use bincode;
+use jormungandr_utils::serde::value;
+use std::{
+    fs,
+    io::{self, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+/// name of the file, within the node's storage directory, where the
+/// fragment pool is saved
+const POOL_FILE: &'static str = "fragment_pool";
+
+/// version of the format of the file, a file written in another format
+/// is ignored. It changes only when the layout of the saved types does.
+const FORMAT_VERSION: u8 = 1;
+
+custom_error! {pub PersistenceError
+    Io { source: io::Error } = "I/O error while accessing the saved fragment pool: {source}",
+    Encoding { source: bincode::Error } = "invalid saved fragment pool: {source}",
+}
+
+/// a pending fragment as saved on disk
+#[derive(Serialize, Deserialize)]
+pub struct SavedEntry {
+    #[serde(with = "as_bytes")]
+    pub fragment: Fragment,
+    #[serde(with = "OriginDef")]
+    pub origin: Origin,
+    pub received_at: SystemTime,
+}
+
+/// the content of the fragment pool, see the
+/// [module documentation](./index.html)
+#[derive(Serialize, Deserialize)]
+pub struct SavedPool {
+    pub entries: Vec<SavedEntry>,
+    #[serde(with = "saved_logs")]
+    pub logs: Vec<Log>,
+}
+
+/// the file the fragment pool is saved in
+#[derive(Clone)]
+pub struct PoolFile {
+    path: PathBuf,
+}
+
+impl PoolFile {
+    /// the pool will be saved within the given storage directory
+    pub fn new<P: AsRef<Path>>(storage_directory: P) -> Self {
+        PoolFile {
+            path: storage_directory.as_ref().join(POOL_FILE),
+        }
+    }
+
+    pub fn save(&self, saved: &SavedPool) -> Result<(), PersistenceError> {
+        if let Some(directory) = self.path.parent() {
+            fs::create_dir_all(directory)?;
+        }
+
+        // write in a temporary file first so a crash while writing
+        // does not leave a partial file behind
+        let tmp_path = self.path.with_extension("tmp");
+        {
+            let mut file = io::BufWriter::new(fs::File::create(&tmp_path)?);
+            bincode::serialize_into(&mut file, &FORMAT_VERSION)?;
+            bincode::serialize_into(&mut file, saved)?;
+            file.flush()?;
+        }
+        fs::rename(tmp_path, &self.path)?;
+        Ok(())
+    }
+
+    /// load the saved pool, `None` if there is no saved pool or if it
+    /// was written in another format.
+    pub fn load(&self) -> Result<Option<SavedPool>, PersistenceError> {
+        let file = match fs::File::open(&self.path) {
+            Ok(file) => file,
+            Err(ref error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error.into()),
+        };
+        let mut reader = io::BufReader::new(file);
+        let version: u8 = bincode::deserialize_from(&mut reader)?;
+        if version != FORMAT_VERSION {
+            return Ok(None);
+        }
+        Ok(Some(bincode::deserialize_from(reader)?))
+    }
+}
+
+/// how long something last updated at `since` has left to live, `None`
+/// if it has expired already
+pub(super) fn remaining_ttl(ttl: Duration, since: &SystemTime) -> Option<Duration> {
+    // a time in the future (the clock went backward) counts as now
+    let elapsed = since.elapsed().unwrap_or(Duration::from_secs(0));
+    ttl.checked_sub(elapsed)
+        .filter(|remaining| *remaining > Duration::from_secs(0))
+}
+
+// The logs are serialized for the REST API as text, they are saved in
+// a binary form defined below instead.
+
+#[derive(Serialize, Deserialize)]
+#[serde(remote = "Log")]
+struct LogDef {
+    #[serde(with = "as_bytes")]
+    fragment_id: FragmentId,
+    last_updated_at: SystemTime,
+    received_at: SystemTime,
+    #[serde(with = "OriginDef")]
+    received_from: Origin,
+    #[serde(with = "StatusDef")]
+    status: Status,
+    #[serde(with = "PoolEntryStatsDef")]
+    entry: PoolEntryStats,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(remote = "Origin")]
+enum OriginDef {
+    Network { node_id: NodeId, addr: SocketAddr },
+    Rest { addr: Option<SocketAddr> },
+    Block,
+    Unknown,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(remote = "Status")]
+enum StatusDef {
+    Pending,
+    Rejected {
+        reason: String,
+    },
+    InABlock {
+        #[serde(with = "BlockDateDef")]
+        date: BlockDate,
+    },
+    Replaced {
+        #[serde(with = "as_bytes")]
+        by: FragmentId,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(remote = "BlockDate")]
+struct BlockDateDef {
+    epoch: u32,
+    slot_id: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(remote = "PoolEntryStats")]
+struct PoolEntryStatsDef {
+    #[serde(with = "value")]
+    fee: Value,
+    size: usize,
+    #[serde(with = "value")]
+    ancestors_fee: Value,
+    ancestors_size: usize,
+    #[serde(with = "value")]
+    descendants_fee: Value,
+    descendants_size: usize,
+}
+
+mod saved_logs {
+    use super::LogDef;
+    use crate::fragment::Log;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    #[derive(Serialize, Deserialize)]
+    struct SavedLog(#[serde(with = "LogDef")] Log);
+
+    pub fn serialize<S: Serializer>(logs: &[Log], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(logs.iter().cloned().map(SavedLog))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Log>, D::Error> {
+        let logs = Vec::<SavedLog>::deserialize(deserializer)?;
+        Ok(logs.into_iter().map(|SavedLog(log)| log).collect())
+    }
+}
+
+/// the fragments and their identifiers are saved in their binary
+/// encoding
+mod as_bytes {
+    use chain_core::property;
+    use serde::{de::Error as _, ser::Error as _, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S, T>(data: &T, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+        T: property::Serialize,
+    {
+        let bytes = data.serialize_as_vec().map_err(S::Error::custom)?;
+        serializer.serialize_bytes(&bytes)
+    }
+
+    pub fn deserialize<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+    where
+        D: Deserializer<'de>,
+        T: property::Deserialize,
+    {
+        let bytes = Vec::<u8>::deserialize(deserializer)?;
+        T::deserialize(bytes.as_slice()).map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    fn log(status: Status) -> Log {
+        Log {
+            fragment_id: FragmentId::hash_bytes(&[0]),
+            last_updated_at: UNIX_EPOCH + Duration::new(1_560_000_000, 42),
+            received_at: UNIX_EPOCH + Duration::from_secs(1_550_000_000),
+            received_from: Origin::Rest {
+                addr: Some("127.0.0.1:8443".parse().unwrap()),
+            },
+            status,
+            entry: PoolEntryStats {
+                fee: Value(10),
+                size: 200,
+                ancestors_fee: Value(20),
+                ancestors_size: 300,
+                descendants_fee: Value(0),
+                descendants_size: 0,
+            },
+        }
+    }
+
+    fn round_trip(saved: &SavedPool) -> SavedPool {
+        let bytes = bincode::serialize(saved).unwrap();
+        bincode::deserialize(&bytes).unwrap()
+    }
+
+    #[test]
+    fn logs_round_trip() {
+        let statuses = vec![
+            Status::Pending,
+            Status::Rejected {
+                reason: "evicted".to_owned(),
+            },
+            Status::InABlock {
+                date: BlockDate {
+                    epoch: 3,
+                    slot_id: 14,
+                },
+            },
+            Status::Replaced {
+                by: FragmentId::hash_bytes(&[1]),
+            },
+        ];
+        let saved = SavedPool {
+            entries: Vec::new(),
+            logs: statuses.into_iter().map(log).collect(),
+        };
+
+        let loaded = round_trip(&saved);
+        assert_eq!(loaded.logs.len(), saved.logs.len());
+        for (loaded, saved) in loaded.logs.iter().zip(saved.logs.iter()) {
+            assert_eq!(loaded.fragment_id, saved.fragment_id);
+            assert_eq!(loaded.last_updated_at, saved.last_updated_at);
+            assert_eq!(loaded.received_at, saved.received_at);
+            assert_eq!(loaded.received_from, saved.received_from);
+            assert!(loaded.status == saved.status);
+            assert_eq!(loaded.entry.ancestors_fee, saved.entry.ancestors_fee);
+            assert_eq!(loaded.entry.ancestors_size, saved.entry.ancestors_size);
+        }
+    }
+
+    #[test]
+    fn expired_ttl() {
+        let ttl = Duration::from_secs(60);
+        assert!(remaining_ttl(ttl, &SystemTime::now()).is_some());
+        let long_ago = SystemTime::now() - Duration::from_secs(120);
+        assert!(remaining_ttl(ttl, &long_ago).is_none());
+    }
+}
@@ -1,10 +1,49 @@
 use crate::{
-    blockcfg::{HeaderContentEvalContext, Ledger, LedgerParameters},
-    fragment::{selection::FragmentSelectionAlgorithm, Fragment, Log, Logs, Origin, Status},
+    blockcfg::{BlockDate, HeaderContentEvalContext, Ledger, LedgerParameters, Value},
+    fragment::{
+        persistence::{PersistenceError, PoolFile, SavedEntry, SavedPool},
+        selection::FragmentSelectionAlgorithm,
+        validation::ValidationError,
+        Fragment, FragmentId, Log, Logs, Origin, PoolEntry, PoolEntryStats, Status,
+    },
 };
 use std::time::{Duration, SystemTime};
 use tokio::{prelude::*, sync::lock::Lock, timer};
 
+custom_error! {pub InsertError
+    PoolFull = "the fragment pool is full",
+    OriginQuotaExceeded = "the fragment pool holds too many fragments from this origin",
+    DoubleSpend = "the fragment spends inputs already spent by pending fragments",
+    ReplacementFeeTooLow = "the fragment does not pay more fee than the pending fragments it conflicts with",
+    Invalid { source: ValidationError } = "the fragment is invalid: {source}",
+}
+
+/// what to do with a fragment spending inputs already spent by pending
+/// fragments
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictPolicy {
+    /// the first fragment received is kept, the other is rejected
+    Reject,
+    /// the fragment replaces the pending fragments it conflicts with
+    /// (and their descendants) if it pays more fee than all of them
+    ReplaceByFee,
+}
+
+/// the capacity of the pool
+#[derive(Debug, Clone, Copy)]
+pub struct PoolLimits {
+    /// maximum number of fragments in the pool
+    pub max_entries: usize,
+    /// maximum total size, in bytes, of the fragments in the pool
+    pub max_size: usize,
+    /// maximum number of fragments in the pool received from the
+    /// same [`Sender`], so a single source cannot fill the pool
+    ///
+    /// [`Sender`]: ./enum.Sender.html
+    pub max_entries_per_origin: usize,
+}
+
 #[derive(Clone)]
 pub struct Pool {
     logs: Logs,
@@ -12,10 +51,15 @@ pub struct Pool {
 }
 
 impl Pool {
-    pub fn new(ttl: Duration, logs: Logs) -> Self {
+    pub fn new(
+        ttl: Duration,
+        limits: PoolLimits,
+        conflict_policy: ConflictPolicy,
+        logs: Logs,
+    ) -> Self {
         Pool {
             logs,
-            pool: Lock::new(internal::Pool::new(ttl)),
+            pool: Lock::new(internal::Pool::new(ttl, limits, conflict_policy)),
         }
     }
 
@@ -23,48 +67,315 @@ impl Pool {
         &self.logs
     }
 
+    /// insert the fragment in the pool, its fee is computed with the
+    /// given ledger parameters (the ones of the current tip).
+    ///
+    /// When the pool is full, the entries paying a lower fee per byte
+    /// than the fragment are evicted to make room for it. The fragment
+    /// is refused if there are not enough of them or if its origin
+    /// exceeds its quota.
+    ///
+    /// A fragment conflicting with pending fragments is handled as set
+    /// by the [`ConflictPolicy`], it is recorded as rejected if it is
+    /// refused.
+    ///
+    /// [`ConflictPolicy`]: ./enum.ConflictPolicy.html
+    ///
+    /// Returns `false` if the fragment is already in the pool or in a
+    /// block. A fragment rejected or replaced before is tried again, the
+    /// blockchain may accept it now.
     pub fn insert(
         &mut self,
         origin: Origin,
         fragment: Fragment,
-    ) -> impl Future<Item = bool, Error = ()> {
+        ledger_params: LedgerParameters,
+    ) -> impl Future<Item = bool, Error = InsertError> {
         use chain_core::property::Message as _;
 
         let id = fragment.id();
         let mut lock = self.pool.clone();
-        let mut logs = self.logs.clone();
+        let logs = self.logs.clone();
 
-        self.logs()
-            .exists(vec![id.clone()])
-            .and_then(move |exists| {
-                if exists[0] {
-                    future::Either::A(future::ok(false))
-                } else {
-                    future::Either::B(future::poll_fn(move || Ok(lock.poll_lock())).and_then(
-                        move |mut guard| {
-                            guard.insert(fragment);
-
-                            let log = Log {
-                                fragment_id: id,
-                                last_updated_at: SystemTime::now(),
-                                received_at: SystemTime::now(),
-                                received_from: origin,
-                                status: Status::Pending,
-                            };
-                            logs.insert(log).map(|()| true)
+        future::poll_fn(move || Ok(lock.poll_lock()))
+            .and_then(move |pool| logs.inner().map(|logs| (pool, logs)))
+            .and_then(move |(mut pool, mut logs)| {
+                // the log of a fragment expired from the pool may still
+                // be pending
+                match logs.get(&id).map(|log| &log.status) {
+                    Some(Status::Pending) if pool.contains(&id) => return Ok(false),
+                    Some(Status::InABlock { .. }) => return Ok(false),
+                    _ => {}
+                }
+
+                let rejected_entry = rejected_stats(&fragment, origin, &ledger_params);
+                let inserted = PoolEntry::new(&fragment, origin, &ledger_params)
+                    .map_err(InsertError::from)
+                    .and_then(|entry| pool.insert(entry, fragment));
+                let (status, entry, result) = match inserted {
+                    Ok(entry) => (Status::Pending, entry, Ok(true)),
+                    // the conflicting fragment cannot be added to the
+                    // blockchain after the one it conflicts with either
+                    Err(error @ InsertError::DoubleSpend)
+                    | Err(error @ InsertError::ReplacementFeeTooLow)
+                    | Err(error @ InsertError::Invalid { .. }) => (
+                        Status::Rejected {
+                            reason: error.to_string(),
+                        },
+                        rejected_entry,
+                        Err(error),
+                    ),
+                    Err(error) => return Err(error),
+                };
+
+                logs.insert(Log {
+                    fragment_id: id,
+                    last_updated_at: SystemTime::now(),
+                    received_at: SystemTime::now(),
+                    received_from: origin,
+                    status,
+                    entry,
+                });
+                pool.update_logs(&mut logs);
+                result
+            })
+    }
+
+    /// record the given fragment as rejected without adding it to the
+    /// pool, unless it is already known.
+    pub fn reject<E>(
+        &mut self,
+        origin: Origin,
+        fragment: Fragment,
+        ledger_params: LedgerParameters,
+        reason: String,
+    ) -> impl Future<Item = (), Error = E> {
+        use chain_core::property::Message as _;
+
+        let id = fragment.id();
+        self.logs.inner().map(move |mut logs| {
+            if logs.exists(vec![id.clone()])[0] {
+                return;
+            }
+
+            logs.insert(Log {
+                fragment_id: id,
+                last_updated_at: SystemTime::now(),
+                received_at: SystemTime::now(),
+                received_from: origin,
+                status: Status::Rejected { reason },
+                entry: rejected_stats(&fragment, origin, &ledger_params),
+            });
+        })
+    }
+
+    /// update the pool after the tip has been switched to another
+    /// branch.
+    ///
+    /// The `returned` fragments, from the blocks no longer in the main
+    /// chain, are put back in the pool if they are still valid (as
+    /// checked against the new tip) and recorded as rejected otherwise.
+    /// The `committed` fragments, from the blocks now in the main chain,
+    /// are removed from the pool.
+    pub fn roll_back(
+        &mut self,
+        returned: Vec<(Fragment, Result<(), ValidationError>)>,
+        committed: Vec<(FragmentId, BlockDate)>,
+        ledger_params: LedgerParameters,
+    ) -> impl Future<Item = (), Error = ()> {
+        use chain_core::property::Message as _;
+
+        let mut lock = self.pool.clone();
+        let logs = self.logs.clone();
+
+        future::poll_fn(move || Ok(lock.poll_lock()))
+            .and_then(move |pool| logs.inner().map(|logs| (pool, logs)))
+            .map(move |(mut pool, mut logs)| {
+                for (fragment_id, date) in committed {
+                    let status = Status::InABlock { date };
+                    match pool.remove(&fragment_id) {
+                        Some((entry, _)) => logs.modify_entry(&entry, status),
+                        None => {
+                            if logs.get(&fragment_id).is_some() {
+                                logs.modify(&fragment_id, status);
+                            }
+                        }
+                    }
+                }
+
+                for (fragment, validation) in returned {
+                    let id = fragment.id();
+                    let origin = match logs.get(&id).map(|log| log.received_from) {
+                        // the node did not receive the fragment by itself
+                        Some(Origin::Unknown) | None => Origin::Block,
+                        Some(origin) => origin,
+                    };
+                    let rejected_entry = rejected_stats(&fragment, origin, &ledger_params);
+
+                    let (status, entry) = match validation {
+                        Err(error) => {
+                            pool.remove(&id);
+                            (
+                                Status::Rejected {
+                                    reason: error.to_string(),
+                                },
+                                rejected_entry,
+                            )
+                        }
+                        Ok(()) if pool.contains(&id) => continue,
+                        Ok(()) => match PoolEntry::new(&fragment, origin, &ledger_params)
+                            .map_err(InsertError::from)
+                            .and_then(|entry| pool.insert(entry, fragment))
+                        {
+                            Ok(entry) => (Status::Pending, entry),
+                            Err(error) => (
+                                Status::Rejected {
+                                    reason: error.to_string(),
+                                },
+                                rejected_entry,
+                            ),
                         },
-                    ))
+                    };
+
+                    if logs.get(&id).is_some() {
+                        logs.modify(&id, status);
+                        logs.update_entry(&id, entry);
+                    } else {
+                        logs.insert(Log {
+                            fragment_id: id,
+                            last_updated_at: SystemTime::now(),
+                            received_at: SystemTime::now(),
+                            received_from: origin,
+                            status,
+                            entry,
+                        });
+                    }
+                }
+                pool.update_logs(&mut logs);
+            })
+    }
+
+    /// write the pending fragments and the logs in the given file
+    pub fn save(&self, file: PoolFile) -> impl Future<Item = (), Error = PersistenceError> {
+        let mut lock = self.pool.clone();
+        let logs = self.logs.clone();
+
+        future::poll_fn(move || Ok(lock.poll_lock()))
+            .and_then(move |pool| logs.inner().map(|logs| (pool, logs)))
+            .map(move |(pool, logs)| SavedPool {
+                entries: pool.saved_entries(),
+                logs: logs.logs().cloned().collect(),
+            })
+            .and_then(move |saved| file.save(&saved))
+    }
+
+    /// restore the pool saved before the node was stopped.
+    ///
+    /// The `entries` were checked against the ledger of the restored tip:
+    /// the valid ones are put back in the pool, to expire when they
+    /// would have if the node had kept running, the others are recorded
+    /// as rejected.
+    pub fn restore(
+        &mut self,
+        entries: Vec<(SavedEntry, Result<(), ValidationError>)>,
+        saved_logs: Vec<Log>,
+        ledger_params: LedgerParameters,
+    ) -> impl Future<Item = (), Error = ()> {
+        use chain_core::property::Message as _;
+
+        let mut lock = self.pool.clone();
+        let logs = self.logs.clone();
+
+        future::poll_fn(move || Ok(lock.poll_lock()))
+            .and_then(move |pool| logs.inner().map(|logs| (pool, logs)))
+            .map(move |(mut pool, mut logs)| {
+                for log in saved_logs {
+                    logs.restore(log);
+                }
+
+                for (saved, validation) in entries {
+                    let SavedEntry {
+                        fragment,
+                        origin,
+                        received_at,
+                    } = saved;
+                    let id = fragment.id();
+                    if pool.contains(&id) || pool.is_expired(&received_at) {
+                        continue;
+                    }
+                    let rejected_entry = rejected_stats(&fragment, origin, &ledger_params);
+
+                    let (status, entry) = match validation
+                        .and_then(|()| PoolEntry::new(&fragment, origin, &ledger_params))
+                        .map_err(|error| error.to_string())
+                        .and_then(|mut entry| {
+                            entry.set_received_at(received_at);
+                            pool.insert(entry, fragment)
+                                .map_err(|error| error.to_string())
+                        }) {
+                        Ok(entry) => (Status::Pending, entry),
+                        Err(reason) => (Status::Rejected { reason }, rejected_entry),
+                    };
+
+                    if logs.get(&id).is_some() {
+                        // the status of a restored log is kept, so its
+                        // expiration is not pushed back
+                        if let Status::Rejected { .. } = status {
+                            logs.modify(&id, status);
+                        }
+                        logs.update_entry(&id, entry);
+                    } else {
+                        logs.insert(Log {
+                            fragment_id: id,
+                            last_updated_at: SystemTime::now(),
+                            received_at,
+                            received_from: origin,
+                            status,
+                            entry,
+                        });
+                    }
                 }
+                pool.update_logs(&mut logs);
             })
     }
 
+    /// the pending fragments the given fragment depends on, directly or
+    /// not: the fragments whose outputs it spends and the previous
+    /// spendings from its accounts, each one after the fragments it
+    /// depends on.
+    pub fn pending_ancestors<E>(
+        &self,
+        fragment: &Fragment,
+    ) -> impl Future<Item = Vec<Fragment>, Error = E> {
+        let mut lock = self.pool.clone();
+        let fragment = fragment.clone();
+        future::poll_fn(move || Ok(lock.poll_lock()))
+            .map(move |guard| guard.pending_ancestors(&fragment))
+    }
+
+    /// get the fragments of the pool matching the given identifiers,
+    /// the fragments unknown to the pool are ignored.
+    pub fn get(
+        &self,
+        fragment_ids: Vec<FragmentId>,
+    ) -> impl Future<Item = Vec<Fragment>, Error = ()> {
+        let mut lock = self.pool.clone();
+        future::poll_fn(move || Ok(lock.poll_lock()))
+            .and_then(move |guard| future::ok(guard.get(&fragment_ids)))
+    }
+
     pub fn poll_purge(&mut self) -> impl Future<Item = (), Error = timer::Error> {
         let mut lock = self.pool.clone();
-        let purge_logs = self.logs.poll_purge();
+        let logs = self.logs.clone();
 
         future::poll_fn(move || Ok(lock.poll_lock()))
-            .and_then(move |mut guard| future::poll_fn(move || guard.poll_purge()))
-            .and_then(move |()| purge_logs)
+            .and_then(move |pool| logs.inner().map(|logs| (pool, logs)))
+            .and_then(move |(mut pool, mut logs)| {
+                future::poll_fn(move || {
+                    try_ready!(pool.poll_purge());
+                    pool.update_logs(&mut logs);
+                    logs.poll_purge()
+                })
+            })
     }
 
     pub fn select<SelectAlg>(
@@ -84,83 +395,1480 @@ impl Pool {
             .and_then(move |pool| logs.inner().map(|logs| (pool, logs)))
             .and_then(move |(mut pool, mut logs)| {
                 selection_alg.select(&ledger, &ledger_params, &metadata, &mut logs, &mut pool);
+                pool.update_logs(&mut logs);
                 future::ok(selection_alg)
             })
     }
 }
 
+/// the statistics recorded in the log of a fragment refused by the pool.
+/// The fee of a fragment whose fee overflows is recorded as zero.
+fn rejected_stats(
+    fragment: &Fragment,
+    origin: Origin,
+    ledger_params: &LedgerParameters,
+) -> PoolEntryStats {
+    let entry = PoolEntry::new(fragment, origin, ledger_params)
+        .unwrap_or_else(|_| PoolEntry::with_fee(fragment, origin, Value::zero()));
+    PoolEntryStats::from(&entry)
+}
+
 pub(super) mod internal {
-    use crate::fragment::{Fragment, FragmentId, PoolEntry};
+    use super::{ConflictPolicy, InsertError, PoolLimits};
+    use crate::{
+        blockcfg::Value,
+        fragment::{
+            entry::{spent_accounts, spent_inputs, spent_transactions, transaction_id, SpentInput},
+            logs::internal::Logs,
+            persistence::{remaining_ttl, SavedEntry},
+            Fragment, FragmentId, PoolEntry, PoolEntryStats, Sender, Status,
+        },
+    };
+    use chain_core::property::Message as _;
+    use chain_impl_mockchain::transaction::{AccountIdentifier, TransactionId};
     use std::{
-        collections::{BTreeMap, HashMap, VecDeque},
-        sync::Arc,
-        time::Duration,
+        cmp::{Ordering, Reverse},
+        collections::{hash_map, BTreeMap, HashMap, HashSet},
+        time::{Duration, SystemTime},
     };
     use tokio::{
         prelude::*,
         timer::{self, delay_queue, DelayQueue},
     };
 
+    type Links = HashMap<FragmentId, HashSet<FragmentId>>;
+
+    /// the arrival time of an entry, the sequence number tells apart
+    /// the entries received at the same time
+    type TimeKey = (SystemTime, u64);
+    /// the fee rate of the package of an entry with its ancestors, the
+    /// oldest entry comes last between entries of the same fee rate so
+    /// the best entry is always the last one
+    type AncestorsKey = (Rate, Reverse<TimeKey>);
+    /// the fee rate of the package of an entry with its descendants
+    type DescendantsKey = (Rate, u64);
+
+    /// a pending fragment with its entry
+    struct Entry {
+        entry: PoolEntry,
+        fragment: Fragment,
+        /// the inputs the fragment spends, with the counters of its
+        /// spendings from accounts
+        inputs: Vec<SpentInput>,
+        expiration: delay_queue::Key,
+        seq: u64,
+    }
+
     pub struct Pool {
-        pub entries: HashMap<FragmentId, (Arc<PoolEntry>, Fragment, delay_queue::Key)>,
-        pub entries_by_id: BTreeMap<FragmentId, Arc<PoolEntry>>,
-        pub entries_by_time: VecDeque<FragmentId>,
+        entries: HashMap<FragmentId, Entry>,
+        /// the pending fragments by arrival time, oldest first
+        by_time: BTreeMap<TimeKey, FragmentId>,
+        /// the pending fragments by fee rate of their package with
+        /// their ancestors, the package to add to a block first is last
+        by_ancestors_rate: BTreeMap<AncestorsKey, FragmentId>,
+        /// the pending fragments by fee rate of their package with
+        /// their descendants, the package to evict first is first
+        by_descendants_rate: BTreeMap<DescendantsKey, FragmentId>,
+        /// the sequence number of the next entry
+        next_seq: u64,
+        /// the pending fragments by the identifier of the transaction
+        /// they carry
+        by_transaction: HashMap<TransactionId, FragmentId>,
+        /// the pending fragments by the identifiers of the transactions
+        /// whose outputs they spend. A fragment may be received before
+        /// the fragment whose outputs it spends.
+        by_spent_transaction: HashMap<TransactionId, HashSet<FragmentId>>,
+        /// the pending fragment spending each input, there is only one
+        /// as the conflicting fragments are not added
+        by_spent_input: HashMap<SpentInput, FragmentId>,
+        /// the pending spendings from each account by spending counter,
+        /// counted from the counter of the account on the ledger. The
+        /// counter is not part of the transaction: a spending is taken
+        /// to be signed for the counter following the last pending
+        /// spending from the account, which is its parent.
+        by_account: HashMap<AccountIdentifier, BTreeMap<u32, FragmentId>>,
+        /// the pending fragments whose outputs a fragment spends, and the
+        /// previous pending spendings from its accounts
+        parents: Links,
+        /// the pending fragments spending the outputs of a fragment, and
+        /// the next pending spendings from its accounts
+        children: Links,
+        /// the entries whose stats changed since the logs were last updated
+        updated: HashSet<FragmentId>,
+        /// the entries evicted or replaced since the logs were last
+        /// updated, with their new status
+        removed: Vec<(PoolEntry, Status)>,
+        /// the number of entries received from each sender
+        by_sender: HashMap<Sender, usize>,
+        /// the total size of the entries
+        size: usize,
         expirations: DelayQueue<FragmentId>,
         ttl: Duration,
+        limits: PoolLimits,
+        conflict_policy: ConflictPolicy,
     }
 
     impl Pool {
-        pub fn new(ttl: Duration) -> Self {
+        pub fn new(ttl: Duration, limits: PoolLimits, conflict_policy: ConflictPolicy) -> Self {
             Pool {
                 entries: HashMap::new(),
-                entries_by_id: BTreeMap::new(),
-                entries_by_time: VecDeque::new(),
+                by_time: BTreeMap::new(),
+                by_ancestors_rate: BTreeMap::new(),
+                by_descendants_rate: BTreeMap::new(),
+                next_seq: 0,
+                by_transaction: HashMap::new(),
+                by_spent_transaction: HashMap::new(),
+                by_spent_input: HashMap::new(),
+                by_account: HashMap::new(),
+                parents: HashMap::new(),
+                children: HashMap::new(),
+                updated: HashSet::new(),
+                removed: Vec::new(),
+                by_sender: HashMap::new(),
+                size: 0,
                 expirations: DelayQueue::new(),
                 ttl,
+                limits,
+                conflict_policy,
             }
         }
 
-        pub fn insert(&mut self, fragment: Fragment) {
-            let entry = Arc::new(PoolEntry::new(&fragment));
+        /// insert the entry of the fragment, returns the stats of the
+        /// entry once its ancestors and descendants are accounted for
+        pub fn insert(
+            &mut self,
+            entry: PoolEntry,
+            fragment: Fragment,
+        ) -> Result<PoolEntryStats, InsertError> {
             let fragment_id = entry.fragment_ref().clone();
-            let delay = self.expirations.insert(fragment_id.clone(), self.ttl);
+            let sender = entry.received_from().sender();
 
-            self.entries
-                .insert(fragment_id.clone(), (entry.clone(), fragment, delay));
-            self.entries_by_id
-                .insert(fragment_id.clone(), entry.clone());
-            self.entries_by_time.push_back(fragment_id);
-        }
+            let from_sender = self.by_sender.get(&sender).cloned().unwrap_or(0);
+            if from_sender >= self.limits.max_entries_per_origin {
+                return Err(InsertError::OriginQuotaExceeded);
+            }
 
-        pub fn remove(&mut self, fragment_id: &FragmentId) -> Option<Fragment> {
-            if let Some((_, fragment, cache_key)) = self.entries.remove(fragment_id) {
-                self.entries_by_id.remove(fragment_id);
-                self.entries_by_time
-                    .iter()
-                    .position(|id| id == fragment_id)
-                    .map(|position| {
-                        self.entries_by_time.remove(position);
-                    });
-                self.expirations.remove(&cache_key);
-                Some(fragment)
+            let inputs = spent_inputs(&fragment, |account| self.next_counter(account));
+            let conflicts: HashSet<FragmentId> = inputs
+                .iter()
+                .filter_map(|input| self.by_spent_input.get(input).cloned())
+                .collect();
+            let replaced = if conflicts.is_empty() {
+                HashSet::new()
+            } else {
+                match self.conflict_policy {
+                    ConflictPolicy::Reject => return Err(InsertError::DoubleSpend),
+                    ConflictPolicy::ReplaceByFee => self.replaced_by(&entry, conflicts)?,
+                }
+            };
+            self.make_room(&entry, &fragment, &replaced)?;
+
+            let replaced_any = !replaced.is_empty();
+            for replaced_id in replaced {
+                if let Some((replaced_entry, _)) = self.remove(&replaced_id) {
+                    self.removed.push((
+                        replaced_entry,
+                        Status::Replaced {
+                            by: fragment_id.clone(),
+                        },
+                    ));
+                }
+            }
+            // the replaced entries may have been the last spendings from
+            // an account
+            let inputs = if replaced_any {
+                spent_inputs(&fragment, |account| self.next_counter(account))
             } else {
-                None
+                inputs
+            };
+            let parents = self.parents_of(&fragment);
+            for input in inputs.iter() {
+                if let SpentInput::Account(account, counter) = input {
+                    self.by_account
+                        .entry(account.clone())
+                        .or_default()
+                        .insert(*counter, fragment_id.clone());
+                }
+                self.by_spent_input
+                    .insert(input.clone(), fragment_id.clone());
             }
+
+            *self.by_sender.entry(sender).or_insert(0) += 1;
+            self.size += entry.fragment_size();
+            // the entries restored from the saved pool expire `ttl`
+            // after they were first received
+            let ttl = remaining_ttl(self.ttl, entry.received_at()).unwrap_or_default();
+            let delay = self.expirations.insert(fragment_id.clone(), ttl);
+
+            for id in spent_transactions(&fragment) {
+                self.by_spent_transaction
+                    .entry(id)
+                    .or_default()
+                    .insert(fragment_id.clone());
+            }
+
+            let children = match transaction_id(&fragment) {
+                Some(id) => {
+                    let children = self
+                        .by_spent_transaction
+                        .get(&id)
+                        .cloned()
+                        .unwrap_or_default();
+                    self.by_transaction.insert(id, fragment_id.clone());
+                    children
+                }
+                None => HashSet::new(),
+            };
+
+            for parent in parents.iter() {
+                self.children
+                    .entry(parent.clone())
+                    .or_default()
+                    .insert(fragment_id.clone());
+            }
+            for child in children.iter() {
+                self.parents
+                    .entry(child.clone())
+                    .or_default()
+                    .insert(fragment_id.clone());
+            }
+            self.parents.insert(fragment_id.clone(), parents);
+            self.children.insert(fragment_id.clone(), children);
+
+            let entry = Entry {
+                entry,
+                fragment,
+                inputs,
+                expiration: delay,
+                seq: self.next_seq,
+            };
+            self.next_seq += 1;
+            self.by_time.insert(entry.time_key(), fragment_id.clone());
+            self.by_ancestors_rate
+                .insert(entry.ancestors_key(), fragment_id.clone());
+            self.by_descendants_rate
+                .insert(entry.descendants_key(), fragment_id.clone());
+            let fragment_rate = (*entry.entry.fragment_fee(), *entry.entry.fragment_size());
+            self.entries.insert(fragment_id.clone(), entry);
+
+            let ancestors = reachable(&self.parents, &fragment_id);
+            let descendants = reachable(&self.children, &fragment_id);
+            let ancestors_rate = self.accumulate(&ancestors);
+            let descendants_rate = self.accumulate(&descendants);
+            self.set_aggregates(fragment_id.clone(), ancestors_rate, descendants_rate);
+            self.update_related(fragment_rate, ancestors, descendants, true);
+
+            Ok(PoolEntryStats::from(&self.entries[&fragment_id].entry))
+        }
+
+        pub fn contains(&self, fragment_id: &FragmentId) -> bool {
+            self.entries.contains_key(fragment_id)
+        }
+
+        pub fn entry(&self, fragment_id: &FragmentId) -> Option<&PoolEntry> {
+            self.entries.get(fragment_id).map(|entry| &entry.entry)
+        }
+
+        /// the pending fragment received first
+        pub fn oldest(&self) -> Option<FragmentId> {
+            self.by_time.values().next().cloned()
+        }
+
+        /// the pending fragment whose package with its ancestors pays
+        /// the highest fee per byte, the oldest one between packages of
+        /// the same fee rate. The `skipped` fragments are ignored.
+        pub fn best_package(&self, skipped: &HashSet<FragmentId>) -> Option<FragmentId> {
+            self.by_ancestors_rate
+                .values()
+                .rev()
+                .find(|fragment_id| !skipped.contains(*fragment_id))
+                .cloned()
+        }
+
+        /// whether an entry received at the given time has expired
+        pub fn is_expired(&self, received_at: &SystemTime) -> bool {
+            remaining_ttl(self.ttl, received_at).is_none()
+        }
+
+        /// the entries to save, oldest first so the fragments come
+        /// before the fragments spending their outputs
+        pub fn saved_entries(&self) -> Vec<SavedEntry> {
+            self.by_time
+                .values()
+                .filter_map(|id| self.entries.get(id))
+                .map(|entry| SavedEntry {
+                    fragment: entry.fragment.clone(),
+                    origin: *entry.entry.received_from(),
+                    received_at: *entry.entry.received_at(),
+                })
+                .collect()
+        }
+
+        pub fn get(&self, fragment_ids: &[FragmentId]) -> Vec<Fragment> {
+            fragment_ids
+                .iter()
+                .filter_map(|id| self.entries.get(id))
+                .map(|entry| entry.fragment.clone())
+                .collect()
+        }
+
+        pub fn remove(&mut self, fragment_id: &FragmentId) -> Option<(PoolEntry, Fragment)> {
+            self.unlink(fragment_id)
+                .map(|(entry, fragment, cache_key)| {
+                    self.expirations.remove(&cache_key);
+                    (entry, fragment)
+                })
         }
 
         pub fn poll_purge(&mut self) -> Poll<(), timer::Error> {
-            while let Some(entry) = try_ready!(self.expirations.poll()) {
-                self.entries.remove(entry.get_ref());
-                self.entries_by_id.remove(entry.get_ref());
-                self.entries_by_time
-                    .iter()
-                    .position(|id| id == entry.get_ref())
-                    .map(|position| {
-                        self.entries_by_time.remove(position);
-                    });
+            // stop at the first entry not expired yet
+            while let Async::Ready(Some(entry)) = self.expirations.poll()? {
+                self.unlink(entry.get_ref());
             }
 
             Ok(Async::Ready(()))
         }
+
+        /// the pending fragments the given fragment depends on, directly
+        /// or not (see `parents_of`)
+        pub fn ancestors(&self, fragment_id: &FragmentId) -> HashSet<FragmentId> {
+            reachable(&self.parents, fragment_id)
+        }
+
+        /// the pending fragments the given fragment depends on, directly
+        /// or not, the fragment may not be in the pool. The fragments
+        /// come after their own ancestors.
+        pub fn pending_ancestors(&self, fragment: &Fragment) -> Vec<Fragment> {
+            let parents = match self.parents.get(&fragment.id()) {
+                Some(parents) => parents.clone(),
+                None => self.parents_of(fragment),
+            };
+            let mut visited = HashSet::new();
+            let mut ordered = Vec::new();
+            for parent in parents.iter() {
+                self.visit_ancestors(parent, &mut visited, &mut ordered);
+            }
+            ordered
+                .iter()
+                .filter_map(|id| self.entries.get(id))
+                .map(|entry| entry.fragment.clone())
+                .collect()
+        }
+
+        /// the pending fragments a fragment not in the pool yet depends
+        /// on: the fragments whose outputs it spends and the last pending
+        /// spendings from the accounts it spends from
+        fn parents_of(&self, fragment: &Fragment) -> HashSet<FragmentId> {
+            let spent = spent_transactions(fragment)
+                .into_iter()
+                .filter_map(|id| self.by_transaction.get(&id).cloned());
+            let previous = spent_accounts(fragment).into_iter().filter_map(|account| {
+                self.by_account
+                    .get(&account)
+                    .and_then(|spendings| spendings.values().next_back().cloned())
+            });
+            spent.chain(previous).collect()
+        }
+
+        /// the spending counter following the last pending spending from
+        /// the account
+        fn next_counter(&self, account: &AccountIdentifier) -> u32 {
+            self.by_account
+                .get(account)
+                .and_then(|spendings| spendings.keys().next_back())
+                .map_or(0, |counter| counter + 1)
+        }
+
+        /// add the entry to `ordered` after its ancestors
+        fn visit_ancestors(
+            &self,
+            fragment_id: &FragmentId,
+            visited: &mut HashSet<FragmentId>,
+            ordered: &mut Vec<FragmentId>,
+        ) {
+            if !visited.insert(fragment_id.clone()) {
+                return;
+            }
+            if let Some(parents) = self.parents.get(fragment_id) {
+                for parent in parents {
+                    self.visit_ancestors(parent, visited, ordered);
+                }
+            }
+            ordered.push(fragment_id.clone());
+        }
+
+        /// update the stats of the logs of the entries that changed and
+        /// the status of the evicted entries
+        pub fn update_logs(&mut self, logs: &mut Logs) {
+            for fragment_id in self.updated.drain() {
+                if let Some(entry) = self.entries.get(&fragment_id) {
+                    logs.update_entry(&fragment_id, PoolEntryStats::from(&entry.entry));
+                }
+            }
+            for (entry, status) in self.removed.drain(..) {
+                logs.modify_entry(&entry, status);
+            }
+        }
+
+        /// the entries the given entry replaces: the entries it
+        /// conflicts with and their descendants, which cannot be added
+        /// to the blockchain without them.
+        ///
+        /// The entry must pay more fee than all of them.
+        fn replaced_by(
+            &self,
+            entry: &PoolEntry,
+            conflicts: HashSet<FragmentId>,
+        ) -> Result<HashSet<FragmentId>, InsertError> {
+            let mut replaced = HashSet::new();
+            for fragment_id in conflicts {
+                replaced.extend(reachable(&self.children, &fragment_id));
+                replaced.insert(fragment_id);
+            }
+
+            let (replaced_fee, _) = self.accumulate(&replaced);
+            if entry.fragment_fee().0 <= replaced_fee.0 {
+                return Err(InsertError::ReplacementFeeTooLow);
+            }
+            Ok(replaced)
+        }
+
+        /// evict the entries paying the lowest fee per byte until the
+        /// given entry fits in the pool, the entries it replaces
+        /// excluded. Nothing is evicted if the entry cannot fit without
+        /// evicting entries paying more than it does.
+        ///
+        /// The descendants of an evicted entry cannot be added to a
+        /// block anymore, they are evicted with it: the fee rate of
+        /// an entry includes its descendants. For the same reason the
+        /// ancestors of the given entry are never evicted.
+        fn make_room(
+            &mut self,
+            entry: &PoolEntry,
+            fragment: &Fragment,
+            replaced: &HashSet<FragmentId>,
+        ) -> Result<(), InsertError> {
+            let (_, replaced_size) = self.accumulate(replaced);
+            let mut count = self.entries.len() - replaced.len();
+            let mut size = self.size - replaced_size;
+            let limits = self.limits;
+            let fits = |count: usize, size: usize| {
+                count < limits.max_entries && size + entry.fragment_size() <= limits.max_size
+            };
+
+            if fits(count, size) {
+                return Ok(());
+            }
+
+            let mut ancestors = HashSet::new();
+            for parent in self.parents_of(fragment) {
+                ancestors.extend(reachable(&self.parents, &parent));
+                ancestors.insert(parent);
+            }
+
+            let entry_rate = Rate(*entry.fragment_fee(), *entry.fragment_size());
+            let mut evicted = replaced.clone();
+            for ((rate, _), fragment_id) in self.by_descendants_rate.iter() {
+                if fits(count, size) {
+                    break;
+                }
+                // the descendants of an entry which is not an ancestor
+                // are not ancestors either
+                if evicted.contains(fragment_id) || ancestors.contains(fragment_id) {
+                    continue;
+                }
+                if *rate >= entry_rate {
+                    return Err(InsertError::PoolFull);
+                }
+
+                let mut package = reachable(&self.children, fragment_id);
+                package.insert(fragment_id.clone());
+                for id in package {
+                    if !evicted.contains(&id) {
+                        if let Some(evicted_entry) = self.entries.get(&id) {
+                            count -= 1;
+                            size -= evicted_entry.entry.fragment_size();
+                            evicted.insert(id);
+                        }
+                    }
+                }
+            }
+            if !fits(count, size) {
+                return Err(InsertError::PoolFull);
+            }
+
+            for fragment_id in evicted.difference(replaced) {
+                if let Some((evicted_entry, _)) = self.remove(fragment_id) {
+                    self.removed.push((
+                        evicted_entry,
+                        Status::Rejected {
+                            reason: "evicted from the full fragment pool".to_owned(),
+                        },
+                    ));
+                }
+            }
+            Ok(())
+        }
+
+        // remove the entry and its links to the other entries, the
+        // key of the entry in the expiration queue is returned so it can
+        // be removed if it has not expired.
+        fn unlink(
+            &mut self,
+            fragment_id: &FragmentId,
+        ) -> Option<(PoolEntry, Fragment, delay_queue::Key)> {
+            let removed = self.entries.remove(fragment_id)?;
+            self.by_time.remove(&removed.time_key());
+            self.by_ancestors_rate.remove(&removed.ancestors_key());
+            self.by_descendants_rate.remove(&removed.descendants_key());
+            let Entry {
+                entry,
+                fragment,
+                inputs,
+                expiration,
+                ..
+            } = removed;
+            let ancestors = reachable(&self.parents, fragment_id);
+            let descendants = reachable(&self.children, fragment_id);
+
+            self.size -= entry.fragment_size();
+            if let hash_map::Entry::Occupied(mut count) =
+                self.by_sender.entry(entry.received_from().sender())
+            {
+                *count.get_mut() -= 1;
+                if *count.get() == 0 {
+                    count.remove();
+                }
+            }
+
+            for parent in self.parents.remove(fragment_id).unwrap_or_default() {
+                if let Some(children) = self.children.get_mut(&parent) {
+                    children.remove(fragment_id);
+                }
+            }
+            for child in self.children.remove(fragment_id).unwrap_or_default() {
+                if let Some(parents) = self.parents.get_mut(&child) {
+                    parents.remove(fragment_id);
+                }
+            }
+
+            if let Some(id) = transaction_id(&fragment) {
+                if self.by_transaction.get(&id) == Some(fragment_id) {
+                    self.by_transaction.remove(&id);
+                }
+            }
+            for input in inputs {
+                if let SpentInput::Account(ref account, counter) = input {
+                    if let hash_map::Entry::Occupied(mut spendings) =
+                        self.by_account.entry(account.clone())
+                    {
+                        spendings.get_mut().remove(&counter);
+                        if spendings.get().is_empty() {
+                            spendings.remove();
+                        }
+                    }
+                }
+                if self.by_spent_input.get(&input) == Some(fragment_id) {
+                    self.by_spent_input.remove(&input);
+                }
+            }
+            for id in spent_transactions(&fragment) {
+                if let hash_map::Entry::Occupied(mut spenders) = self.by_spent_transaction.entry(id)
+                {
+                    spenders.get_mut().remove(fragment_id);
+                    if spenders.get().is_empty() {
+                        spenders.remove();
+                    }
+                }
+            }
+
+            self.updated.remove(fragment_id);
+            let fragment_rate = (*entry.fragment_fee(), *entry.fragment_size());
+            self.update_related(fragment_rate, ancestors, descendants, false);
+
+            Some((entry, fragment, expiration))
+        }
+
+        /// update the aggregates of the ancestors and of the descendants
+        /// of an entry just inserted (`added`) or removed, given its fee
+        /// and size.
+        ///
+        /// Usually the entry is a leaf, whose ancestors gain or lose it as
+        /// a descendant, or a root, whose descendants gain or lose it as
+        /// an ancestor: its fee and size are added to or subtracted from
+        /// their aggregates along the path. An entry in the middle of a
+        /// chain may connect or disconnect the others through several
+        /// paths, their aggregates are computed again.
+        fn update_related(
+            &mut self,
+            (fee, size): (Value, usize),
+            ancestors: HashSet<FragmentId>,
+            descendants: HashSet<FragmentId>,
+            added: bool,
+        ) {
+            let shift = |(total_fee, total_size): (Value, usize)| {
+                if added {
+                    (Value(total_fee.0.saturating_add(fee.0)), total_size + size)
+                } else {
+                    (
+                        Value(total_fee.0.saturating_sub(fee.0)),
+                        total_size.saturating_sub(size),
+                    )
+                }
+            };
+
+            if descendants.is_empty() {
+                for fragment_id in ancestors {
+                    if let Some((ancestors, descendants)) = self.aggregates(&fragment_id) {
+                        self.set_aggregates(fragment_id, ancestors, shift(descendants));
+                    }
+                }
+            } else if ancestors.is_empty() {
+                for fragment_id in descendants {
+                    if let Some((ancestors, descendants)) = self.aggregates(&fragment_id) {
+                        self.set_aggregates(fragment_id, shift(ancestors), descendants);
+                    }
+                }
+            } else {
+                let mut related = ancestors;
+                related.extend(descendants);
+                self.recompute(related);
+            }
+        }
+
+        fn recompute(&mut self, fragment_ids: HashSet<FragmentId>) {
+            for fragment_id in fragment_ids {
+                let ancestors = self.accumulate(&reachable(&self.parents, &fragment_id));
+                let descendants = self.accumulate(&reachable(&self.children, &fragment_id));
+                self.set_aggregates(fragment_id, ancestors, descendants);
+            }
+        }
+
+        /// the accumulated fee and size of the ancestors and of the
+        /// descendants of the entry
+        fn aggregates(&self, fragment_id: &FragmentId) -> Option<((Value, usize), (Value, usize))> {
+            self.entries.get(fragment_id).map(|entry| {
+                let entry = &entry.entry;
+                (
+                    (*entry.ancestors_fee(), entry.ancestors_size()),
+                    (*entry.descendants_fee(), entry.descendants_size()),
+                )
+            })
+        }
+
+        /// set the accumulated fee and size of the ancestors and of the
+        /// descendants of the entry, and index it again by its rates
+        fn set_aggregates(
+            &mut self,
+            fragment_id: FragmentId,
+            (ancestors_fee, ancestors_size): (Value, usize),
+            (descendants_fee, descendants_size): (Value, usize),
+        ) {
+            if let Some(entry) = self.entries.get_mut(&fragment_id) {
+                self.by_ancestors_rate.remove(&entry.ancestors_key());
+                self.by_descendants_rate.remove(&entry.descendants_key());
+                entry.entry.set_ancestors(ancestors_fee, ancestors_size);
+                entry
+                    .entry
+                    .set_descendants(descendants_fee, descendants_size);
+                self.by_ancestors_rate
+                    .insert(entry.ancestors_key(), fragment_id.clone());
+                self.by_descendants_rate
+                    .insert(entry.descendants_key(), fragment_id.clone());
+                self.updated.insert(fragment_id);
+            }
+        }
+
+        fn accumulate(&self, fragment_ids: &HashSet<FragmentId>) -> (Value, usize) {
+            let (fee, size) = fragment_ids
+                .iter()
+                .filter_map(|id| self.entries.get(id))
+                .fold((0u64, 0usize), |(fee, size), entry| {
+                    (
+                        fee.saturating_add(entry.entry.fragment_fee().0),
+                        size + entry.entry.fragment_size(),
+                    )
+                });
+            (Value(fee), size)
+        }
+    }
+
+    /// the accumulated fee and size of the entry and its descendants
+    fn descendants_rate(entry: &PoolEntry) -> (Value, usize) {
+        (
+            entry.with_descendants_fee().unwrap_or(Value::zero()),
+            entry.with_descendants_size(),
+        )
+    }
+
+    /// compare the fees per byte
+    fn compare_fee_rates(
+        (a_fee, a_size): (Value, usize),
+        (b_fee, b_size): (Value, usize),
+    ) -> Ordering {
+        (a_fee.0 as u128 * b_size as u128).cmp(&(b_fee.0 as u128 * a_size as u128))
+    }
+
+    /// a fee per byte: a fee and the size it is paid for
+    #[derive(Clone, Copy)]
+    struct Rate(Value, usize);
+
+    impl Ord for Rate {
+        fn cmp(&self, other: &Self) -> Ordering {
+            compare_fee_rates((self.0, self.1), (other.0, other.1))
+        }
+    }
+
+    impl PartialOrd for Rate {
+        fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+            Some(self.cmp(other))
+        }
+    }
+
+    impl PartialEq for Rate {
+        fn eq(&self, other: &Self) -> bool {
+            self.cmp(other) == Ordering::Equal
+        }
+    }
+
+    impl Eq for Rate {}
+
+    impl Entry {
+        fn time_key(&self) -> TimeKey {
+            (*self.entry.received_at(), self.seq)
+        }
+
+        fn ancestors_key(&self) -> AncestorsKey {
+            let rate = Rate(
+                self.entry.with_ancestors_fee().unwrap_or(Value::zero()),
+                self.entry.with_ancestors_size(),
+            );
+            (rate, Reverse(self.time_key()))
+        }
+
+        fn descendants_key(&self) -> DescendantsKey {
+            let (fee, size) = descendants_rate(&self.entry);
+            (Rate(fee, size), self.seq)
+        }
+    }
+
+    /// the entries reachable from the given one following the links,
+    /// the given entry excluded
+    fn reachable(links: &Links, from: &FragmentId) -> HashSet<FragmentId> {
+        let mut reached = HashSet::new();
+        let mut to_visit: Vec<&FragmentId> = links.get(from).into_iter().flatten().collect();
+        while let Some(fragment_id) = to_visit.pop() {
+            if fragment_id != from && reached.insert(fragment_id.clone()) {
+                to_visit.extend(links.get(fragment_id).into_iter().flatten());
+            }
+        }
+        reached
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        blockcfg::{BlockDate, Message},
+        fragment::{
+            logs,
+            testing::{outputs, run, TestLedger, Utxo},
+        },
+    };
+    use chain_core::property::Message as _;
+    use chain_impl_mockchain::transaction::{
+        AuthenticatedTransaction, Input, NoExtra, Transaction,
+    };
+    use slog::{Discard, Logger};
+    use std::collections::HashSet;
+    use tokio::runtime::current_thread::Runtime;
+
+    const LIMITS: PoolLimits = PoolLimits {
+        max_entries: 100,
+        max_size: usize::max_value(),
+        max_entries_per_origin: usize::max_value(),
+    };
+
+    const ORIGIN: Origin = Origin::Rest { addr: None };
+
+    /// the pool with the runtime whose timer expires its entries
+    struct TestPool {
+        runtime: Runtime,
+        pool: Pool,
+        test_ledger: TestLedger,
+    }
+
+    impl TestPool {
+        fn new(funds: usize) -> (Self, Vec<Utxo>) {
+            let (test_ledger, utxos) = TestLedger::new(funds, 1_000);
+            let logs = Logs::new(Duration::from_secs(60), Logger::root(Discard, o!()));
+            let test_pool = TestPool {
+                runtime: Runtime::new().unwrap(),
+                pool: Pool::new(
+                    Duration::from_secs(60),
+                    LIMITS,
+                    ConflictPolicy::Reject,
+                    logs,
+                ),
+                test_ledger,
+            };
+            (test_pool, utxos)
+        }
+
+        fn insert(&mut self, fragment: &Fragment) -> Result<bool, InsertError> {
+            let insert =
+                self.pool
+                    .insert(ORIGIN, fragment.clone(), self.test_ledger.params.clone());
+            self.runtime.block_on(insert)
+        }
+
+        fn status(&mut self, fragment: &Fragment) -> Option<Status> {
+            let id = fragment.id();
+            let status = self
+                .pool
+                .logs()
+                .inner::<()>()
+                .map(move |logs| logs.get(&id).map(|log| log.status.clone()));
+            self.runtime.block_on(status).unwrap()
+        }
+
+        fn contains(&mut self, fragment: &Fragment) -> bool {
+            let get = self.pool.get(vec![fragment.id()]);
+            !self.runtime.block_on(get).unwrap().is_empty()
+        }
+
+        fn stats(&mut self, fragment: &Fragment) -> Option<PoolEntryStats> {
+            let id = fragment.id();
+            let mut lock = self.pool.pool.clone();
+            let stats = future::poll_fn(move || Ok::<_, ()>(lock.poll_lock()))
+                .map(move |pool| pool.entry(&id).map(PoolEntryStats::from));
+            self.runtime.block_on(stats).unwrap()
+        }
+
+        fn roll_back(&mut self, returned: Vec<Fragment>, committed: Vec<(FragmentId, BlockDate)>) {
+            let returned = returned
+                .into_iter()
+                .map(|fragment| (fragment, Ok(())))
+                .collect();
+            let roll_back =
+                self.pool
+                    .roll_back(returned, committed, self.test_ledger.params.clone());
+            self.runtime.block_on(roll_back).unwrap();
+        }
+    }
+
+    #[test]
+    fn rejected_fragment_is_tried_again() {
+        let (mut test_pool, utxos) = TestPool::new(1);
+        let fragment = test_pool.test_ledger.spend(&utxos, 1);
+        let reject = test_pool.pool.reject::<()>(
+            ORIGIN,
+            fragment.clone(),
+            test_pool.test_ledger.params.clone(),
+            "rejected".to_owned(),
+        );
+        test_pool.runtime.block_on(reject).unwrap();
+
+        assert!(test_pool.insert(&fragment).unwrap());
+        assert_eq!(test_pool.status(&fragment), Some(Status::Pending));
+        assert!(test_pool.contains(&fragment));
+        // already pending
+        assert!(!test_pool.insert(&fragment).unwrap());
+    }
+
+    #[test]
+    fn fragment_in_a_block_is_not_inserted_again() {
+        let (mut test_pool, utxos) = TestPool::new(1);
+        let fragment = test_pool.test_ledger.spend(&utxos, 1);
+        let date = BlockDate {
+            epoch: 0,
+            slot_id: 1,
+        };
+        assert!(test_pool.insert(&fragment).unwrap());
+        let roll_back = test_pool.pool.roll_back(
+            Vec::new(),
+            vec![(fragment.id(), date)],
+            test_pool.test_ledger.params.clone(),
+        );
+        test_pool.runtime.block_on(roll_back).unwrap();
+
+        assert!(!test_pool.insert(&fragment).unwrap());
+        assert_eq!(test_pool.status(&fragment), Some(Status::InABlock { date }));
+        assert!(!test_pool.contains(&fragment));
+    }
+
+    /// a spending of the given value from the test account, the pool
+    /// does not check the witnesses
+    fn account_spending(value: u64) -> Fragment {
+        Message::Transaction(AuthenticatedTransaction {
+            transaction: Transaction {
+                inputs: vec![Input {
+                    index_or_account: 0xff,
+                    value: Value(value),
+                    input_ptr: [1; 32],
+                }],
+                outputs: vec![],
+                extra: NoExtra,
+            },
+            witnesses: vec![],
+        })
+    }
+
+    fn insert_with_fee(
+        pool: &mut internal::Pool,
+        fragment: &Fragment,
+        fee: u64,
+    ) -> Result<PoolEntryStats, InsertError> {
+        let entry = PoolEntry::with_fee(fragment, ORIGIN, Value(fee));
+        pool.insert(entry, fragment.clone())
+    }
+
+    fn ids(fragments: &[Fragment]) -> Vec<FragmentId> {
+        fragments.iter().map(|fragment| fragment.id()).collect()
+    }
+
+    #[test]
+    fn sequential_account_spendings_are_accepted() {
+        run(|| {
+            let mut pool =
+                internal::Pool::new(Duration::from_secs(60), LIMITS, ConflictPolicy::Reject);
+            let first = account_spending(10);
+            let second = account_spending(20);
+            let third = account_spending(30);
+
+            insert_with_fee(&mut pool, &first, 1).unwrap();
+            insert_with_fee(&mut pool, &second, 1).unwrap();
+
+            // the second spending is signed for the counter following
+            // the first one, it cannot be applied before it
+            assert_eq!(ids(&pool.pending_ancestors(&second)), vec![first.id()]);
+            assert_eq!(
+                pool.ancestors(&second.id()),
+                vec![first.id()].into_iter().collect()
+            );
+
+            // the first spending is added to a block
+            pool.remove(&first.id());
+            insert_with_fee(&mut pool, &third, 1).unwrap();
+            assert_eq!(ids(&pool.pending_ancestors(&third)), vec![second.id()]);
+        });
+    }
+
+    fn internal_pool(max_entries: usize, conflict_policy: ConflictPolicy) -> internal::Pool {
+        let limits = PoolLimits {
+            max_entries,
+            ..LIMITS
+        };
+        internal::Pool::new(Duration::from_secs(60), limits, conflict_policy)
+    }
+
+    fn internal_logs() -> logs::internal::Logs {
+        logs::internal::Logs::new(Duration::from_secs(60), Logger::root(Discard, o!()))
+    }
+
+    fn status(logs: &logs::internal::Logs, fragment: &Fragment) -> Option<Status> {
+        logs.get(&fragment.id()).map(|log| log.status.clone())
+    }
+
+    fn is_rejected(logs: &logs::internal::Logs, fragment: &Fragment) -> bool {
+        match status(logs, fragment) {
+            Some(Status::Rejected { .. }) => true,
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn full_pool_evicts_the_lowest_fee_rate() {
+        run(|| {
+            let (test_ledger, utxos) = TestLedger::new(3, 1_000);
+            let fragments: Vec<Fragment> = utxos
+                .chunks(1)
+                .map(|utxo| test_ledger.spend(utxo, 1))
+                .collect();
+            let mut pool = internal_pool(2, ConflictPolicy::Reject);
+            let mut logs = internal_logs();
+            insert_with_fee(&mut pool, &fragments[0], 5).unwrap();
+            insert_with_fee(&mut pool, &fragments[1], 1).unwrap();
+
+            // does not pay more than any entry
+            match insert_with_fee(&mut pool, &fragments[2], 1) {
+                Err(InsertError::PoolFull) => {}
+                _ => panic!("the fragment is added to the full pool"),
+            }
+            assert!(pool.contains(&fragments[1].id()));
+
+            insert_with_fee(&mut pool, &fragments[2], 10).unwrap();
+            pool.update_logs(&mut logs);
+            assert!(pool.contains(&fragments[0].id()));
+            assert!(!pool.contains(&fragments[1].id()));
+            assert!(pool.contains(&fragments[2].id()));
+            assert!(is_rejected(&logs, &fragments[1]));
+        });
+    }
+
+    #[test]
+    fn descendants_are_evicted_with_their_ancestor() {
+        run(|| {
+            let (test_ledger, utxos) = TestLedger::new(3, 1_000);
+            let parent = test_ledger.spend(&utxos[0..1], 1);
+            let child = test_ledger.spend(&outputs(&parent), 1);
+            let other = test_ledger.spend(&utxos[1..2], 1);
+            let incoming = test_ledger.spend(&utxos[2..3], 1);
+            let mut pool = internal_pool(3, ConflictPolicy::Reject);
+            let mut logs = internal_logs();
+            insert_with_fee(&mut pool, &parent, 1).unwrap();
+            insert_with_fee(&mut pool, &child, 2).unwrap();
+            insert_with_fee(&mut pool, &other, 5).unwrap();
+
+            insert_with_fee(&mut pool, &incoming, 10).unwrap();
+            pool.update_logs(&mut logs);
+
+            assert!(!pool.contains(&parent.id()));
+            assert!(!pool.contains(&child.id()));
+            assert!(pool.contains(&other.id()));
+            assert!(is_rejected(&logs, &parent));
+            assert!(is_rejected(&logs, &child));
+        });
+    }
+
+    #[test]
+    fn ancestors_of_the_incoming_fragment_are_not_evicted() {
+        run(|| {
+            let (test_ledger, utxos) = TestLedger::new(2, 1_000);
+            let parent = test_ledger.spend(&utxos[0..1], 1);
+            let other = test_ledger.spend(&utxos[1..2], 1);
+            let child = test_ledger.spend(&outputs(&parent), 1);
+            let mut pool = internal_pool(2, ConflictPolicy::Reject);
+            insert_with_fee(&mut pool, &parent, 1).unwrap();
+            insert_with_fee(&mut pool, &other, 5).unwrap();
+
+            insert_with_fee(&mut pool, &child, 10).unwrap();
+
+            assert!(pool.contains(&parent.id()));
+            assert!(pool.contains(&child.id()));
+            assert!(!pool.contains(&other.id()));
+        });
+    }
+
+    #[test]
+    fn double_spend_is_rejected() {
+        run(|| {
+            let (test_ledger, utxos) = TestLedger::new(1, 1_000);
+            let first = test_ledger.spend(&utxos, 1);
+            let second = test_ledger.spend(&utxos, 2);
+            let mut pool = internal_pool(10, ConflictPolicy::Reject);
+            insert_with_fee(&mut pool, &first, 1).unwrap();
+
+            match insert_with_fee(&mut pool, &second, 100) {
+                Err(InsertError::DoubleSpend) => {}
+                _ => panic!("the double spend is not rejected"),
+            }
+            assert!(pool.contains(&first.id()));
+            assert!(!pool.contains(&second.id()));
+        });
+    }
+
+    #[test]
+    fn conflicting_fragments_are_replaced_by_fee() {
+        run(|| {
+            let (test_ledger, utxos) = TestLedger::new(1, 1_000);
+            let first = test_ledger.spend(&utxos, 1);
+            let child = test_ledger.spend(&outputs(&first), 1);
+            let second = test_ledger.spend(&utxos, 2);
+            let mut pool = internal_pool(10, ConflictPolicy::ReplaceByFee);
+            let mut logs = internal_logs();
+            insert_with_fee(&mut pool, &first, 5).unwrap();
+            insert_with_fee(&mut pool, &child, 5).unwrap();
+
+            // the child cannot be added without the replaced fragment,
+            // its fee counts too
+            match insert_with_fee(&mut pool, &second, 10) {
+                Err(InsertError::ReplacementFeeTooLow) => {}
+                _ => panic!("the replacement paying too little is accepted"),
+            }
+            assert!(pool.contains(&first.id()));
+
+            insert_with_fee(&mut pool, &second, 11).unwrap();
+            pool.update_logs(&mut logs);
+
+            assert!(pool.contains(&second.id()));
+            for replaced in [&first, &child].iter() {
+                assert!(!pool.contains(&replaced.id()));
+                assert_eq!(
+                    status(&logs, replaced),
+                    Some(Status::Replaced { by: second.id() })
+                );
+            }
+        });
+    }
+
+    fn aggregates(pool: &internal::Pool, fragment: &Fragment) -> ((u64, usize), (u64, usize)) {
+        let entry = pool.entry(&fragment.id()).unwrap();
+        (
+            (entry.ancestors_fee().0, entry.ancestors_size()),
+            (entry.descendants_fee().0, entry.descendants_size()),
+        )
+    }
+
+    #[test]
+    fn aggregates_follow_the_insertions_and_the_removals() {
+        run(|| {
+            let (test_ledger, utxos) = TestLedger::new(1, 1_000);
+            let parent = test_ledger.spend(&utxos, 1);
+            let child = test_ledger.spend(&outputs(&parent), 1);
+            let grandchild = test_ledger.spend(&outputs(&child), 1);
+            let mut pool = internal_pool(10, ConflictPolicy::Reject);
+            // received before the fragments they depend on
+            let g = insert_with_fee(&mut pool, &grandchild, 4).unwrap().size;
+            let c = insert_with_fee(&mut pool, &child, 2).unwrap().size;
+            let p = insert_with_fee(&mut pool, &parent, 1).unwrap().size;
+
+            assert_eq!(aggregates(&pool, &parent), ((0, 0), (6, c + g)));
+            assert_eq!(aggregates(&pool, &child), ((1, p), (4, g)));
+            assert_eq!(aggregates(&pool, &grandchild), ((3, p + c), (0, 0)));
+
+            // the parent and the grandchild are not related anymore
+            pool.remove(&child.id());
+            assert_eq!(aggregates(&pool, &parent), ((0, 0), (0, 0)));
+            assert_eq!(aggregates(&pool, &grandchild), ((0, 0), (0, 0)));
+
+            insert_with_fee(&mut pool, &child, 2).unwrap();
+            assert_eq!(aggregates(&pool, &parent), ((0, 0), (6, c + g)));
+            assert_eq!(aggregates(&pool, &child), ((1, p), (4, g)));
+            assert_eq!(aggregates(&pool, &grandchild), ((3, p + c), (0, 0)));
+
+            pool.remove(&parent.id());
+            assert_eq!(aggregates(&pool, &child), ((0, 0), (4, g)));
+            assert_eq!(aggregates(&pool, &grandchild), ((2, c), (0, 0)));
+        });
+    }
+
+    #[test]
+    fn roll_back_updates_the_aggregates() {
+        let (mut test_pool, utxos) = TestPool::new(1);
+        let parent = test_pool.test_ledger.spend(&utxos, 1);
+        let child = test_pool.test_ledger.spend(&outputs(&parent), 1);
+        let date = BlockDate {
+            epoch: 0,
+            slot_id: 1,
+        };
+        test_pool.insert(&parent).unwrap();
+        test_pool.insert(&child).unwrap();
+        let parent_size = test_pool.stats(&parent).unwrap().size;
+        let child_size = test_pool.stats(&child).unwrap().size;
+
+        test_pool.roll_back(Vec::new(), vec![(parent.id(), date)]);
+        assert!(!test_pool.contains(&parent));
+        assert_eq!(test_pool.status(&parent), Some(Status::InABlock { date }));
+        assert_eq!(test_pool.stats(&child).unwrap().ancestors_size, 0);
+
+        // the block of the parent is rolled back
+        test_pool.roll_back(vec![parent.clone()], Vec::new());
+        assert!(test_pool.contains(&parent));
+        assert_eq!(test_pool.status(&parent), Some(Status::Pending));
+        assert_eq!(
+            test_pool.stats(&parent).unwrap().descendants_size,
+            child_size
+        );
+        assert_eq!(test_pool.stats(&child).unwrap().ancestors_size, parent_size);
+    }
+
+    #[test]
+    fn restore_puts_back_the_valid_entries() {
+        let (mut test_pool, utxos) = TestPool::new(3);
+        let valid = test_pool.test_ledger.spend(&utxos[0..1], 1);
+        let invalid = test_pool.test_ledger.spend(&utxos[1..2], 1);
+        let expired = test_pool.test_ledger.spend(&utxos[2..3], 1);
+        let committed = test_pool.test_ledger.spend(&utxos[2..3], 2);
+        let date = BlockDate {
+            epoch: 0,
+            slot_id: 1,
+        };
+        let saved = |fragment: &Fragment, received_at| SavedEntry {
+            fragment: fragment.clone(),
+            origin: ORIGIN,
+            received_at,
+        };
+        let now = SystemTime::now();
+        let entries = vec![
+            (saved(&valid, now), Ok(())),
+            (saved(&invalid, now), Err(ValidationError::NoInputs)),
+            (saved(&expired, now - Duration::from_secs(3600)), Ok(())),
+        ];
+        let saved_logs = vec![Log::without_entry(
+            committed.id(),
+            Status::InABlock { date },
+        )];
+
+        let restore =
+            test_pool
+                .pool
+                .restore(entries, saved_logs, test_pool.test_ledger.params.clone());
+        test_pool.runtime.block_on(restore).unwrap();
+
+        assert!(test_pool.contains(&valid));
+        assert_eq!(test_pool.status(&valid), Some(Status::Pending));
+        assert!(!test_pool.contains(&invalid));
+        match test_pool.status(&invalid) {
+            Some(Status::Rejected { .. }) => {}
+            _ => panic!("the invalid entry is not rejected"),
+        }
+        assert!(!test_pool.contains(&expired));
+        assert_eq!(test_pool.status(&expired), None);
+        assert_eq!(
+            test_pool.status(&committed),
+            Some(Status::InABlock { date })
+        );
+    }
+
+    #[test]
+    fn best_package_includes_the_ancestors() {
+        run(|| {
+            let (test_ledger, utxos) = TestLedger::new(3, 1_000);
+            let standalone = test_ledger.spend(&utxos[0..1], 1);
+            let parent = test_ledger.spend(&utxos[1..2], 1);
+            let child = test_ledger.spend(&outputs(&parent), 1);
+            let older = test_ledger.spend(&utxos[2..3], 1);
+            let mut pool = internal_pool(10, ConflictPolicy::Reject);
+            assert_eq!(pool.best_package(&HashSet::new()), None);
+
+            insert_with_fee(&mut pool, &older, 5).unwrap();
+            insert_with_fee(&mut pool, &standalone, 5).unwrap();
+            insert_with_fee(&mut pool, &parent, 1).unwrap();
+            insert_with_fee(&mut pool, &child, 20).unwrap();
+
+            let mut skipped = HashSet::new();
+            let mut best = Vec::new();
+            while let Some(fragment_id) = pool.best_package(&skipped) {
+                skipped.insert(fragment_id.clone());
+                best.push(fragment_id);
+            }
+            // the oldest first between the packages of the same fee rate
+            assert_eq!(
+                best,
+                vec![child.id(), older.id(), standalone.id(), parent.id()]
+            );
+        });
+    }
+}
+
+// Each bench of the pool has a `baseline_` counterpart running the same
+// operations on the indexes the pool had before, to compare them.
+#[cfg(all(test, feature = "with-bench"))]
+mod bench {
+    use super::{internal::Pool, ConflictPolicy, PoolLimits};
+    use crate::{
+        blockcfg::{Message, Value},
+        fragment::{Fragment, FragmentId, Origin, PoolEntry},
+    };
+    use chain_core::property::Message as _;
+    use chain_impl_mockchain::transaction::{
+        AuthenticatedTransaction, Input, NoExtra, Transaction,
+    };
+    use std::{
+        collections::{HashMap, VecDeque},
+        time::Duration,
+    };
+    use test::Bencher;
+    use tokio::{prelude::*, runtime::current_thread::Runtime};
+
+    const ENTRIES: usize = 100_000;
+
+    const LIMITS: PoolLimits = PoolLimits {
+        max_entries: ENTRIES,
+        max_size: usize::max_value(),
+        max_entries_per_origin: usize::max_value(),
+    };
+
+    /// a transaction spending an input of its own, so the fragments do
+    /// not conflict nor depend on each other
+    fn fragment(n: usize) -> Fragment {
+        let mut input_ptr = [0u8; 32];
+        input_ptr[..8].copy_from_slice(&(n as u64).to_be_bytes());
+        Message::Transaction(AuthenticatedTransaction {
+            transaction: Transaction {
+                inputs: vec![Input {
+                    index_or_account: 0,
+                    value: Value(1_000),
+                    input_ptr,
+                }],
+                outputs: vec![],
+                extra: NoExtra,
+            },
+            witnesses: vec![],
+        })
+    }
+
+    fn entry(n: usize, fragment: &Fragment) -> PoolEntry {
+        PoolEntry::with_fee(
+            fragment,
+            Origin::Rest { addr: None },
+            Value((n % 1_000) as u64),
+        )
+    }
+
+    fn fill(fragments: &[Fragment]) -> Pool {
+        let mut pool = Pool::new(Duration::from_secs(3600), LIMITS, ConflictPolicy::Reject);
+        for (n, fragment) in fragments.iter().enumerate() {
+            pool.insert(entry(n, fragment), fragment.clone()).unwrap();
+        }
+        pool
+    }
+
+    /// the indexes of the pool before they were ordered: the arrival
+    /// order is kept in a `VecDeque` searched linearly when an entry is
+    /// removed, and the entry to evict is found by scanning all the
+    /// entries. The fragments of the benches do not depend on each other
+    /// and have the same size, so the lowest fee rate is the lowest fee.
+    #[derive(Default)]
+    struct Baseline {
+        entries: HashMap<FragmentId, (PoolEntry, Fragment)>,
+        entries_by_time: VecDeque<FragmentId>,
+    }
+
+    impl Baseline {
+        fn insert(&mut self, entry: PoolEntry, fragment: Fragment) {
+            if self.entries.len() >= LIMITS.max_entries {
+                let lowest = self
+                    .entries
+                    .iter()
+                    .min_by_key(|(_, (entry, _))| entry.fragment_fee().0)
+                    .map(|(id, _)| id.clone());
+                if let Some(id) = lowest {
+                    self.remove(&id);
+                }
+            }
+            let id = entry.fragment_ref().clone();
+            self.entries_by_time.push_back(id.clone());
+            self.entries.insert(id, (entry, fragment));
+        }
+
+        fn remove(&mut self, fragment_id: &FragmentId) -> Option<(PoolEntry, Fragment)> {
+            let position = self
+                .entries_by_time
+                .iter()
+                .position(|id| id == fragment_id)?;
+            self.entries_by_time.remove(position);
+            self.entries.remove(fragment_id)
+        }
+
+        fn oldest(&self) -> Option<FragmentId> {
+            self.entries_by_time.front().cloned()
+        }
+    }
+
+    fn fill_baseline(fragments: &[Fragment]) -> Baseline {
+        let mut pool = Baseline::default();
+        for (n, fragment) in fragments.iter().enumerate() {
+            pool.insert(entry(n, fragment), fragment.clone());
+        }
+        pool
+    }
+
+    // the pool registers the expiration of its entries with the timer
+    // of the runtime
+    fn run<F: FnOnce()>(f: F) {
+        Runtime::new()
+            .unwrap()
+            .block_on(future::lazy(|| {
+                f();
+                Ok::<(), ()>(())
+            }))
+            .unwrap();
+    }
+
+    #[bench]
+    fn insert_100k(b: &mut Bencher) {
+        let fragments: Vec<Fragment> = (0..ENTRIES).map(fragment).collect();
+        run(|| b.iter(|| fill(&fragments)));
+    }
+
+    #[bench]
+    fn baseline_insert_100k(b: &mut Bencher) {
+        let fragments: Vec<Fragment> = (0..ENTRIES).map(fragment).collect();
+        b.iter(|| fill_baseline(&fragments));
+    }
+
+    #[bench]
+    fn insert_and_remove_oldest_100k(b: &mut Bencher) {
+        let fragments: Vec<Fragment> = (0..ENTRIES).map(fragment).collect();
+        run(|| {
+            b.iter(|| {
+                let mut pool = fill(&fragments);
+                while let Some(id) = pool.oldest() {
+                    pool.remove(&id);
+                }
+            })
+        });
+    }
+
+    #[bench]
+    fn baseline_insert_and_remove_oldest_100k(b: &mut Bencher) {
+        let fragments: Vec<Fragment> = (0..ENTRIES).map(fragment).collect();
+        b.iter(|| {
+            let mut pool = fill_baseline(&fragments);
+            while let Some(id) = pool.oldest() {
+                pool.remove(&id);
+            }
+        });
+    }
+
+    // an entry other than the oldest is removed, as when a fragment is
+    // added to a block or replaced, and put back
+    #[bench]
+    fn remove_newest_from_100k(b: &mut Bencher) {
+        let fragments: Vec<Fragment> = (0..ENTRIES).map(fragment).collect();
+        let newest = fragments[ENTRIES - 1].id();
+        run(|| {
+            let mut pool = fill(&fragments);
+            b.iter(|| {
+                let (entry, fragment) = pool.remove(&newest).unwrap();
+                pool.insert(entry, fragment).unwrap();
+            })
+        });
+    }
+
+    #[bench]
+    fn baseline_remove_newest_from_100k(b: &mut Bencher) {
+        let fragments: Vec<Fragment> = (0..ENTRIES).map(fragment).collect();
+        let newest = fragments[ENTRIES - 1].id();
+        let mut pool = fill_baseline(&fragments);
+        b.iter(|| {
+            let (entry, fragment) = pool.remove(&newest).unwrap();
+            pool.insert(entry, fragment);
+        });
+    }
+
+    #[bench]
+    fn evict_from_full_pool_100k(b: &mut Bencher) {
+        let fragments: Vec<Fragment> = (0..ENTRIES).map(fragment).collect();
+        run(|| {
+            let mut pool = fill(&fragments);
+            let mut n = ENTRIES;
+            b.iter(|| {
+                // pays more than any entry of the pool, the entry paying
+                // the lowest fee rate is evicted
+                let fragment = fragment(n);
+                let entry =
+                    PoolEntry::with_fee(&fragment, Origin::Rest { addr: None }, Value(n as u64));
+                pool.insert(entry, fragment).unwrap();
+                n += 1;
+            })
+        });
+    }
+
+    #[bench]
+    fn baseline_evict_from_full_pool_100k(b: &mut Bencher) {
+        let fragments: Vec<Fragment> = (0..ENTRIES).map(fragment).collect();
+        let mut pool = fill_baseline(&fragments);
+        let mut n = ENTRIES;
+        b.iter(|| {
+            let fragment = fragment(n);
+            let entry =
+                PoolEntry::with_fee(&fragment, Origin::Rest { addr: None }, Value(n as u64));
+            pool.insert(entry, fragment);
+            n += 1;
+        });
     }
 }
@@ -1,11 +1,30 @@
 use crate::{
-    fragment::{Logs, Pool},
-    intercom::TransactionMsg,
+    blockcfg::{BlockDate, HeaderContentEvalContext, Ledger, LedgerParameters},
+    blockchain::{Rollback, Tip},
+    fragment::{
+        rate_limit::{RateLimit, RateLimiter},
+        validation::{validate, ValidationError},
+        Fragment, FragmentId, InsertError, Logs, Origin, Pool, PoolFile, PoolLimits,
+    },
+    intercom::{Error, NetworkMsg, PropagateMsg, TransactionMsg},
     rest::v0::node::stats::StatsCounter,
-    utils::{async_msg::MessageQueue, task::TokioServiceInfo},
+    settings::start::Mempool,
+    start_up::NodeStorage,
+    utils::{
+        async_msg::{MessageBox, MessageQueue},
+        task::TokioServiceInfo,
+    },
 };
+use chain_core::property::{ChainLength as _, HasMessages as _, Message as _};
+use chain_storage::error as storage;
+use futures::sync::mpsc;
+use network_core::server::content::ProposeTransactionsResponse;
 use slog::Logger;
-use std::time::Duration;
+use std::{
+    collections::HashSet,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
 use tokio::{
     prelude::{
         future::Either::{A, B},
@@ -18,19 +37,31 @@ pub struct Process {
     pool: Pool,
     logs: Logs,
     garbage_collection_interval: Duration,
+    pool_file: Option<PoolFile>,
+    rate_limit: RateLimit,
 }
 
 impl Process {
-    pub fn new(
-        pool_ttl: Duration,
-        logs_ttl: Duration,
-        garbage_collection_interval: Duration,
-    ) -> Self {
-        let logs = Logs::new(logs_ttl);
+    /// the pool is saved in the `pool_file`, if any, and restored from
+    /// it when the process starts
+    pub fn new(mempool_settings: &Mempool, pool_file: Option<PoolFile>, logger: Logger) -> Self {
+        let logs = Logs::new(mempool_settings.log_ttl, logger);
+        let pool_limits = PoolLimits {
+            max_entries: mempool_settings.max_entries,
+            max_size: mempool_settings.max_size,
+            max_entries_per_origin: mempool_settings.max_entries_per_origin,
+        };
         Process {
-            pool: Pool::new(pool_ttl, logs.clone()),
+            pool: Pool::new(
+                mempool_settings.fragment_ttl,
+                pool_limits,
+                mempool_settings.conflict_policy,
+                logs.clone(),
+            ),
             logs,
-            garbage_collection_interval,
+            garbage_collection_interval: mempool_settings.garbage_collection_interval,
+            pool_file,
+            rate_limit: mempool_settings.rate_limit,
         }
     }
 
@@ -45,57 +76,253 @@ impl Process {
         self,
         service_info: TokioServiceInfo,
         stats_counter: StatsCounter,
+        tip: Tip,
+        storage: Arc<RwLock<NodeStorage>>,
+        rollbacks: mpsc::UnboundedReceiver<Rollback>,
+        network_msgbox: MessageBox<NetworkMsg>,
         input: MessageQueue<TransactionMsg>,
     ) -> impl Future<Item = (), Error = ()> {
+        // the saved pool is restored before any new fragment is handled
+        let restored = self.restore_pool(&tip, service_info.logger().clone());
         service_info.spawn(self.start_pool_garbage_collector(service_info.logger().clone()));
+        if let Some(pool_file) = self.pool_file.clone() {
+            service_info.spawn(self.start_pool_saver(pool_file, service_info.logger().clone()));
+        }
+        service_info.spawn(self.start_rollback_handler(
+            tip.clone(),
+            storage,
+            rollbacks,
+            service_info.logger().clone(),
+        ));
 
+        let logger = service_info.logger().clone();
         let pool = self.pool.clone();
         let pool_copy = self.pool;
+        let mut rate_limiter = RateLimiter::new(self.rate_limit);
 
-        input.for_each(move |input| {
+        let handle_input = input.for_each(move |input| {
             match input {
                 TransactionMsg::ProposeTransaction(txids, reply) => {
                     let logs = pool.logs().clone();
 
-                    A(A(logs.exists(txids).and_then(|rep| {
-                        reply.reply_ok(rep);
+                    A(A(logs.exists(txids.clone()).and_then(|rep| {
+                        let items = txids.into_iter().zip(rep).collect();
+                        reply.reply_ok(ProposeTransactionsResponse::new(items));
                         future::ok(())
                     })))
                 }
-                TransactionMsg::SendTransaction(origin, txs) => {
+                TransactionMsg::SendTransaction(origin, txs, reply) => {
                     // TODO? stats_counter.add_tx_recv_cnt(txs.len());
 
+                    // the fragments of a sender exceeding its rate are
+                    // dropped before any processing, they are not even
+                    // logged as rejected
+                    if !rate_limiter.take(origin.sender(), txs.len(), Instant::now()) {
+                        debug!(logger, "fragments dropped, the sender exceeds its rate limit" ;
+                            "origin" => format!("{:?}", origin),
+                            "fragments" => txs.len(),
+                        );
+                        if let Some(reply) = reply {
+                            reply.reply_error(Error::resource_exhausted(
+                                "too many fragments sent, try again later",
+                            ));
+                        }
+                        return B(B(future::ok(())));
+                    }
+
                     // Note that we cannot use apply_block here, since we don't have a valid context to which to apply
                     // those blocks. one valid tx in a given context, could be invalid in another. for example
                     // fee calculations, existence utxo / account solvency.
 
-                    // FIXME/TODO check that the txs are valid within themselves with basic requirements (e.g. inputs >= outputs).
-                    // we also want to keep a basic capability to filter away repetitive queries or definitely discarded txid.
-
-                    // This interface only makes sense for messages coming from arbitrary users (like transaction, certificates),
-                    // for other message we don't want to receive them through this interface, and possibly
-                    // put them in another pool.
+                    // The fragments are checked against the ledger of the
+                    // current tip, with the pending fragments they depend
+                    // on applied first: the ones that cannot be applied are
+                    // rejected at once instead of waiting in the pool.
+                    // The fees are computed with the fee settings of the
+                    // current tip too, they may change with the next epoch.
+                    let ledger = tip.ledger().unwrap();
+                    let ledger_params = ledger.get_ledger_parameters();
+                    let metadata = next_block_metadata(&tip, &ledger);
 
-                    let mut pool_copy = pool_copy.clone();
+                    let pool_copy = pool_copy.clone();
                     let stats_counter = stats_counter.clone();
+                    let network_msgbox = network_msgbox.clone();
+                    let logger = logger.clone();
 
-                    A(B(stream::iter_ok(txs).for_each(move |tx| {
-                        let stats_counter = stats_counter.clone();
-                        pool_copy.insert(origin, tx).map(move |inserted| {
-                            if inserted {
-                                stats_counter.add_tx_recv_cnt(1)
-                            }
+                    A(B(stream::iter_ok::<_, ()>(txs)
+                        .fold(None, move |first_error: Option<InsertError>, tx| {
+                            let stats_counter = stats_counter.clone();
+                            let mut network_msgbox = network_msgbox.clone();
+                            let logger = logger.clone();
+                            let mut pool = pool_copy.clone();
+                            let ledger = ledger.clone();
+                            let ledger_params = ledger_params.clone();
+                            let metadata = metadata.clone();
+
+                            pool.pending_ancestors::<()>(&tx).and_then(move |ancestors| {
+                                let validation = validate_with_ancestors(
+                                    &ledger,
+                                    &ledger_params,
+                                    &metadata,
+                                    &ancestors,
+                                    &tx,
+                                );
+                                if let Err(error) = validation {
+                                    debug!(logger, "fragment rejected" ;
+                                        "fragment_id" => tx.id().to_string(),
+                                        "reason" => error.to_string(),
+                                    );
+                                    return A(
+                                        pool.reject(origin, tx, ledger_params, error.to_string())
+                                            .map(move |()| first_error),
+                                    );
+                                }
+
+                                // a fragment that cannot be added to the pool
+                                // does not prevent adding the next ones of the
+                                // batch, the first error is replied
+                                let fragment_id = tx.id();
+                                let propagated = tx.clone();
+                                B(pool.insert(origin, tx, ledger_params).then(move |result| {
+                                    let inserted = match result {
+                                        Ok(inserted) => inserted,
+                                        Err(error) => {
+                                            debug!(logger, "fragment not added to the pool" ;
+                                                "fragment_id" => fragment_id.to_string(),
+                                                "reason" => error.to_string(),
+                                            );
+                                            return Ok(first_error.or(Some(error)));
+                                        }
+                                    };
+                                    if !inserted {
+                                        return Ok(first_error);
+                                    }
+                                    stats_counter.add_tx_recv_cnt(1);
+
+                                    // the fragment is relayed to the peers but the
+                                    // one it was received from. It is only relayed
+                                    // the first time it is received: the copies
+                                    // received later are already in the logs.
+                                    let sender = match origin {
+                                        Origin::Network { node_id, .. } => Some(node_id),
+                                        Origin::Rest { .. }
+                                        | Origin::Block
+                                        | Origin::Unknown => None,
+                                    };
+                                    let msg = NetworkMsg::Propagate(PropagateMsg::Message(propagated, sender));
+                                    if let Err(err) = network_msgbox.try_send(msg) {
+                                        warn!(logger, "cannot propagate the fragment to the network" ; "reason" => err.to_string());
+                                    }
+                                    Ok(first_error)
+                                }))
+                            })
                         })
-                    })))
+                        .map(move |first_error| {
+                            if let Some(reply) = reply {
+                                reply.reply(match first_error {
+                                    None => Ok(()),
+                                    Some(error @ InsertError::DoubleSpend)
+                                    | Some(error @ InsertError::ReplacementFeeTooLow) => {
+                                        Err(Error::failed_precondition(error))
+                                    }
+                                    Some(error) => Err(Error::failed(error)),
+                                });
+                            }
+                        })))
                 }
-                TransactionMsg::GetTransactions(_txids, _handler) => {
-                    // this function is no yet implemented, this is not handled in the
-                    B(future::ok(unimplemented!()))
+                TransactionMsg::GetTransactions(txids, handler) => {
+                    B(A(pool.get(txids).map(move |fragments| {
+                        tokio::spawn(handler.forward(stream::iter_ok(fragments)));
+                    })))
                 }
             }
+        });
+
+        restored.and_then(move |()| handle_input)
+    }
+
+    /// put the fragments saved before the node was stopped back in the
+    /// pool, the ones that cannot be applied to the ledger of the
+    /// restored tip anymore are recorded as rejected
+    fn restore_pool(&self, tip: &Tip, logger: Logger) -> impl Future<Item = (), Error = ()> {
+        let saved = match self.pool_file {
+            None => return A(future::ok(())),
+            Some(ref pool_file) => match pool_file.load() {
+                Ok(Some(saved)) => saved,
+                Ok(None) => return A(future::ok(())),
+                Err(error) => {
+                    warn!(logger, "cannot load the saved fragment pool" ; "reason" => error.to_string());
+                    return A(future::ok(()));
+                }
+            },
+        };
+        info!(logger, "restoring the saved fragment pool" ;
+            "fragments" => saved.entries.len(),
+            "logs" => saved.logs.len(),
+        );
+
+        let (ledger_params, validations) =
+            validate_in_order(tip, saved.entries.iter().map(|entry| &entry.fragment));
+        let entries = saved.entries.into_iter().zip(validations).collect();
+        let mut pool = self.pool().clone();
+        B(pool.restore(entries, saved.logs, ledger_params))
+    }
+
+    /// put the fragments of the blocks rolled back by a switch of the
+    /// tip back in the pool
+    fn start_rollback_handler(
+        &self,
+        tip: Tip,
+        storage: Arc<RwLock<NodeStorage>>,
+        rollbacks: mpsc::UnboundedReceiver<Rollback>,
+        logger: Logger,
+    ) -> impl Future<Item = (), Error = ()> {
+        let mut pool = self.pool().clone();
+        rollbacks.for_each(move |rollback| {
+            let fragments = rolled_back_fragments(&storage.read().unwrap(), &rollback);
+            let (returned, committed) = match fragments {
+                Ok(fragments) => fragments,
+                Err(error) => {
+                    error!(logger, "cannot read the blocks of the rolled back branches" ; "reason" => error.to_string());
+                    return A(future::ok(()));
+                }
+            };
+            info!(logger, "returning the fragments of the rolled back blocks to the pool" ;
+                "rolled_back" => rollback.removed.len(),
+                "fragments" => returned.len(),
+            );
+
+            let (ledger_params, validations) = validate_in_order(&tip, returned.iter());
+            let validated = returned.into_iter().zip(validations).collect();
+            B(pool.roll_back(validated, committed, ledger_params))
         })
     }
 
+    /// save the pool periodically, so the fragments are not lost if the
+    /// node is not stopped cleanly
+    fn start_pool_saver(
+        &self,
+        pool_file: PoolFile,
+        logger: Logger,
+    ) -> impl Future<Item = (), Error = ()> {
+        let pool = self.pool().clone();
+        let error_logger = logger.clone();
+        Interval::new_interval(self.garbage_collection_interval)
+            .map_err(move |error| {
+                error!(error_logger, "Cannot run the fragment pool saving" ; "reason" => error.to_string());
+            })
+            .for_each(move |_instant| {
+                debug!(logger, "save the fragment pool");
+                let logger = logger.clone();
+                pool.save(pool_file.clone()).then(move |result| {
+                    if let Err(error) = result {
+                        warn!(logger, "cannot save the fragment pool" ; "reason" => error.to_string());
+                    }
+                    Ok(())
+                })
+            })
+    }
+
     fn start_pool_garbage_collector(&self, logger: Logger) -> impl Future<Item = (), Error = ()> {
         let mut pool = self.pool().clone();
         let garbage_collection_interval = self.garbage_collection_interval;
@@ -110,3 +337,139 @@ impl Process {
             })
     }
 }
+
+/// the fragments are not in a block yet, evaluate them as if they were
+/// in the block following the tip
+fn next_block_metadata(tip: &Tip, ledger: &Ledger) -> HeaderContentEvalContext {
+    HeaderContentEvalContext {
+        block_date: ledger.date(),
+        chain_length: tip.chain_length().unwrap().next(),
+        nonce: None,
+    }
+}
+
+/// check the fragments in order against the ledger of the tip, a
+/// fragment may spend the outputs of a fragment before it
+fn validate_in_order<'a, I>(
+    tip: &Tip,
+    fragments: I,
+) -> (LedgerParameters, Vec<Result<(), ValidationError>>)
+where
+    I: Iterator<Item = &'a Fragment>,
+{
+    let mut ledger = tip.ledger().unwrap();
+    let ledger_params = ledger.get_ledger_parameters();
+    let metadata = next_block_metadata(tip, &ledger);
+    let validations = fragments
+        .map(|fragment| {
+            validate(&ledger, &ledger_params, &metadata, fragment)
+                .map(|new_ledger| ledger = new_ledger)
+        })
+        .collect();
+    (ledger_params, validations)
+}
+
+/// check the fragment against the given ledger with the pending
+/// fragments it depends on applied first, in order. The ancestors that
+/// cannot be applied anymore are skipped: the fragment is rejected if it
+/// needs them.
+fn validate_with_ancestors(
+    ledger: &Ledger,
+    ledger_params: &LedgerParameters,
+    metadata: &HeaderContentEvalContext,
+    ancestors: &[Fragment],
+    fragment: &Fragment,
+) -> Result<Ledger, ValidationError> {
+    let mut ledger = ledger.clone();
+    for ancestor in ancestors {
+        if let Ok(new_ledger) = validate(&ledger, ledger_params, metadata, ancestor) {
+            ledger = new_ledger;
+        }
+    }
+    validate(&ledger, ledger_params, metadata, fragment)
+}
+
+/// the fragments of the blocks removed from the main chain which are
+/// not in the blocks added to it, and the fragments of the added blocks
+/// with the date of their block
+fn rolled_back_fragments(
+    storage: &NodeStorage,
+    rollback: &Rollback,
+) -> Result<(Vec<Fragment>, Vec<(FragmentId, BlockDate)>), storage::Error> {
+    let mut committed = Vec::new();
+    for hash in rollback.added.iter() {
+        let (block, _) = storage.get_block(hash)?;
+        let date = block.header.date();
+        committed.extend(block.messages().map(|fragment| (fragment.id(), date)));
+    }
+
+    let committed_ids: HashSet<FragmentId> = committed.iter().map(|(id, _)| id.clone()).collect();
+    let mut returned = Vec::new();
+    for hash in rollback.removed.iter() {
+        let (block, _) = storage.get_block(hash)?;
+        returned.extend(
+            block
+                .messages()
+                .filter(|fragment| !committed_ids.contains(&fragment.id()))
+                .cloned(),
+        );
+    }
+
+    Ok((returned, committed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fragment::testing::{outputs, TestLedger};
+
+    fn validate_after(
+        test_ledger: &TestLedger,
+        ancestors: &[Fragment],
+        fragment: &Fragment,
+    ) -> Result<Ledger, ValidationError> {
+        validate_with_ancestors(
+            &test_ledger.ledger,
+            &test_ledger.params,
+            &test_ledger.metadata,
+            ancestors,
+            fragment,
+        )
+    }
+
+    #[test]
+    fn pending_ancestors_are_applied_first() {
+        let (test_ledger, utxos) = TestLedger::new(1, 1_000);
+        let parent = test_ledger.spend(&utxos, 1);
+        let child = test_ledger.spend(&outputs(&parent), 1);
+        let grandchild = test_ledger.spend(&outputs(&child), 1);
+
+        assert!(validate_after(&test_ledger, &[], &child).is_err());
+        assert!(validate_after(&test_ledger, &[parent.clone()], &child).is_ok());
+        assert!(validate_after(&test_ledger, &[parent, child], &grandchild).is_ok());
+    }
+
+    #[test]
+    fn ancestors_not_applying_anymore_are_skipped() {
+        let (mut test_ledger, utxos) = TestLedger::new(2, 1_000);
+        let parent = test_ledger.spend(&utxos[0..1], 1);
+        let child = test_ledger.spend(&outputs(&parent), 1);
+        let unrelated = test_ledger.spend(&utxos[1..2], 1);
+        // the parent was added to the block of the tip
+        test_ledger.apply(&parent);
+
+        assert!(validate_after(&test_ledger, &[parent.clone()], &child).is_ok());
+        assert!(validate_after(&test_ledger, &[parent, child], &unrelated).is_ok());
+    }
+
+    #[test]
+    fn fragment_conflicting_with_an_ancestor_is_rejected() {
+        let (test_ledger, utxos) = TestLedger::new(1, 1_000);
+        let parent = test_ledger.spend(&utxos, 2);
+        let child = test_ledger.spend(&outputs(&parent)[0..1], 1);
+        let double_spend = test_ledger.spend(&outputs(&parent), 1);
+
+        assert!(validate_after(&test_ledger, &[parent.clone()], &double_spend).is_ok());
+        assert!(validate_after(&test_ledger, &[parent, child], &double_spend).is_err());
+    }
+}
@@ -0,0 +1,163 @@
+use crate::fragment::Sender;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// the buckets full again are removed at this interval, a full bucket
+/// behaves as a missing one
+const PURGE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// the rate at which a sender may submit fragments
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct RateLimit {
+    /// the number of fragments a sender may submit every second, on
+    /// average
+    pub fragments_per_second: u32,
+    /// the number of fragments a sender may submit at once, after not
+    /// submitting any for a while
+    pub burst: u32,
+}
+
+/// token bucket rate limiting of the fragments of each sender: each
+/// fragment takes a token from the bucket of its sender, the buckets
+/// are refilled at the given rate up to the burst size.
+///
+/// The REST clients whose address is unknown are not limited: they
+/// would all share the same bucket, one of them could exhaust it for
+/// the others.
+pub struct RateLimiter {
+    limit: RateLimit,
+    buckets: HashMap<Sender, Bucket>,
+    last_purge: Instant,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit) -> Self {
+        RateLimiter {
+            limit,
+            buckets: HashMap::new(),
+            last_purge: Instant::now(),
+        }
+    }
+
+    /// take `count` tokens from the bucket of the sender, returns `false`
+    /// without taking any if there are not enough of them.
+    ///
+    /// A batch larger than the burst size costs the burst size, so it
+    /// is not refused forever: it takes all the tokens of a full bucket.
+    pub fn take(&mut self, sender: Sender, count: usize, now: Instant) -> bool {
+        if let Sender::Client(None) = sender {
+            return true;
+        }
+        if now.duration_since(self.last_purge) >= PURGE_INTERVAL {
+            self.purge(now);
+        }
+
+        let limit = self.limit;
+        let bucket = self.buckets.entry(sender).or_insert(Bucket {
+            tokens: limit.burst as f64,
+            updated_at: now,
+        });
+        bucket.refill(&limit, now);
+
+        let cost = count.min(limit.burst as usize) as f64;
+        if bucket.tokens < cost {
+            return false;
+        }
+        bucket.tokens -= cost;
+        true
+    }
+
+    fn purge(&mut self, now: Instant) {
+        let limit = self.limit;
+        self.buckets.retain(|_, bucket| {
+            bucket.refill(&limit, now);
+            bucket.tokens < limit.burst as f64
+        });
+        self.last_purge = now;
+    }
+}
+
+impl Bucket {
+    fn refill(&mut self, limit: &RateLimit, now: Instant) {
+        let elapsed = now.duration_since(self.updated_at);
+        let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+        let tokens = self.tokens + elapsed * limit.fragments_per_second as f64;
+        self.tokens = tokens.min(limit.burst as f64);
+        self.updated_at = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::IpAddr;
+
+    fn limiter() -> RateLimiter {
+        RateLimiter::new(RateLimit {
+            fragments_per_second: 2,
+            burst: 4,
+        })
+    }
+
+    fn client() -> Sender {
+        Sender::Client(Some(IpAddr::from([127, 0, 0, 1])))
+    }
+
+    #[test]
+    fn burst_then_refill() {
+        let mut limiter = limiter();
+        let sender = client();
+        let start = Instant::now();
+
+        assert!(limiter.take(sender, 3, start));
+        assert!(limiter.take(sender, 1, start));
+        assert!(!limiter.take(sender, 1, start));
+
+        let later = start + Duration::from_millis(500);
+        assert!(limiter.take(sender, 1, later));
+        assert!(!limiter.take(sender, 1, later));
+    }
+
+    #[test]
+    fn senders_have_their_own_bucket() {
+        let mut limiter = limiter();
+        let now = Instant::now();
+
+        assert!(limiter.take(client(), 4, now));
+        assert!(!limiter.take(client(), 1, now));
+        assert!(limiter.take(Sender::Block, 4, now));
+    }
+
+    #[test]
+    fn batch_larger_than_the_burst_takes_the_full_bucket() {
+        let mut limiter = limiter();
+        let now = Instant::now();
+
+        assert!(limiter.take(Sender::Block, 10, now));
+        assert!(!limiter.take(Sender::Block, 1, now));
+
+        // the bucket must be full again
+        let later = now + Duration::from_millis(1500);
+        assert!(!limiter.take(Sender::Block, 10, later));
+        let full = now + Duration::from_secs(2);
+        assert!(limiter.take(Sender::Block, 10, full));
+    }
+
+    #[test]
+    fn clients_without_address_are_not_limited() {
+        let mut limiter = limiter();
+        let now = Instant::now();
+
+        for _ in 0..10 {
+            assert!(limiter.take(Sender::Client(None), 4, now));
+        }
+        assert!(limiter.buckets.is_empty());
+    }
+}
@@ -1,11 +1,24 @@
 use crate::{
-    blockcfg::{BlockBuilder, HeaderContentEvalContext, Ledger, LedgerParameters},
+    blockcfg::{BlockBuilder, HeaderContentEvalContext, Ledger, LedgerParameters, Value},
     fragment::{FragmentId, Status},
 };
 
 use super::logs::internal::Logs;
 use super::pool::internal::Pool;
 
+use std::collections::HashSet;
+
+/// the algorithms available to select the fragments of the blocks
+/// created by the node
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SelectionAlgorithm {
+    /// see [`OldestFirst`](./struct.OldestFirst.html)
+    OldestFirst,
+    /// see [`FeeRate`](./struct.FeeRate.html)
+    FeeRate,
+}
+
 pub enum SelectionOutput {
     Commit { fragment_id: FragmentId },
     RequestSmallerFee,
@@ -55,19 +68,19 @@ impl FragmentSelectionAlgorithm for OldestFirst {
     ) {
         let mut total = 0usize;
 
-        while let Some(id) = pool.entries_by_time.pop_front() {
-            if total >= self.max_per_block {
-                break;
-            }
-
-            let fragment = pool.remove(&id).unwrap();
+        while total < self.max_per_block {
+            let id = match pool.oldest() {
+                Some(id) => id,
+                None => break,
+            };
+            let (entry, fragment) = pool.remove(&id).unwrap();
 
             match ledger.apply_fragment(ledger_params, &fragment, metadata) {
                 Ok(_) => {
                     self.builder.message(fragment);
 
-                    logs.modify(
-                        &id,
+                    logs.modify_entry(
+                        &entry,
                         Status::InABlock {
                             date: metadata.block_date,
                         },
@@ -75,8 +88,8 @@ impl FragmentSelectionAlgorithm for OldestFirst {
 
                     total += 1;
                 }
-                Err(error) => logs.modify(
-                    &id,
+                Err(error) => logs.modify_entry(
+                    &entry,
                     Status::Rejected {
                         reason: error.to_string(),
                     },
@@ -85,3 +98,317 @@ impl FragmentSelectionAlgorithm for OldestFirst {
         }
     }
 }
+
+/// select the fragments paying the highest fee per byte first.
+///
+/// A fragment spending the outputs of other pending fragments cannot be
+/// added without them: the fee rate of its package (the fragment and its
+/// pending ancestors) is considered and the whole package is added to
+/// the block, ancestors first.
+pub struct FeeRate {
+    builder: BlockBuilder,
+    max_per_block: usize,
+    max_block_size: usize,
+    total: usize,
+    total_size: usize,
+    total_fee: Value,
+}
+
+impl FeeRate {
+    pub fn new(max_per_block: usize, max_block_size: usize) -> Self {
+        FeeRate {
+            builder: BlockBuilder::new(),
+            max_per_block,
+            max_block_size,
+            total: 0,
+            total_size: 0,
+            total_fee: Value::zero(),
+        }
+    }
+
+    /// add the package of the given fragment to the block, the fragments
+    /// are removed from the pool and their logs are updated.
+    ///
+    /// The package is left in the pool if it does not fit in the block.
+    fn select_package(
+        &mut self,
+        ledger: &mut Ledger,
+        ledger_params: &LedgerParameters,
+        metadata: &HeaderContentEvalContext,
+        logs: &mut Logs,
+        pool: &mut Pool,
+        fragment_id: &FragmentId,
+    ) -> SelectionOutput {
+        let (package_fee, package_size) = match pool.entry(fragment_id) {
+            Some(entry) => (entry.with_ancestors_fee(), entry.with_ancestors_size()),
+            None => {
+                return SelectionOutput::Reject {
+                    reason: "fragment not in the pool".to_owned(),
+                }
+            }
+        };
+
+        let mut package: Vec<FragmentId> = pool.ancestors(fragment_id).into_iter().collect();
+        package.push(fragment_id.clone());
+
+        if self.total + package.len() > self.max_per_block
+            || self.total_size + package_size > self.max_block_size
+        {
+            return SelectionOutput::RequestSmallerSize;
+        }
+        if package_fee.and_then(|fee| self.total_fee + fee).is_err() {
+            return SelectionOutput::RequestSmallerFee;
+        }
+
+        // an ancestor has fewer ancestors than any of its descendants
+        package.sort_by_key(|id| pool.ancestors(id).len());
+
+        for id in package {
+            let (entry, fragment) = match pool.remove(&id) {
+                Some(removed) => removed,
+                None => continue,
+            };
+
+            match ledger.apply_fragment(ledger_params, &fragment, metadata) {
+                Ok(new_ledger) => {
+                    *ledger = new_ledger;
+                    self.builder.message(fragment);
+
+                    logs.modify_entry(
+                        &entry,
+                        Status::InABlock {
+                            date: metadata.block_date,
+                        },
+                    );
+
+                    self.total += 1;
+                    self.total_size += entry.fragment_size();
+                    // cannot overflow, the fee of the package was checked
+                    if let Ok(total_fee) = self.total_fee + *entry.fragment_fee() {
+                        self.total_fee = total_fee;
+                    }
+                }
+                Err(error) => {
+                    // the descendants still in the pool are rejected
+                    // when they are selected in turn
+                    let reason = error.to_string();
+                    logs.modify_entry(
+                        &entry,
+                        Status::Rejected {
+                            reason: reason.clone(),
+                        },
+                    );
+                    return SelectionOutput::Reject { reason };
+                }
+            }
+        }
+
+        SelectionOutput::Commit {
+            fragment_id: fragment_id.clone(),
+        }
+    }
+}
+
+impl FragmentSelectionAlgorithm for FeeRate {
+    fn finalize(self) -> BlockBuilder {
+        self.builder
+    }
+
+    fn select(
+        &mut self,
+        ledger: &Ledger,
+        ledger_params: &LedgerParameters,
+        metadata: &HeaderContentEvalContext,
+        logs: &mut Logs,
+        pool: &mut Pool,
+    ) {
+        // the fragments are applied in turn, a package may spend the
+        // outputs of a package added before it
+        let mut ledger = ledger.clone();
+        // the packages that do not fit in the block
+        let mut skipped = HashSet::new();
+
+        while self.total < self.max_per_block {
+            let fragment_id = match pool.best_package(&skipped) {
+                Some(id) => id,
+                None => break,
+            };
+
+            match self.select_package(
+                &mut ledger,
+                ledger_params,
+                metadata,
+                logs,
+                pool,
+                &fragment_id,
+            ) {
+                SelectionOutput::Commit { .. } => {}
+                // a smaller package may still fit
+                SelectionOutput::RequestSmallerFee | SelectionOutput::RequestSmallerSize => {
+                    skipped.insert(fragment_id);
+                }
+                // the rejected fragment is not in the pool anymore
+                SelectionOutput::Reject { .. } => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fragment::{
+        testing::{outputs, run, TestLedger},
+        ConflictPolicy, Fragment, Origin, PoolEntry, PoolLimits,
+    };
+    use chain_core::property::{HasMessages as _, Message as _};
+    use slog::{Discard, Logger};
+    use std::time::Duration;
+
+    const LIMITS: PoolLimits = PoolLimits {
+        max_entries: 100,
+        max_size: usize::max_value(),
+        max_entries_per_origin: usize::max_value(),
+    };
+
+    struct Selection {
+        test_ledger: TestLedger,
+        pool: Pool,
+        logs: Logs,
+    }
+
+    impl Selection {
+        fn new(test_ledger: TestLedger) -> Self {
+            Selection {
+                test_ledger,
+                pool: Pool::new(Duration::from_secs(60), LIMITS, ConflictPolicy::Reject),
+                logs: Logs::new(Duration::from_secs(60), Logger::root(Discard, o!())),
+            }
+        }
+
+        /// insert the fragment paying the given fee, whatever the fees
+        /// of the ledger are
+        fn insert(&mut self, fragment: &Fragment, fee: u64) {
+            let entry = PoolEntry::with_fee(fragment, Origin::Rest { addr: None }, Value(fee));
+            self.pool.insert(entry, fragment.clone()).unwrap();
+        }
+
+        /// the fragments of the block, in order
+        fn select(&mut self, max_per_block: usize) -> Vec<FragmentId> {
+            let mut algorithm = FeeRate::new(max_per_block, usize::max_value());
+            algorithm.select(
+                &self.test_ledger.ledger,
+                &self.test_ledger.params,
+                &self.test_ledger.metadata,
+                &mut self.logs,
+                &mut self.pool,
+            );
+            algorithm
+                .finalize()
+                .make_genesis_block()
+                .messages()
+                .map(|fragment| fragment.id())
+                .collect()
+        }
+
+        fn assert_in_a_block(&self, fragment: &Fragment) {
+            let log = self.logs.get(&fragment.id()).expect("no log");
+            assert_eq!(
+                log.status,
+                Status::InABlock {
+                    date: self.test_ledger.metadata.block_date
+                }
+            );
+            assert!(!self.pool.contains(&fragment.id()));
+        }
+    }
+
+    #[test]
+    fn packages_are_selected_by_ancestors_fee_rate() {
+        run(|| {
+            let (test_ledger, utxos) = TestLedger::new(3, 1_000);
+            let low = test_ledger.spend(&utxos[0..1], 1);
+            let middle = test_ledger.spend(&utxos[1..2], 1);
+            // pays little on its own but its child makes it worth it
+            let parent = test_ledger.spend(&utxos[2..3], 1);
+            let child = test_ledger.spend(&outputs(&parent), 1);
+            let mut selection = Selection::new(test_ledger);
+            selection.insert(&low, 10);
+            selection.insert(&middle, 1_000);
+            selection.insert(&parent, 1);
+            selection.insert(&child, 10_000);
+
+            let block = selection.select(10);
+
+            assert_eq!(block, vec![parent.id(), child.id(), middle.id(), low.id()]);
+            for fragment in [&parent, &child, &middle, &low].iter() {
+                selection.assert_in_a_block(fragment);
+            }
+        });
+    }
+
+    #[test]
+    fn parents_come_before_their_children() {
+        run(|| {
+            let (test_ledger, utxos) = TestLedger::new(1, 1_000);
+            let parent = test_ledger.spend(&utxos, 2);
+            let child = test_ledger.spend(&outputs(&parent)[0..1], 1);
+            let grandchild = test_ledger.spend(&outputs(&child), 1);
+            let sibling = test_ledger.spend(&outputs(&parent)[1..2], 1);
+            let mut selection = Selection::new(test_ledger);
+            // the descendants are received first and pay more
+            selection.insert(&grandchild, 1_000);
+            selection.insert(&sibling, 100);
+            selection.insert(&child, 10);
+            selection.insert(&parent, 1);
+
+            let block = selection.select(10);
+
+            assert_eq!(
+                block,
+                vec![parent.id(), child.id(), grandchild.id(), sibling.id()]
+            );
+        });
+    }
+
+    #[test]
+    fn max_number_of_transactions_per_block_is_respected() {
+        run(|| {
+            let (test_ledger, utxos) = TestLedger::new(3, 1_000);
+            let fragments: Vec<Fragment> = utxos
+                .chunks(1)
+                .map(|utxo| test_ledger.spend(utxo, 1))
+                .collect();
+            let mut selection = Selection::new(test_ledger);
+            for (n, fragment) in fragments.iter().enumerate() {
+                selection.insert(fragment, n as u64 + 1);
+            }
+
+            let block = selection.select(2);
+
+            assert_eq!(block, vec![fragments[2].id(), fragments[1].id()]);
+            assert!(selection.pool.contains(&fragments[0].id()));
+            assert!(selection.logs.get(&fragments[0].id()).is_none());
+        });
+    }
+
+    #[test]
+    fn package_larger_than_the_space_left_is_skipped() {
+        run(|| {
+            let (test_ledger, utxos) = TestLedger::new(2, 1_000);
+            let parent = test_ledger.spend(&utxos[0..1], 1);
+            let child = test_ledger.spend(&outputs(&parent), 1);
+            let standalone = test_ledger.spend(&utxos[1..2], 1);
+            let mut selection = Selection::new(test_ledger);
+            selection.insert(&parent, 1);
+            selection.insert(&child, 10_000);
+            selection.insert(&standalone, 10);
+
+            let block = selection.select(1);
+
+            assert_eq!(block, vec![standalone.id()]);
+            assert!(selection.pool.contains(&parent.id()));
+            assert!(selection.pool.contains(&child.id()));
+        });
+    }
+}
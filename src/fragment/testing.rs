@@ -0,0 +1,188 @@
+//! a ledger with initial funds and the transactions spending them, for
+//! the tests of the fragment pool
+
+use crate::{
+    blockcfg::{
+        BlockBuilder, HeaderContentEvalContext, HeaderHash, Ledger, LedgerParameters, Message,
+        Value,
+    },
+    fragment::Fragment,
+};
+use chain_addr::{Address, Discrimination, Kind};
+use chain_core::property::Block as _;
+use chain_crypto::{Ed25519, Ed25519Extended, KeyPair, PublicKey, SecretKey};
+use chain_impl_mockchain::{
+    block::ConsensusVersion,
+    config::{Block0Date, ConfigParam},
+    leadership::bft::LeaderId,
+    message::ConfigParams,
+    milli::Milli,
+    transaction::{
+        AuthenticatedTransaction, Input, NoExtra, Output, Transaction, TransactionId, Witness,
+    },
+};
+use rand::SeedableRng;
+use rand_chacha::ChaChaRng;
+use tokio::{prelude::*, runtime::current_thread::Runtime};
+
+/// an output of a transaction the test key can spend
+#[derive(Clone)]
+pub struct Utxo {
+    pub transaction_id: TransactionId,
+    pub index: u8,
+    pub value: Value,
+}
+
+pub struct TestLedger {
+    pub ledger: Ledger,
+    pub params: LedgerParameters,
+    pub metadata: HeaderContentEvalContext,
+    block0_hash: HeaderHash,
+    secret_key: SecretKey<Ed25519Extended>,
+    address: Address,
+}
+
+impl TestLedger {
+    /// a ledger whose block0 gives `funds` outputs of the given value to
+    /// the test key, returned with the ledger
+    pub fn new(funds: usize, value: u64) -> (Self, Vec<Utxo>) {
+        let leader: KeyPair<Ed25519Extended> =
+            KeyPair::generate(&mut ChaChaRng::from_seed([1; 32]));
+        let secret_key: SecretKey<Ed25519Extended> =
+            SecretKey::generate(&mut ChaChaRng::from_seed([0; 32]));
+        let public_key: PublicKey<Ed25519> = secret_key.to_public();
+        let address = Address(Discrimination::Test, Kind::Single(public_key));
+
+        let mut params = ConfigParams::new();
+        params.push(ConfigParam::Block0Date(Block0Date(0)));
+        params.push(ConfigParam::Discrimination(Discrimination::Test));
+        params.push(ConfigParam::ConsensusVersion(ConsensusVersion::Bft));
+        params.push(ConfigParam::SlotDuration(10));
+        params.push(ConfigParam::SlotsPerEpoch(10));
+        params.push(ConfigParam::EpochStabilityDepth(10));
+        params.push(ConfigParam::AddBftLeader(LeaderId::from(
+            leader.public_key().clone(),
+        )));
+        params.push(ConfigParam::ConsensusGenesisPraosActiveSlotsCoeff(
+            Milli::from_millis(500),
+        ));
+        params.push(ConfigParam::KESUpdateSpeed(3600));
+
+        let initial_funds = Message::Transaction(AuthenticatedTransaction {
+            transaction: Transaction {
+                inputs: Vec::new(),
+                outputs: (0..funds)
+                    .map(|_| Output {
+                        address: address.clone(),
+                        value: Value(value),
+                    })
+                    .collect(),
+                extra: NoExtra,
+            },
+            witnesses: Vec::new(),
+        });
+        let utxos = outputs(&initial_funds);
+
+        let block0 = BlockBuilder::new().make_genesis_block();
+        let ledger = Ledger::new(block0.id(), &[Message::Initial(params), initial_funds]).unwrap();
+        let params = ledger.get_ledger_parameters();
+        let metadata = HeaderContentEvalContext {
+            block_date: ledger.date(),
+            chain_length: block0.chain_length().next(),
+            nonce: None,
+        };
+        let test_ledger = TestLedger {
+            ledger,
+            params,
+            metadata,
+            block0_hash: block0.id(),
+            secret_key,
+            address,
+        };
+        (test_ledger, utxos)
+    }
+
+    /// apply the fragment to the ledger, as if it was in a block
+    pub fn apply(&mut self, fragment: &Fragment) {
+        self.ledger = self
+            .ledger
+            .apply_fragment(&self.params, fragment, &self.metadata)
+            .unwrap();
+    }
+
+    /// a transaction spending the given outputs and splitting their
+    /// value between `outputs` outputs, the remainder goes to the last
+    /// one. The fees of the ledger are zero, the transaction is balanced.
+    pub fn spend(&self, utxos: &[Utxo], outputs: u64) -> Fragment {
+        let total: u64 = utxos.iter().map(|utxo| utxo.value.0).sum();
+        let transaction = Transaction {
+            inputs: utxos
+                .iter()
+                .map(|utxo| {
+                    let mut input_ptr = [0u8; 32];
+                    input_ptr.clone_from_slice(utxo.transaction_id.as_ref());
+                    Input {
+                        index_or_account: utxo.index,
+                        value: utxo.value,
+                        input_ptr,
+                    }
+                })
+                .collect(),
+            outputs: (0..outputs)
+                .map(|n| {
+                    let value = if n == outputs - 1 {
+                        total - total / outputs * (outputs - 1)
+                    } else {
+                        total / outputs
+                    };
+                    Output {
+                        address: self.address.clone(),
+                        value: Value(value),
+                    }
+                })
+                .collect(),
+            extra: NoExtra,
+        };
+        let transaction_id = transaction.hash();
+        let witnesses = utxos
+            .iter()
+            .map(|_| Witness::new_utxo(&self.block0_hash, &transaction_id, &self.secret_key))
+            .collect();
+        Message::Transaction(AuthenticatedTransaction {
+            transaction,
+            witnesses,
+        })
+    }
+}
+
+/// the outputs of the transaction carried by the fragment
+pub fn outputs(fragment: &Fragment) -> Vec<Utxo> {
+    match fragment {
+        Message::Transaction(tx) => {
+            let transaction_id = tx.transaction.hash();
+            tx.transaction
+                .outputs
+                .iter()
+                .enumerate()
+                .map(|(index, output)| Utxo {
+                    transaction_id: transaction_id.clone(),
+                    index: index as u8,
+                    value: output.value,
+                })
+                .collect()
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// the pool and the logs register the expiration of their entries with
+/// the timer of the runtime
+pub fn run<F: FnOnce()>(f: F) {
+    Runtime::new()
+        .unwrap()
+        .block_on(future::lazy(|| {
+            f();
+            Ok::<(), ()>(())
+        }))
+        .unwrap();
+}
@@ -0,0 +1,247 @@
+use crate::{
+    blockcfg::{HeaderContentEvalContext, Ledger, LedgerParameters, Message, Value, ValueError},
+    fragment::Fragment,
+};
+use chain_addr::Address;
+use chain_impl_mockchain::{ledger, transaction::Transaction};
+
+custom_error! {pub ValidationError
+    NotAllowed = "this kind of fragment is only allowed in the genesis block",
+    NoInputs = "the transaction has no inputs",
+    FeeOverflow = "the fee of the transaction overflows",
+    InvalidValue { source: ValueError } = "the values of the transaction overflow: {source}",
+    NotBalanced { inputs: Value, outputs: Value, fee: Value } = "the transaction is not balanced: inputs {inputs}, outputs {outputs}, fee {fee}",
+    WitnessCount { inputs: usize, witnesses: usize } = "the transaction has {inputs} inputs but {witnesses} witnesses",
+    Ledger { source: ledger::Error } = "the fragment cannot be applied to the ledger of the tip: {source}",
+}
+
+/// check the fragment can be added to the pool: the checks not
+/// depending on the state of the blockchain are run first, then the
+/// fragment is applied to the given ledger (the one of the tip).
+///
+/// Returns the ledger with the fragment applied. A fragment accepted here
+/// may still be rejected when the block is created, the ledger changes
+/// in the meantime.
+pub fn validate(
+    ledger: &Ledger,
+    ledger_params: &LedgerParameters,
+    metadata: &HeaderContentEvalContext,
+    fragment: &Fragment,
+) -> Result<Ledger, ValidationError> {
+    use chain_impl_mockchain::fee::FeeAlgorithm as _;
+
+    match fragment {
+        Message::Initial(_) | Message::OldUtxoDeclaration(_) => {
+            return Err(ValidationError::NotAllowed)
+        }
+        Message::Transaction(tx) => validate_transaction(
+            &tx.transaction,
+            tx.witnesses.len(),
+            ledger_params.fees.calculate(&tx.transaction),
+        )?,
+        Message::Certificate(tx) => validate_transaction(
+            &tx.transaction,
+            tx.witnesses.len(),
+            ledger_params.fees.calculate(&tx.transaction),
+        )?,
+        _ => {}
+    }
+
+    let ledger = ledger.apply_fragment(ledger_params, fragment, metadata)?;
+    Ok(ledger)
+}
+
+fn validate_transaction<Extra>(
+    transaction: &Transaction<Address, Extra>,
+    witnesses: usize,
+    fee: Option<Value>,
+) -> Result<(), ValidationError> {
+    let inputs = transaction.inputs.len();
+    if inputs == 0 {
+        return Err(ValidationError::NoInputs);
+    }
+    if inputs != witnesses {
+        return Err(ValidationError::WitnessCount { inputs, witnesses });
+    }
+
+    let fee = fee.ok_or(ValidationError::FeeOverflow)?;
+    let total_input = transaction.total_input()?;
+    let total_output = transaction.total_output()?;
+    if total_input != (total_output + fee)? {
+        return Err(ValidationError::NotBalanced {
+            inputs: total_input,
+            outputs: total_output,
+            fee,
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fragment::testing::{outputs, TestLedger, Utxo};
+    use chain_impl_mockchain::{
+        message::ConfigParams,
+        transaction::{AuthenticatedTransaction, NoExtra},
+    };
+
+    fn transaction(fragment: &Fragment) -> Transaction<Address, NoExtra> {
+        match fragment {
+            Message::Transaction(tx) => tx.transaction.clone(),
+            _ => panic!("not a transaction"),
+        }
+    }
+
+    fn check(transaction: &Transaction<Address, NoExtra>) -> Result<(), ValidationError> {
+        validate_transaction(transaction, transaction.inputs.len(), Some(Value::zero()))
+    }
+
+    #[test]
+    fn genesis_fragments_are_not_allowed() {
+        let (test_ledger, _) = TestLedger::new(1, 1_000);
+        let fragment = Message::Initial(ConfigParams::new());
+
+        match validate(
+            &test_ledger.ledger,
+            &test_ledger.params,
+            &test_ledger.metadata,
+            &fragment,
+        ) {
+            Err(ValidationError::NotAllowed) => {}
+            _ => panic!("the genesis fragment is not rejected"),
+        }
+    }
+
+    #[test]
+    fn balanced_transaction_is_accepted() {
+        let (test_ledger, utxos) = TestLedger::new(1, 1_000);
+        let fragment = test_ledger.spend(&utxos, 2);
+
+        assert!(check(&transaction(&fragment)).is_ok());
+        assert!(validate(
+            &test_ledger.ledger,
+            &test_ledger.params,
+            &test_ledger.metadata,
+            &fragment,
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn transaction_without_inputs_is_rejected() {
+        let (test_ledger, utxos) = TestLedger::new(1, 1_000);
+        let mut transaction = transaction(&test_ledger.spend(&utxos, 1));
+        transaction.inputs.clear();
+
+        match check(&transaction) {
+            Err(ValidationError::NoInputs) => {}
+            _ => panic!("the transaction without inputs is not rejected"),
+        }
+    }
+
+    #[test]
+    fn witness_count_must_match_the_inputs() {
+        let (test_ledger, utxos) = TestLedger::new(2, 1_000);
+        let transaction = transaction(&test_ledger.spend(&utxos, 1));
+
+        match validate_transaction(&transaction, 1, Some(Value::zero())) {
+            Err(ValidationError::WitnessCount {
+                inputs: 2,
+                witnesses: 1,
+            }) => {}
+            _ => panic!("the missing witness is not detected"),
+        }
+    }
+
+    #[test]
+    fn overflowing_fee_is_rejected() {
+        let (test_ledger, utxos) = TestLedger::new(1, 1_000);
+        let transaction = transaction(&test_ledger.spend(&utxos, 1));
+
+        match validate_transaction(&transaction, 1, None) {
+            Err(ValidationError::FeeOverflow) => {}
+            _ => panic!("the overflowing fee is not rejected"),
+        }
+    }
+
+    #[test]
+    fn overflowing_values_are_rejected() {
+        let (test_ledger, utxos) = TestLedger::new(1, 1_000);
+        let mut transaction = transaction(&test_ledger.spend(&utxos, 2));
+        for output in transaction.outputs.iter_mut() {
+            output.value = Value(u64::max_value());
+        }
+
+        match check(&transaction) {
+            Err(ValidationError::InvalidValue { .. }) => {}
+            _ => panic!("the overflowing outputs are not rejected"),
+        }
+    }
+
+    #[test]
+    fn unbalanced_transaction_is_rejected() {
+        let (test_ledger, utxos) = TestLedger::new(1, 1_000);
+        let mut transaction = transaction(&test_ledger.spend(&utxos, 1));
+        transaction.outputs[0].value = Value(999);
+
+        match validate_transaction(&transaction, 1, Some(Value(2))) {
+            Err(ValidationError::NotBalanced {
+                inputs: Value(1_000),
+                outputs: Value(999),
+                fee: Value(2),
+            }) => {}
+            _ => panic!("the unbalanced transaction is not rejected"),
+        }
+        assert!(validate_transaction(&transaction, 1, Some(Value(1))).is_ok());
+    }
+
+    #[test]
+    fn unknown_inputs_are_rejected_by_the_ledger() {
+        let (test_ledger, utxos) = TestLedger::new(1, 1_000);
+        let parent = test_ledger.spend(&utxos, 1);
+        let child = test_ledger.spend(&outputs(&parent), 1);
+        let unknown = Utxo {
+            index: 1,
+            ..utxos[0].clone()
+        };
+        let fragments = vec![child, test_ledger.spend(&[unknown], 1)];
+
+        for fragment in fragments.iter() {
+            match validate(
+                &test_ledger.ledger,
+                &test_ledger.params,
+                &test_ledger.metadata,
+                fragment,
+            ) {
+                Err(ValidationError::Ledger { .. }) => {}
+                _ => panic!("the fragment spending an unknown output is not rejected"),
+            }
+        }
+    }
+
+    #[test]
+    fn stateless_checks_come_before_the_ledger() {
+        let (test_ledger, utxos) = TestLedger::new(1, 1_000);
+        let transaction = transaction(&test_ledger.spend(&utxos, 1));
+        // the witness is valid but missing from the fragment
+        let fragment = Message::Transaction(AuthenticatedTransaction {
+            transaction,
+            witnesses: Vec::new(),
+        });
+
+        match validate(
+            &test_ledger.ledger,
+            &test_ledger.params,
+            &test_ledger.metadata,
+            &fragment,
+        ) {
+            Err(ValidationError::WitnessCount {
+                inputs: 1,
+                witnesses: 0,
+            }) => {}
+            _ => panic!("the missing witness is not detected"),
+        }
+    }
+}
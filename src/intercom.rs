@@ -1,9 +1,10 @@
 use crate::blockcfg::{Block, Header, HeaderHash, Message, MessageId};
 use crate::fragment;
-use crate::network::p2p::topology::NodeId;
+use crate::network::p2p::{misbehaviour::Misbehaviour, topology::NodeId};
 use futures::prelude::*;
+use futures::stream;
 use futures::sync::{mpsc, oneshot};
-use network_core::error as core_error;
+use network_core::{error as core_error, server::content::ProposeTransactionsResponse};
 use slog::Logger;
 use std::{
     error,
@@ -29,6 +30,33 @@ impl Error {
         }
     }
 
+    pub fn not_found<S: Into<String>>(message: S) -> Self {
+        Error {
+            code: core_error::Code::NotFound,
+            cause: message.into().into(),
+        }
+    }
+
+    pub fn failed_precondition<T>(cause: T) -> Self
+    where
+        T: Into<Box<dyn error::Error + Send + Sync>>,
+    {
+        Error {
+            code: core_error::Code::FailedPrecondition,
+            cause: cause.into(),
+        }
+    }
+
+    pub fn resource_exhausted<T>(cause: T) -> Self
+    where
+        T: Into<Box<dyn error::Error + Send + Sync>>,
+    {
+        Error {
+            code: core_error::Code::ResourceExhausted,
+            cause: cause.into(),
+        }
+    }
+
     pub fn unimplemented<S: Into<String>>(message: S) -> Self {
         Error {
             code: core_error::Code::Unimplemented,
@@ -144,27 +172,48 @@ pub fn unary_reply<T, E>(logger: Logger) -> (ReplyHandle<T>, ReplyFuture<T, E>)
     (ReplyHandle { sender }, future)
 }
 
+/// number of items a reply stream buffers before the producer waits
+/// for the requester to consume them
+const REPLY_STREAM_BUFFER_LEN: usize = 8;
+
 #[derive(Debug)]
 pub struct ReplyStreamHandle<T> {
-    sender: mpsc::UnboundedSender<Result<T, Error>>,
+    sender: mpsc::Sender<Result<T, Error>>,
 }
 
 impl<T> ReplyStreamHandle<T> {
-    pub fn send(&mut self, item: T) {
-        self.sender.unbounded_send(Ok(item)).unwrap()
-    }
-
-    pub fn send_error(&mut self, error: Error) {
-        self.sender.unbounded_send(Err(error)).unwrap()
+    /// send the items of the stream to the requester, then close the reply.
+    ///
+    /// The items are pulled from the stream as the requester consumes
+    /// them, a slow requester slows down the production of the reply
+    /// instead of having it buffered in memory. An error of the stream
+    /// is sent to the requester and ends the reply.
+    pub fn forward<S>(self, stream: S) -> impl Future<Item = (), Error = ()>
+    where
+        S: Stream<Item = T, Error = Error>,
+    {
+        let mut failed = false;
+        stream
+            .then(|res| -> Result<_, mpsc::SendError<Result<T, Error>>> { Ok(res) })
+            .take_while(move |res| {
+                let proceed = !failed;
+                failed = res.is_err();
+                Ok(proceed)
+            })
+            .forward(self.sender)
+            .map(|_| ())
+            // the requester dropped the reply stream
+            .map_err(|_| ())
     }
 
-    pub fn close(self) {
-        self.sender.wait().close().unwrap();
+    /// reply with the given error only
+    pub fn reply_error(self, error: Error) -> impl Future<Item = (), Error = ()> {
+        self.forward(stream::once(Err(error)))
     }
 }
 
 pub struct ReplyStream<T, E> {
-    receiver: mpsc::UnboundedReceiver<Result<T, Error>>,
+    receiver: mpsc::Receiver<Result<T, Error>>,
     logger: Logger,
     _phantom_error: PhantomData<E>,
 }
@@ -191,7 +240,7 @@ where
 }
 
 pub fn stream_reply<T, E>(logger: Logger) -> (ReplyStreamHandle<T>, ReplyStream<T, E>) {
-    let (sender, receiver) = mpsc::unbounded();
+    let (sender, receiver) = mpsc::channel(REPLY_STREAM_BUFFER_LEN);
     let stream = ReplyStream {
         receiver,
         logger,
@@ -200,24 +249,18 @@ pub fn stream_reply<T, E>(logger: Logger) -> (ReplyStreamHandle<T>, ReplyStream<
     (ReplyStreamHandle { sender }, stream)
 }
 
-pub fn do_stream_reply<T, F>(mut handler: ReplyStreamHandle<T>, f: F)
-where
-    F: FnOnce(&mut ReplyStreamHandle<T>) -> Result<(), Error>,
-{
-    match f(&mut handler) {
-        Ok(()) => {}
-        Err(e) => {
-            handler.send_error(e);
-        }
-    };
-    handler.close();
-}
-
-/// ...
+/// Messages to the fragment task.
 #[derive(Debug)]
 pub enum TransactionMsg {
-    ProposeTransaction(Vec<MessageId>, ReplyHandle<Vec<bool>>),
-    SendTransaction(fragment::Origin, Vec<Message>),
+    /// ask which of the given fragments are already known to the node
+    ProposeTransaction(
+        Vec<MessageId>,
+        ReplyHandle<ProposeTransactionsResponse<MessageId>>,
+    ),
+    /// submit fragments to the pool. The reply, if any, fails when a
+    /// fragment could not be added because the pool is full; the
+    /// fragments rejected as invalid are reported in the logs.
+    SendTransaction(fragment::Origin, Vec<Message>, Option<ReplyHandle<()>>),
     GetTransactions(Vec<MessageId>, ReplyStreamHandle<Message>),
 }
 
@@ -230,6 +273,9 @@ pub enum ClientMsg {
     GetBlocks(Vec<HeaderHash>, ReplyStreamHandle<Block>),
     GetBlocksRange(HeaderHash, HeaderHash, ReplyStreamHandle<Block>),
     PullBlocksToTip(Vec<HeaderHash>, ReplyStreamHandle<Block>),
+    PullBlocks(Vec<HeaderHash>, HeaderHash, ReplyStreamHandle<Block>),
+    PullHeaders(Vec<HeaderHash>, HeaderHash, ReplyStreamHandle<Header>),
+    PullHeadersToTip(Vec<HeaderHash>, ReplyStreamHandle<Header>),
 }
 
 impl Debug for ClientMsg {
@@ -266,6 +312,23 @@ impl Debug for ClientMsg {
                 .field(from)
                 .field(&format_args!("_"))
                 .finish(),
+            ClientMsg::PullBlocks(from, to, _) => f
+                .debug_tuple("PullBlocks")
+                .field(from)
+                .field(to)
+                .field(&format_args!("_"))
+                .finish(),
+            ClientMsg::PullHeaders(from, to, _) => f
+                .debug_tuple("PullHeaders")
+                .field(from)
+                .field(to)
+                .field(&format_args!("_"))
+                .finish(),
+            ClientMsg::PullHeadersToTip(from, _) => f
+                .debug_tuple("PullHeadersToTip")
+                .field(from)
+                .field(&format_args!("_"))
+                .finish(),
         }
     }
 }
@@ -277,17 +340,28 @@ pub enum BlockMsg {
     LeadershipBlock(Block),
     /// Leadership process expect a new end of epoch
     LeadershipExpectEndOfEpoch,
-    /// An untrusted Block has been received from the network task
-    NetworkBlock(Block),
+    /// An untrusted Block has been received from the network task,
+    /// along with the peer that sent it if it is known
+    NetworkBlock(Block, Option<NodeId>),
     /// A untrusted block Header has been received from the network task
     AnnouncedBlock(Header, NodeId),
+    /// The headers of a chain ending at `to`, requested to a peer
+    /// with `NetworkMsg::PullHeaders`, in ascending order. The peer may
+    /// send only the beginning of the chain.
+    ChainHeaders {
+        headers: Vec<Header>,
+        to: HeaderHash,
+        node_id: NodeId,
+    },
 }
 
 /// Propagation requests for the network task.
 #[derive(Clone, Debug)]
 pub enum PropagateMsg {
     Block(Header),
-    Message(Message),
+    /// a fragment, with the peer it was received from if any: the
+    /// fragment is not sent back to it
+    Message(Message, Option<NodeId>),
 }
 
 /// Messages to the network task.
@@ -295,6 +369,16 @@ pub enum PropagateMsg {
 pub enum NetworkMsg {
     Propagate(PropagateMsg),
     GetBlocks(NodeId, Vec<HeaderHash>),
+    /// Pull the headers of the chain ending at `to` from a peer, starting
+    /// after the most recent of the `from` checkpoints the peer knows
+    /// about. If no peer is given, any connected peer is asked.
+    PullHeaders {
+        node_id: Option<NodeId>,
+        from: Vec<HeaderHash>,
+        to: HeaderHash,
+    },
+    /// The peer sent something invalid, increase its misbehaviour score
+    PeerMisbehaved(NodeId, Misbehaviour),
 }
 
 #[cfg(test)]
@@ -5,6 +5,7 @@ use crate::{
     intercom::BlockMsg,
     leadership::{EpochParameters, Leadership, Task, TaskParameters},
     secure::enclave::{Enclave, LeaderId},
+    settings::start::Mempool,
     utils::{async_msg::MessageBox, task::TokioServiceInfo},
 };
 use chain_core::property::BlockDate as _;
@@ -31,6 +32,7 @@ pub struct Process {
     service_info: TokioServiceInfo,
 
     fragment_pool: Pool,
+    mempool_settings: Mempool,
     blockchain_tip: Tip,
 
     block_message_box: MessageBox<BlockMsg>,
@@ -46,6 +48,7 @@ impl Process {
     pub fn new(
         service_info: TokioServiceInfo,
         fragment_pool: Pool,
+        mempool_settings: Mempool,
         blockchain_tip: Tip,
         block_message_box: MessageBox<BlockMsg>,
     ) -> Self {
@@ -56,6 +59,7 @@ impl Process {
         Process {
             service_info,
             fragment_pool,
+            mempool_settings,
             blockchain_tip,
             block_message_box,
             epoch_broadcaster,
@@ -105,6 +109,7 @@ impl Process {
         let blockchain_tip = self.blockchain_tip.clone();
         let logger = self.service_info.logger().clone();
         let fragment_pool = self.fragment_pool.clone();
+        let mempool_settings = self.mempool_settings.clone();
         let block_message = self.block_message_box.clone();
         let task = Task::new(
             logger,
@@ -112,6 +117,7 @@ impl Process {
             enclave,
             blockchain_tip,
             fragment_pool,
+            mempool_settings,
             epoch_receiver,
             block_message,
         );
@@ -4,10 +4,11 @@ use crate::{
         LeaderOutput, Ledger,
     },
     blockchain::Tip,
-    fragment::Pool,
+    fragment::{selection::SelectionAlgorithm, Pool},
     intercom::BlockMsg,
     leadership::{LeaderSchedule, Leadership},
     secure::enclave::{Enclave, LeaderId},
+    settings::start::Mempool,
     utils::async_msg::MessageBox,
 };
 use chain_core::property::ChainLength as _;
@@ -38,6 +39,7 @@ pub struct Task {
     blockchain_tip: Tip,
     epoch_receiver: watch::Receiver<Option<TaskParameters>>,
     fragment_pool: Pool,
+    mempool_settings: Mempool,
     block_message: MessageBox<BlockMsg>,
 }
 
@@ -49,6 +51,7 @@ impl Task {
         enclave: Enclave,
         blockchain_tip: Tip,
         fragment_pool: Pool,
+        mempool_settings: Mempool,
         epoch_receiver: watch::Receiver<Option<TaskParameters>>,
         block_message: MessageBox<BlockMsg>,
     ) -> Self {
@@ -66,6 +69,7 @@ impl Task {
             enclave: enclave,
             blockchain_tip,
             fragment_pool,
+            mempool_settings,
             epoch_receiver,
             block_message,
         }
@@ -78,6 +82,7 @@ impl Task {
         let enclave = self.enclave;
         let blockchain_tip = self.blockchain_tip;
         let fragment_pool = self.fragment_pool;
+        let mempool_settings = self.mempool_settings;
         let block_message = self.block_message;
 
         self.epoch_receiver
@@ -94,6 +99,7 @@ impl Task {
                     handle_logger.clone(),
                     blockchain_tip.clone(),
                     fragment_pool.clone(),
+                    mempool_settings.clone(),
                     task_parameters,
                 )
                 .map_err(|error| {
@@ -116,6 +122,7 @@ fn handle_leadership(
     logger: Logger,
     blockchain_tip: Tip,
     mut fragment_pool: Pool,
+    mempool_settings: Mempool,
     task_parameters: TaskParameters,
 ) -> impl Future<Item = (), Error = HandleLeadershipError> {
     let schedule = LeaderSchedule::new(logger.clone(), &leader_id, &enclave, &task_parameters);
@@ -132,6 +139,7 @@ fn handle_leadership(
 
             let block = prepare_block(
                 &mut fragment_pool,
+                &mempool_settings,
                 blockchain_tip.ledger().unwrap().clone(),
                 &task_parameters.leadership,
                 scheduled_event.leader_output.date,
@@ -151,15 +159,16 @@ fn handle_leadership(
 
 fn prepare_block(
     fragment_pool: &mut Pool,
+    mempool_settings: &Mempool,
     ledger: Ledger,
     leadership: &Leadership,
     date: BlockDate,
     chain_length: ChainLength,
     parent_id: HeaderHash,
 ) -> BlockBuilder {
-    use crate::fragment::selection::{FragmentSelectionAlgorithm as _, OldestFirst};
+    use crate::fragment::selection::{FeeRate, FragmentSelectionAlgorithm as _, OldestFirst};
 
-    let selection_algorithm = OldestFirst::new(250 /* TODO!! */);
+    let max_per_block = ledger.settings().max_number_of_transactions_per_block as usize;
     let metadata = HeaderContentEvalContext {
         block_date: date,
         chain_length,
@@ -167,11 +176,28 @@ fn prepare_block(
     };
     let ledger_params = leadership.ledger_parameters().clone();
 
-    let mut bb = fragment_pool
-        .select(ledger, metadata, ledger_params, selection_algorithm)
-        .wait()
-        .unwrap()
-        .finalize();
+    let mut bb = match mempool_settings.selection_algorithm {
+        SelectionAlgorithm::OldestFirst => fragment_pool
+            .select(
+                ledger,
+                metadata,
+                ledger_params,
+                OldestFirst::new(max_per_block),
+            )
+            .wait()
+            .unwrap()
+            .finalize(),
+        SelectionAlgorithm::FeeRate => fragment_pool
+            .select(
+                ledger,
+                metadata,
+                ledger_params,
+                FeeRate::new(max_per_block, mempool_settings.max_block_size),
+            )
+            .wait()
+            .unwrap()
+            .finalize(),
+    };
 
     bb.date(date).parent(parent_id).chain_length(chain_length);
 
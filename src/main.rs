@@ -28,6 +28,8 @@ extern crate network_grpc;
 extern crate poldercast;
 extern crate rand_chacha;
 extern crate tokio;
+extern crate tokio_signal;
+extern crate tokio_threadpool;
 #[macro_use]
 extern crate custom_error;
 
@@ -58,15 +60,24 @@ extern crate test;
 use crate::{
     blockcfg::Leader,
     blockchain::BlockchainR,
+    intercom::TransactionMsg,
+    network::sync::SyncProgress,
     rest::v0::node::stats::StatsCounter,
     secure::enclave::Enclave,
     settings::start::Settings,
-    utils::{async_msg, task::Services},
+    utils::{
+        async_msg::{self, MessageBox, MessageQueue},
+        task::Services,
+    },
 };
-use futures::Future;
+use chain_core::property::Block as _;
+use futures::{Future, Stream};
 use settings::{start::RawSettings, CommandLine};
 use slog::Logger;
-use std::sync::{Arc, Mutex};
+use std::{
+    io,
+    sync::{Arc, Mutex},
+};
 
 pub mod blockcfg;
 pub mod blockchain;
@@ -95,42 +106,53 @@ pub struct BootstrappedNode {
     settings: Settings,
     blockchain: BlockchainR,
     new_epoch_notifier: tokio::sync::mpsc::Receiver<self::leadership::EpochParameters>,
+    block0_hash: blockcfg::HeaderHash,
+    stats_counter: StatsCounter,
+    fragment_process: fragment::Process,
+    fragment_msgbox: MessageBox<TransactionMsg>,
+    fragment_queue: MessageQueue<TransactionMsg>,
+    rest_server: Option<rest::Server>,
     logger: Logger,
 }
 
-const FRAGMENT_TASK_QUEUE_LEN: usize = 1024;
-const NETWORK_TASK_QUEUE_LEN: usize = 32;
-
 fn start_services(bootstrapped_node: BootstrappedNode) -> Result<(), start_up::Error> {
     let mut services = Services::new(bootstrapped_node.logger.clone());
 
     // initialize the network propagation channel
-    let (mut network_msgbox, network_queue) = async_msg::channel(NETWORK_TASK_QUEUE_LEN);
-    let (fragment_msgbox, fragment_queue) = async_msg::channel(FRAGMENT_TASK_QUEUE_LEN);
+    let (mut network_msgbox, network_queue) =
+        async_msg::channel(bootstrapped_node.settings.mempool.network_queue_len);
+    let fragment_msgbox = bootstrapped_node.fragment_msgbox;
     let new_epoch_notifier = bootstrapped_node.new_epoch_notifier;
 
-    let stats_counter = StatsCounter::default();
+    let stats_counter = bootstrapped_node.stats_counter;
 
-    let (fragment_pool, pool_logs) = {
+    let fragment_pool = {
         let stats_counter = stats_counter.clone();
-        use std::time::Duration;
-        // TODO: get the TTL and from the settings
-        let process = fragment::Process::new(
-            // TTL of a fragment in the MemPool: 1h
-            Duration::from_secs(3600),
-            // TTL of a MemPool log: 2h
-            Duration::from_secs(3600 * 2),
-            // Interval between GC pauses: 15min
-            Duration::from_secs(3600 / 4),
-        );
+        let process = bootstrapped_node.fragment_process;
+        let fragment_queue = bootstrapped_node.fragment_queue;
+        let network_msgbox = network_msgbox.clone();
+        let tip = bootstrapped_node.blockchain.lock_read().tip.clone();
+        let storage = bootstrapped_node.blockchain.lock_read().storage.clone();
+        let rollbacks = bootstrapped_node
+            .blockchain
+            .lock_write()
+            .fork_choice
+            .subscribe();
 
         let pool = process.pool().clone();
-        let logs = process.logs().clone();
 
         services.spawn_future("fragment", move |info| {
-            process.start(info, stats_counter, fragment_queue)
+            process.start(
+                info,
+                stats_counter,
+                tip,
+                storage,
+                rollbacks,
+                network_msgbox,
+                fragment_queue,
+            )
         });
-        (pool, logs)
+        pool
     };
 
     let block_task = {
@@ -150,7 +172,7 @@ fn start_services(bootstrapped_node: BootstrappedNode) -> Result<(), start_up::E
 
     let client_task = {
         let blockchain = bootstrapped_node.blockchain.clone();
-        services.spawn_with_inputs("client-query", move |info, input| {
+        services.spawn_future_with_inputs("client-query", move |info, input| {
             client::handle_input(info, &blockchain, input)
         })
     };
@@ -160,6 +182,8 @@ fn start_services(bootstrapped_node: BootstrappedNode) -> Result<(), start_up::E
         let fragment_msgbox = fragment_msgbox.clone();
         let block_msgbox = block_task.clone();
         let config = bootstrapped_node.settings.network.clone();
+        let block0_hash = bootstrapped_node.block0_hash;
+        let stats_counter = stats_counter.clone();
         let channels = network::Channels {
             client_box: client_msgbox,
             transaction_box: fragment_msgbox,
@@ -167,7 +191,14 @@ fn start_services(bootstrapped_node: BootstrappedNode) -> Result<(), start_up::E
         };
 
         services.spawn("network", move |info| {
-            network::run(config, network_queue, channels, info.into_logger());
+            network::run(
+                config,
+                block0_hash,
+                stats_counter,
+                network_queue,
+                channels,
+                info.into_logger(),
+            );
         });
     }
 
@@ -190,6 +221,7 @@ fn start_services(bootstrapped_node: BootstrappedNode) -> Result<(), start_up::E
         let fragment_pool = fragment_pool.clone();
         let block_task = block_task.clone();
         let blockchain = bootstrapped_node.blockchain.clone();
+        let mempool_settings = bootstrapped_node.settings.mempool.clone();
 
         let enclave = enclave.clone();
 
@@ -197,6 +229,7 @@ fn start_services(bootstrapped_node: BootstrappedNode) -> Result<(), start_up::E
             let process = self::leadership::Process::new(
                 info,
                 fragment_pool,
+                mempool_settings,
                 blockchain.lock_read().tip.clone(),
                 block_task,
             );
@@ -205,28 +238,60 @@ fn start_services(bootstrapped_node: BootstrappedNode) -> Result<(), start_up::E
         });
     }
 
-    let rest_server = match bootstrapped_node.settings.rest {
-        Some(rest) => {
-            let context = rest::Context {
-                stats_counter,
-                blockchain: bootstrapped_node.blockchain.clone(),
-                transaction_task: Arc::new(Mutex::new(fragment_msgbox)),
-                logs: pool_logs,
-            };
-            Some(rest::start_rest_server(&rest, context)?)
+    // the node runs until it is asked to stop, the services are then
+    // stopped so the pool does not change anymore when it is saved
+    match tokio::runtime::current_thread::block_on_all(shutdown_signal()) {
+        Ok(()) => {
+            info!(bootstrapped_node.logger, "shutting down");
+            services.shutdown();
         }
-        None => None,
-    };
-
-    services.wait_all();
+        Err(error) => {
+            warn!(bootstrapped_node.logger, "cannot listen to the shutdown signals, running until the services stop" ; "reason" => error.to_string());
+            services.wait_all();
+        }
+    }
 
-    if let Some(server) = rest_server {
+    if let Some(server) = bootstrapped_node.rest_server {
         server.stop().wait().unwrap()
     }
 
+    // the pool is saved last, once no more fragments can be received
+    if let Some(ref storage) = bootstrapped_node.settings.storage {
+        if let Err(error) = fragment_pool.save(fragment::PoolFile::new(storage)).wait() {
+            warn!(bootstrapped_node.logger, "cannot save the fragment pool" ; "reason" => error.to_string());
+        }
+    }
+
     Ok(())
 }
 
+/// resolves when the node is asked to stop: on SIGINT or, on unix, on
+/// SIGTERM
+fn shutdown_signal() -> impl Future<Item = (), Error = io::Error> {
+    let interrupt = tokio_signal::ctrl_c()
+        .flatten_stream()
+        .into_future()
+        .map(|_| ())
+        .map_err(|(error, _)| error);
+
+    #[cfg(unix)]
+    let terminate = {
+        use tokio_signal::unix::{Signal, SIGTERM};
+        Signal::new(SIGTERM)
+            .flatten_stream()
+            .into_future()
+            .map(|_| ())
+            .map_err(|(error, _)| error)
+    };
+    #[cfg(not(unix))]
+    let terminate = futures::future::empty();
+
+    interrupt
+        .select(terminate)
+        .map(|_| ())
+        .map_err(|(error, _)| error)
+}
+
 /// # Bootstrap phase
 ///
 /// done at every startup: we need to bootstrap from whatever local state (including nothing)
@@ -250,15 +315,61 @@ fn bootstrap(initialized_node: InitializedNode) -> Result<BootstrappedNode, star
 
     let (new_epoch_announcements, new_epoch_notifier) = tokio::sync::mpsc::channel(100);
 
-    let blockchain =
-        start_up::load_blockchain(block0, storage, new_epoch_announcements, &bootstrap_logger)?;
+    let block0_hash = block0.id();
+    let blockchain = start_up::load_blockchain(
+        &settings,
+        block0,
+        storage,
+        new_epoch_announcements,
+        &bootstrap_logger,
+    )?;
+
+    let stats_counter = StatsCounter::default();
+    let sync_progress = SyncProgress::default();
+    let (fragment_msgbox, fragment_queue) = async_msg::channel(settings.mempool.fragment_queue_len);
+
+    let fragment_process = fragment::Process::new(
+        &settings.mempool,
+        settings.storage.as_ref().map(fragment::PoolFile::new),
+        logger.new(o!(log::KEY_TASK => "fragment")),
+    );
+
+    // the REST server is started before the bootstrap so the operators
+    // can follow the progress of the synchronization
+    let rest_server = match settings.rest {
+        Some(ref rest) => {
+            let context = rest::Context {
+                stats_counter: stats_counter.clone(),
+                blockchain: blockchain.clone(),
+                transaction_task: Arc::new(Mutex::new(fragment_msgbox.clone())),
+                logs: fragment_process.logs().clone(),
+                sync_progress: sync_progress.clone(),
+                logger: logger.new(o!(log::KEY_TASK => "rest")),
+            };
+            Some(rest::start_rest_server(rest, context)?)
+        }
+        None => None,
+    };
 
-    network::bootstrap(&settings.network, blockchain.clone(), &bootstrap_logger);
+    network::bootstrap(
+        &settings.network,
+        block0_hash,
+        blockchain.clone(),
+        sync_progress,
+        stats_counter.clone(),
+        &bootstrap_logger,
+    );
 
     Ok(BootstrappedNode {
         settings,
         blockchain,
         new_epoch_notifier,
+        block0_hash,
+        stats_counter,
+        fragment_process,
+        fragment_msgbox,
+        fragment_queue,
+        rest_server,
         logger,
     })
 }
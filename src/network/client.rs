@@ -1,18 +1,18 @@
 use super::{
-    grpc,
+    grpc, handshake,
     p2p::{
-        comm::{PeerComms, Subscription},
+        comm::{ChainPullRequest, PeerComms, Subscription},
         topology,
     },
     subscription, Channels, ConnectionState,
 };
 use crate::{
-    blockcfg::{Block, HeaderHash},
+    blockcfg::{Block, Header, HeaderHash, Message},
     intercom::{self, BlockMsg, ClientMsg},
 };
 use futures::prelude::*;
 use network_core::{
-    client::{block::BlockService, gossip::GossipService, P2pService},
+    client::{block::BlockService, content::ContentService, gossip::GossipService, P2pService},
     subscription::BlockEvent,
 };
 use slog::Logger;
@@ -26,6 +26,7 @@ where
     remote_node_id: topology::NodeId,
     block_events: S::BlockSubscription,
     block_solicitations: Subscription<Vec<HeaderHash>>,
+    chain_pulls: Subscription<ChainPullRequest>,
     logger: Logger,
 }
 
@@ -42,11 +43,38 @@ impl<S: BlockService> Client<S> {
 impl<S> Client<S>
 where
     S: P2pService<NodeId = topology::NodeId>,
-    S: BlockService<Block = Block>,
+    S: BlockService<Block = Block, Header = Header>,
+    S: ContentService<Message = Message>,
     S: GossipService<Node = topology::Node>,
     S::UploadBlocksFuture: Send + 'static,
+    S::MessageSubscription: Send + 'static,
     S::GossipSubscription: Send + 'static,
 {
+    /// do the handshake with the peer, the connection is dropped
+    /// if the peer is refused.
+    fn handshake(
+        mut service: S,
+        state: ConnectionState,
+    ) -> impl Future<Item = (S, ConnectionState), Error = ()> {
+        let err_logger = state.logger().clone();
+        handshake::perform(&mut service, state.global.handshake.clone())
+            .map_err(move |err| {
+                warn!(err_logger, "handshake request failed: {:?}", err);
+            })
+            .and_then(move |outcome| match outcome {
+                Ok(()) => Ok((service, state)),
+                Err(err) => {
+                    let ours = &state.global.handshake;
+                    warn!(state.logger(), "refusing peer: {}", err;
+                        "version" => ours.version.to_string(),
+                        "features" => ours.features.to_string(),
+                    );
+                    state.global.stats_counter.add_peer_refused_cnt(1);
+                    Err(())
+                }
+            })
+    }
+
     fn subscribe(
         mut service: S,
         state: ConnectionState,
@@ -54,18 +82,25 @@ where
     ) -> impl Future<Item = (Self, PeerComms), Error = ()> {
         let mut peer_comms = PeerComms::new();
         let block_req = service.block_subscription(peer_comms.subscribe_to_block_announcements());
+        let message_req = service.message_subscription(peer_comms.subscribe_to_messages());
         let gossip_req = service.gossip_subscription(peer_comms.subscribe_to_gossip());
         let err_logger = state.logger().clone();
         block_req
-            .join(gossip_req)
+            .join3(message_req, gossip_req)
             .map_err(move |err| {
                 warn!(err_logger, "subscription request failed: {:?}", err);
             })
-            .and_then(move |((block_events, node_id), (gossip_sub, node_id_1))| {
-                if node_id != node_id_1 {
+            .and_then(move |(block_res, message_res, gossip_res)| {
+                let (block_events, node_id) = block_res;
+                let (message_sub, node_id_1) = message_res;
+                let (gossip_sub, node_id_2) = gossip_res;
+                if node_id != node_id_1 || node_id != node_id_2 {
                     warn!(
                         state.logger(),
-                        "peer subscription IDs do not match: {} != {}", node_id, node_id_1
+                        "peer subscription IDs do not match: {}, {}, {}",
+                        node_id,
+                        node_id_1,
+                        node_id_2
                     );
                     return Err(());
                 }
@@ -73,11 +108,19 @@ where
 
                 // Spin off processing tasks for subscriptions that can be
                 // managed with just the global state.
+                subscription::process_messages(
+                    message_sub,
+                    node_id,
+                    state.connection,
+                    channels.transaction_box.clone(),
+                    client_logger.clone(),
+                );
                 subscription::process_gossip(gossip_sub, state.global, client_logger.clone());
 
                 // Plug the block solicitations to be handled
                 // via client requests.
                 let block_solicitations = peer_comms.subscribe_to_block_solicitations();
+                let chain_pulls = peer_comms.subscribe_to_chain_pulls();
 
                 // Resolve with the client instance and communication handles.
                 let client = Client {
@@ -86,6 +129,7 @@ where
                     remote_node_id: node_id,
                     block_events,
                     block_solicitations,
+                    chain_pulls,
                     logger: client_logger,
                 };
                 Ok((client, peer_comms))
@@ -111,9 +155,17 @@ where
                     Block,
                     network_core::error::Error,
                 >(self.logger.clone());
-                self.channels
+                // the upload ends with an error if the query is dropped
+                if let Err(e) = self
+                    .channels
                     .client_box
-                    .send_to(ClientMsg::GetBlocks(block_ids, reply_handle));
+                    .try_send(ClientMsg::GetBlocks(block_ids, reply_handle))
+                {
+                    warn!(
+                        self.logger,
+                        "cannot query the blocks solicited by the peer: {}", e
+                    );
+                }
                 let node_id = self.remote_node_id;
                 let done_logger = self.logger.clone();
                 let err_logger = self.logger.clone();
@@ -140,6 +192,7 @@ where
 {
     fn solicit_blocks(&mut self, block_ids: &[HeaderHash]) {
         let mut block_box = self.channels.block_box.clone();
+        let node_id = self.remote_node_id;
         let err_logger = self.logger.clone();
         let and_then_logger = self.logger.clone();
         tokio::spawn(
@@ -151,7 +204,9 @@ where
                 .and_then(move |blocks| {
                     blocks
                         .for_each(move |block| {
-                            block_box.try_send(BlockMsg::NetworkBlock(block)).unwrap();
+                            block_box
+                                .try_send(BlockMsg::NetworkBlock(block, Some(node_id)))
+                                .unwrap();
                             Ok(())
                         })
                         .map_err(move |e| {
@@ -165,11 +220,62 @@ where
     }
 }
 
+impl<S> Client<S>
+where
+    S: BlockService<Block = Block, Header = Header>,
+    S::PullHeadersFuture: Send + 'static,
+    S::PullHeadersStream: Send + 'static,
+{
+    fn pull_headers(&mut self, req: ChainPullRequest) {
+        let block_box = self.channels.block_box.clone();
+        let node_id = self.remote_node_id;
+        let err_logger = self.logger.clone();
+        let and_then_logger = self.logger.clone();
+        let send_logger = self.logger.clone();
+        tokio::spawn(
+            self.service
+                .pull_headers_to(&req.from, &req.to)
+                .map_err(move |e| {
+                    warn!(err_logger, "PullHeaders request failed: {:?}", e);
+                })
+                .and_then(move |headers| {
+                    headers
+                        .collect()
+                        .map_err(move |e| {
+                            warn!(
+                                and_then_logger,
+                                "PullHeaders response stream failed: {:?}", e
+                            );
+                        })
+                        .and_then(move |headers| {
+                            // wait for the block task to have room for
+                            // the headers rather than dropping them
+                            block_box
+                                .send(BlockMsg::ChainHeaders {
+                                    headers,
+                                    to: req.to,
+                                    node_id,
+                                })
+                                .map(|_| ())
+                                .map_err(move |_| {
+                                    warn!(
+                                        send_logger,
+                                        "cannot pass the pulled headers to the block task"
+                                    );
+                                })
+                        })
+                }),
+        );
+    }
+}
+
 impl<S> Future for Client<S>
 where
-    S: BlockService<Block = Block>,
+    S: BlockService<Block = Block, Header = Header>,
     S::GetBlocksFuture: Send + 'static,
     S::GetBlocksStream: Send + 'static,
+    S::PullHeadersFuture: Send + 'static,
+    S::PullHeadersStream: Send + 'static,
     S::UploadBlocksFuture: Send + 'static,
 {
     type Item = ();
@@ -203,6 +309,18 @@ where
                     self.solicit_blocks(&block_ids);
                 }
             }
+            let chain_pull_polled = self.chain_pulls.poll().unwrap();
+            match chain_pull_polled {
+                Async::NotReady => {}
+                Async::Ready(None) => {
+                    debug!(self.logger, "outbound header pull stream closed");
+                    return Ok(().into());
+                }
+                Async::Ready(Some(req)) => {
+                    streams_ready = true;
+                    self.pull_headers(req);
+                }
+            }
             if !streams_ready {
                 return Ok(Async::NotReady);
             }
@@ -219,7 +337,8 @@ pub fn connect(
         .map_err(move |err| {
             warn!(err_logger, "error connecting to peer: {:?}", err);
         })
-        .and_then(move |conn| Client::subscribe(conn, state, channels))
+        .and_then(move |conn| Client::handshake(conn, state))
+        .and_then(move |(conn, state)| Client::subscribe(conn, state, channels))
         .map(move |(client, comms)| {
             debug!(client.logger(), "connected to peer",);
             (client, comms)
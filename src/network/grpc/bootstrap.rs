@@ -1,71 +1,166 @@
-use super::super::BlockConfig;
-use super::origin_authority;
-use crate::{blockchain::BlockchainR, settings::start::network::Peer};
-use blockcfg::Block;
-use chain_core::property::HasHeader;
+use super::super::{
+    handshake::{self, Handshake},
+    sync::{self, SyncPeer, SyncProgress},
+};
+use super::{origin_authority, Connection};
+use crate::{
+    blockchain::BlockchainR, rest::v0::node::stats::StatsCounter, settings::start::network::Peer,
+};
+use futures::{
+    future::{Either, Loop},
+    stream,
+};
 use http::uri;
-use network_core::client::block::BlockService as _;
-use network_grpc::client::{Connect, Connection, TcpConnector};
+use network_grpc::client::{Connect, TcpConnector};
 use slog::Logger;
-use std::fmt::Debug;
+use std::time::{Duration, Instant};
 use tokio::prelude::*;
-use tokio::{executor::DefaultExecutor, runtime::current_thread};
+use tokio::{executor::DefaultExecutor, runtime::current_thread, timer::Delay};
 
-pub fn bootstrap_from_peer(peer: Peer, blockchain: BlockchainR, logger: &Logger) {
-    info!(logger, "connecting to bootstrap peer {}", peer.connection);
-    let addr = peer.address();
-    let origin = origin_authority(addr);
-    let bootstrap = Connect::new(TcpConnector, DefaultExecutor::current())
-        .origin(uri::Scheme::HTTP, origin)
-        .connect(addr)
-        .map_err(move |e| {
-            error!(logger, "failed to connect to bootstrap peer: {:?}", e);
+/// number of consecutive synchronization attempts that may fail to
+/// apply any block before the bootstrap is abandoned
+const MAX_ATTEMPTS_WITHOUT_PROGRESS: u32 = 5;
+/// delay before retrying a failed synchronization
+const RETRY_DELAY: Duration = Duration::from_secs(10);
+
+/// synchronize the blockchain from the given peers, the peers we
+/// fail to connect to are ignored.
+///
+/// If the synchronization fails, the peers are reconnected and the
+/// synchronization resumes from the last applied block. The bootstrap
+/// is only abandoned after `MAX_ATTEMPTS_WITHOUT_PROGRESS` attempts in
+/// a row did not apply any new block.
+pub fn bootstrap_from_peers(
+    peers: Vec<Peer>,
+    handshake: Handshake,
+    blockchain: BlockchainR,
+    progress: SyncProgress,
+    stats_counter: StatsCounter,
+    logger: &Logger,
+) {
+    let logger = logger.clone();
+    let bootstrap = future::loop_fn(0, move |failures| {
+        let tip_before = blockchain.lock_read().get_tip().ok();
+        let blockchain = blockchain.clone();
+        let progress = progress.clone();
+        let logger = logger.clone();
+        synchronize_once(
+            peers.clone(),
+            handshake.clone(),
+            stats_counter.clone(),
+            blockchain.clone(),
+            progress.clone(),
+            logger.clone(),
+        )
+        .then(move |res| {
+            if res.is_ok() {
+                debug!(logger, "bootstrap complete");
+                return Either::A(future::ok(Loop::Break(())));
+            }
+
+            let failures = if blockchain.lock_read().get_tip().ok() != tip_before {
+                0
+            } else {
+                failures + 1
+            };
+            if failures >= MAX_ATTEMPTS_WITHOUT_PROGRESS {
+                error!(
+                    logger,
+                    "bootstrap abandoned after {} attempts without progress", failures
+                );
+                progress.fail();
+                return Either::A(future::err(()));
+            }
+
+            warn!(
+                logger,
+                "bootstrap interrupted, resuming in {}s",
+                RETRY_DELAY.as_secs()
+            );
+            let err_logger = logger.clone();
+            Either::B(
+                Delay::new(Instant::now() + RETRY_DELAY)
+                    .map_err(move |e| {
+                        error!(err_logger, "bootstrap retry timer error: {:?}", e);
+                    })
+                    .map(move |()| Loop::Continue(failures)),
+            )
         })
-        .and_then(|mut client: Connection<BlockConfig, _, _>| {
-            let tip = blockchain.lock_read().get_tip().unwrap();
-            client
-                .pull_blocks_to_tip(&[tip])
-                .map_err(|e| {
-                    error!(logger, "PullBlocksToTip request failed: {:?}", e);
-                })
-                .and_then(|stream| bootstrap_from_stream(blockchain, stream, logger.clone()))
-        });
+    });
 
-    match current_thread::block_on_all(bootstrap) {
-        Ok(()) => debug!(logger, "bootstrap complete"),
-        Err(()) => {
-            // All specific errors should be logged and mapped to () in
-            // future/stream error handling combinators.
-        }
-    }
+    // All specific errors should be logged and mapped to () in
+    // future/stream error handling combinators.
+    let _ = current_thread::block_on_all(bootstrap);
 }
 
-fn bootstrap_from_stream<S>(
+fn synchronize_once(
+    peers: Vec<Peer>,
+    handshake: Handshake,
+    stats_counter: StatsCounter,
     blockchain: BlockchainR,
-    stream: S,
+    progress: SyncProgress,
     logger: Logger,
-) -> impl Future<Item = (), Error = ()>
-where
-    S: Stream<Item = Block>,
-    S::Error: Debug,
-{
-    let fold_logger = logger.clone();
-    stream
-        .fold(blockchain, move |blockchain, block| {
-            use crate::blockchain::handle_block;
-            debug!(
-                fold_logger,
-                "received block from the bootstrap node: {:#?}",
-                block.header()
-            );
-            let res = handle_block(&mut blockchain.lock_write(), block, true);
-            if let Err(e) = res {
-                error!(fold_logger, "error processing a bootstrap block: {:?}", e);
+) -> impl Future<Item = (), Error = ()> {
+    let connect_logger = logger.clone();
+    stream::iter_ok(peers)
+        .and_then(move |peer| {
+            connect_peer(
+                peer,
+                handshake.clone(),
+                stats_counter.clone(),
+                &connect_logger,
+            )
+        })
+        .filter_map(|peer| peer)
+        .collect()
+        .and_then(move |peers| {
+            if peers.is_empty() {
+                error!(logger, "could not connect to any bootstrap peer");
+                return Either::A(future::err(()));
             }
-            future::ok(blockchain)
+            Either::B(sync::synchronize(peers, blockchain, progress, logger))
         })
-        .map(|_| ())
+}
+
+/// connect to the peer and do the handshake, resolves to `None` if the
+/// connection failed or the peer is refused.
+fn connect_peer(
+    peer: Peer,
+    handshake: Handshake,
+    stats_counter: StatsCounter,
+    logger: &Logger,
+) -> impl Future<Item = Option<SyncPeer<Connection>>, Error = ()> {
+    info!(logger, "connecting to bootstrap peer {}", peer.connection);
+    let addr = peer.address();
+    let origin = origin_authority(addr);
+    let err_logger = logger.clone();
+    let logger = logger.clone();
+    Connect::new(TcpConnector, DefaultExecutor::current())
+        .origin(uri::Scheme::HTTP, origin)
+        .connect(addr)
         .map_err(move |e| {
-            error!(logger, "bootstrap block streaming failed: {:?}", e);
+            warn!(
+                err_logger,
+                "failed to connect to bootstrap peer {}: {:?}", addr, e
+            );
+        })
+        .and_then(move |mut client: Connection| {
+            let err_logger = logger.clone();
+            handshake::perform(&mut client, handshake)
+                .map_err(move |e| {
+                    warn!(
+                        err_logger,
+                        "handshake with bootstrap peer {} failed: {:?}", addr, e
+                    );
+                })
+                .and_then(move |outcome| match outcome {
+                    Ok(()) => Ok(Some(SyncPeer::new(addr, client))),
+                    Err(e) => {
+                        warn!(logger, "refusing bootstrap peer {}: {}", addr, e);
+                        stats_counter.add_peer_refused_cnt(1);
+                        Ok(None)
+                    }
+                })
         })
+        .or_else(|()| Ok(None))
 }
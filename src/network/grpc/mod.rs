@@ -9,7 +9,7 @@ use http::{uri, HttpTryFrom};
 
 use std::net::SocketAddr;
 
-pub use self::bootstrap::bootstrap_from_peer;
+pub use self::bootstrap::bootstrap_from_peers;
 pub use self::client::{connect, fetch_block, Connection};
 pub use self::server::run_listen_socket;
 
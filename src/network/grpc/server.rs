@@ -25,10 +25,8 @@ pub fn run_listen_socket(
             unimplemented!()
         }
         Ok(listener_stream) => {
-            let fold_logger = state.logger().clone();
+            let accept_logger = state.logger().clone();
             let err_logger = state.logger().clone();
-            let node_server = NodeService::new(channels, state);
-            let server = Server::new(node_server, DefaultExecutor::current());
 
             listener_stream
                 .map_err(move |err| {
@@ -40,24 +38,30 @@ pub fn run_listen_socket(
                         "Error while accepting connection on {}: {:?}", sockaddr, err
                     );
                 })
-                .fold(server, move |mut server, stream| {
+                .for_each(move |stream| {
                     // received incoming connection
-                    info!(
-                        fold_logger,
-                        "{} connected to {}",
-                        stream.peer_addr().unwrap(),
-                        stream.local_addr().unwrap(),
-                    );
+                    let (peer_addr, local_addr) = match (stream.peer_addr(), stream.local_addr()) {
+                        (Ok(peer_addr), Ok(local_addr)) => (peer_addr, local_addr),
+                        // the connection may already be closed
+                        (Err(error), _) | (_, Err(error)) => {
+                            warn!(accept_logger, "incoming connection skipped, cannot get its addresses" ; "reason" => error.to_string());
+                            return future::ok(());
+                        }
+                    };
+                    info!(accept_logger, "{} connected to {}", peer_addr, local_addr);
 
+                    // every connection is served by its own node service,
+                    // so what the peer sends can be traced back to it
+                    let node_server = NodeService::new(channels.clone(), state.clone(), peer_addr);
+                    let mut server = Server::new(node_server, DefaultExecutor::current());
                     let conn = server.serve(stream);
-                    let conn_logger = fold_logger.clone();
+                    let conn_logger = accept_logger.clone();
                     tokio::spawn(
                         conn.map_err(move |e| error!(conn_logger, "server error: {:?}", e)),
                     );
 
-                    future::ok(server)
+                    future::ok(())
                 })
-                .map(|_| ())
         }
     }
 }
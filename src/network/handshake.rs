@@ -0,0 +1,370 @@
+//! Handshake done by the nodes when a connection is established.
+//!
+//! Before exchanging anything else, the nodes check that they:
+//!
+//! * are on the same network: nodes started from different genesis
+//!   blocks must not exchange anything;
+//! * speak compatible versions of the protocol;
+//! * support the features of the protocol the other node requires.
+//!
+//! The protocol has no dedicated handshake request, so the node opening
+//! the connection sends a PullHeaders request up to its block0, with a
+//! single checkpoint encoding its protocol version, the oldest version
+//! it can talk to and the features it supports (see [`Handshake::request`]):
+//!
+//! * the server checks the handshake of the client, see
+//!   [`Handshake::check`]. If the client is accepted, the server answers
+//!   the header of its block0, otherwise the request fails with the
+//!   reason of the refusal. The server refuses the other requests and
+//!   the subscriptions of a peer until it has done the handshake;
+//! * the client refuses a peer that does not answer with the header of
+//!   its block0, see [`perform`].
+//!
+//! A node not knowing about the handshake takes the encoded checkpoint
+//! for an unknown block and does not find our block0 either: the client
+//! refuses it as a peer on a different network.
+//!
+//! [`Handshake::request`]: ./struct.Handshake.html#method.request
+//! [`Handshake::check`]: ./struct.Handshake.html#method.check
+//! [`perform`]: ./fn.perform.html
+
+use crate::blockcfg::{Block, Header, HeaderHash};
+use chain_core::property::{Deserialize as _, Header as _};
+use futures::prelude::*;
+use network_core::{client::block::BlockService, error as core_error};
+use std::fmt;
+
+/// the version of the protocol spoken by this node
+pub const PROTOCOL_VERSION: ProtocolVersion = ProtocolVersion(1);
+/// the oldest version of the protocol this node can talk to
+pub const MIN_COMPATIBLE_PROTOCOL_VERSION: ProtocolVersion = ProtocolVersion(1);
+
+/// prefix of the checkpoint encoding the handshake, it cannot be
+/// mistaken for the hash of a block
+const HANDSHAKE_MAGIC: &[u8; 8] = b"jorm-hs\0";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ProtocolVersion(pub u32);
+
+impl fmt::Display for ProtocolVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// set of the optional features of the protocol a node supports
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Features(u32);
+
+impl Features {
+    /// pulling the headers of a chain between checkpoints and a block
+    pub const PULL_HEADERS: Features = Features(0b0001);
+    /// exchanging fragments with the content service
+    pub const CONTENT: Features = Features(0b0010);
+
+    /// the features supported by this node
+    pub fn supported() -> Self {
+        Features::PULL_HEADERS.union(Features::CONTENT)
+    }
+
+    /// the features a peer must support for this node to talk to it
+    pub fn required() -> Self {
+        Features::PULL_HEADERS
+    }
+
+    pub fn union(self, other: Features) -> Self {
+        Features(self.0 | other.0)
+    }
+
+    /// the features of `self` that are not in `other`
+    pub fn difference(self, other: Features) -> Self {
+        Features(self.0 & !other.0)
+    }
+
+    pub fn contains(self, other: Features) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl fmt::Display for Features {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut names = Vec::new();
+        if self.contains(Features::PULL_HEADERS) {
+            names.push("pull-headers");
+        }
+        if self.contains(Features::CONTENT) {
+            names.push("content");
+        }
+        let unknown = self.difference(Features::supported());
+        if !unknown.is_empty() {
+            return write!(
+                f,
+                "{} (and unknown features {:#b})",
+                names.join(", "),
+                unknown.0
+            );
+        }
+        f.write_str(&names.join(", "))
+    }
+}
+
+custom_error! {pub HandshakeError
+    Block0Mismatch { expected: HeaderHash, received: HeaderHash } = "peer is on a different network: its block0 is {received}, ours is {expected}",
+    IncompatibleVersion { version: ProtocolVersion, min_version: ProtocolVersion } = "peer speaks the protocol version {version} and talks to versions from {min_version}, incompatible with our version",
+    MissingFeatures { missing: Features } = "peer does not support the required features: {missing}",
+    UnknownBlock0 { block0: HeaderHash } = "peer is on a different network: it does not know our block0 {block0}",
+    UnexpectedResponse { block0: HeaderHash } = "peer did not answer the handshake with the header of our block0 {block0}",
+    RefusedByPeer { reason: String } = "peer refused our handshake: {reason}",
+    NotDone = "the peer has not done the handshake",
+}
+
+/// what a node tells about itself when a connection is established
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Handshake {
+    pub block0: HeaderHash,
+    pub version: ProtocolVersion,
+    /// the oldest version of the protocol the node can talk to
+    pub min_version: ProtocolVersion,
+    pub features: Features,
+}
+
+impl Handshake {
+    /// the handshake of this node
+    pub fn new(block0: HeaderHash) -> Self {
+        Handshake {
+            block0,
+            version: PROTOCOL_VERSION,
+            min_version: MIN_COMPATIBLE_PROTOCOL_VERSION,
+            features: Features::supported(),
+        }
+    }
+
+    /// the arguments of the PullHeaders request sending this handshake
+    pub fn request(&self) -> (Vec<HeaderHash>, HeaderHash) {
+        let mut bytes = [0; 32];
+        bytes[..8].copy_from_slice(HANDSHAKE_MAGIC);
+        bytes[8..12].copy_from_slice(&self.version.0.to_be_bytes());
+        bytes[12..16].copy_from_slice(&self.min_version.0.to_be_bytes());
+        bytes[16..20].copy_from_slice(&self.features.0.to_be_bytes());
+        let checkpoint = HeaderHash::deserialize(&bytes[..]).unwrap();
+        (vec![checkpoint], self.block0)
+    }
+
+    /// decode the handshake sent by a peer with a PullHeaders request,
+    /// `None` if the request is not a handshake
+    pub fn from_request(from: &[HeaderHash], to: &HeaderHash) -> Option<Self> {
+        let bytes: &[u8] = match from {
+            [checkpoint] => checkpoint.as_ref(),
+            _ => return None,
+        };
+        if !bytes.starts_with(HANDSHAKE_MAGIC) {
+            return None;
+        }
+        let read_u32 = |at: usize| {
+            let mut word = [0; 4];
+            word.copy_from_slice(&bytes[at..at + 4]);
+            u32::from_be_bytes(word)
+        };
+        Some(Handshake {
+            block0: *to,
+            version: ProtocolVersion(read_u32(8)),
+            min_version: ProtocolVersion(read_u32(12)),
+            features: Features(read_u32(16)),
+        })
+    }
+
+    /// check the peer's handshake against ours
+    pub fn check(&self, peer: &Handshake) -> Result<(), HandshakeError> {
+        if peer.block0 != self.block0 {
+            return Err(HandshakeError::Block0Mismatch {
+                expected: self.block0,
+                received: peer.block0,
+            });
+        }
+        if peer.version < self.min_version || self.version < peer.min_version {
+            return Err(HandshakeError::IncompatibleVersion {
+                version: peer.version,
+                min_version: peer.min_version,
+            });
+        }
+        let missing = Features::required().difference(peer.features);
+        if !missing.is_empty() {
+            return Err(HandshakeError::MissingFeatures { missing });
+        }
+        Ok(())
+    }
+
+    /// check the peer's answer to the handshake request
+    pub fn check_response(&self, headers: &[Header]) -> Result<(), HandshakeError> {
+        match headers {
+            [header] if header.id() == self.block0 => Ok(()),
+            _ => Err(HandshakeError::UnexpectedResponse {
+                block0: self.block0,
+            }),
+        }
+    }
+}
+
+/// do the handshake with the peer we are connected to.
+///
+/// The future fails if the request failed, and resolves with the
+/// outcome of the handshake otherwise.
+pub fn perform<S>(
+    service: &mut S,
+    handshake: Handshake,
+) -> impl Future<Item = Result<(), HandshakeError>, Error = core_error::Error>
+where
+    S: BlockService<Block = Block, Header = Header>,
+{
+    let (from, to) = handshake.request();
+    service
+        .pull_headers_to(&from, &to)
+        .and_then(|headers| headers.collect())
+        .then(move |res| match res {
+            Ok(headers) => Ok(handshake.check_response(&headers)),
+            Err(err) => match err.code() {
+                core_error::Code::NotFound => Ok(Err(HandshakeError::UnknownBlock0 {
+                    block0: handshake.block0,
+                })),
+                core_error::Code::FailedPrecondition => Ok(Err(HandshakeError::RefusedByPeer {
+                    reason: err.to_string(),
+                })),
+                _ => Err(err),
+            },
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockcfg::{BlockBuilder, BlockDate};
+    use chain_core::property::Block as _;
+
+    fn block0() -> Block {
+        BlockBuilder::new().make_genesis_block()
+    }
+
+    fn hash(byte: u8) -> HeaderHash {
+        HeaderHash::hash_bytes(&[byte])
+    }
+
+    #[test]
+    fn request_round_trip() {
+        let mut handshake = Handshake::new(hash(0));
+        handshake.version = ProtocolVersion(3);
+        handshake.min_version = ProtocolVersion(2);
+        handshake.features = Features::CONTENT;
+        let (from, to) = handshake.request();
+        assert_eq!(to, handshake.block0);
+        assert_eq!(Handshake::from_request(&from, &to), Some(handshake));
+    }
+
+    #[test]
+    fn other_requests_are_not_handshakes() {
+        assert_eq!(Handshake::from_request(&[], &hash(0)), None);
+        assert_eq!(Handshake::from_request(&[hash(1)], &hash(0)), None);
+        let (mut from, to) = Handshake::new(hash(0)).request();
+        from.push(hash(1));
+        assert_eq!(Handshake::from_request(&from, &to), None);
+    }
+
+    #[test]
+    fn accept_same_network() {
+        let ours = Handshake::new(hash(0));
+        assert!(ours.check(&Handshake::new(hash(0))).is_ok());
+    }
+
+    #[test]
+    fn refuse_different_block0() {
+        let ours = Handshake::new(hash(0));
+        match ours.check(&Handshake::new(hash(1))) {
+            Err(HandshakeError::Block0Mismatch { expected, received }) => {
+                assert_eq!(expected, hash(0));
+                assert_eq!(received, hash(1));
+            }
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn refuse_old_version() {
+        let ours = Handshake::new(hash(0));
+        let mut peer = Handshake::new(hash(0));
+        peer.version = ProtocolVersion(0);
+        peer.min_version = ProtocolVersion(0);
+        match ours.check(&peer) {
+            Err(HandshakeError::IncompatibleVersion { version, .. }) => {
+                assert_eq!(version, ProtocolVersion(0))
+            }
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn refuse_peer_not_talking_to_our_version() {
+        let ours = Handshake::new(hash(0));
+        let mut peer = Handshake::new(hash(0));
+        peer.version = ProtocolVersion(PROTOCOL_VERSION.0 + 2);
+        peer.min_version = ProtocolVersion(PROTOCOL_VERSION.0 + 1);
+        match ours.check(&peer) {
+            Err(HandshakeError::IncompatibleVersion { min_version, .. }) => {
+                assert_eq!(min_version, peer.min_version)
+            }
+            res => panic!("unexpected result: {:?}", res),
+        }
+
+        // a newer peer still talking to our version is accepted
+        peer.min_version = PROTOCOL_VERSION;
+        assert!(ours.check(&peer).is_ok());
+    }
+
+    #[test]
+    fn refuse_missing_features() {
+        let ours = Handshake::new(hash(0));
+        let mut peer = Handshake::new(hash(0));
+        peer.features = Features::CONTENT;
+        match ours.check(&peer) {
+            Err(HandshakeError::MissingFeatures { missing }) => {
+                assert_eq!(missing, Features::PULL_HEADERS)
+            }
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn accept_the_header_of_block0() {
+        let block0 = block0();
+        let ours = Handshake::new(block0.id());
+        assert!(ours.check_response(&[block0.header()]).is_ok());
+    }
+
+    #[test]
+    fn refuse_other_headers() {
+        let block0 = block0();
+        let mut builder = BlockBuilder::new();
+        builder
+            .date(BlockDate {
+                epoch: 0,
+                slot_id: 1,
+            })
+            .parent(block0.id())
+            .chain_length(block0.chain_length().next());
+        let block1 = builder.make_genesis_block();
+        let ours = Handshake::new(block0.id());
+
+        for headers in &[
+            vec![],
+            vec![block1.header()],
+            vec![block0.header(), block1.header()],
+        ] {
+            match ours.check_response(headers) {
+                Err(HandshakeError::UnexpectedResponse { .. }) => {}
+                res => panic!("unexpected result: {:?}", res),
+            }
+        }
+    }
+}
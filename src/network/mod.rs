@@ -7,24 +7,26 @@
 
 mod client;
 mod grpc;
+pub mod handshake;
 // TODO: to be ported
 //mod ntt;
 pub mod p2p;
 mod service;
 mod subscription;
+pub mod sync;
 
+use self::handshake::Handshake;
 use self::p2p::{
     comm::{PeerComms, PeerMap},
     topology::{self, P2pTopology},
 };
+use self::sync::SyncProgress;
 use crate::blockcfg::{Block, HeaderHash};
 use crate::blockchain::BlockchainR;
 use crate::intercom::{BlockMsg, ClientMsg, NetworkMsg, PropagateMsg, TransactionMsg};
+use crate::rest::v0::node::stats::StatsCounter;
 use crate::settings::start::network::{Configuration, Peer, Protocol};
-use crate::utils::{
-    async_msg::{MessageBox, MessageQueue},
-    task::TaskMessageBox,
-};
+use crate::utils::async_msg::{MessageBox, MessageQueue};
 use futures::prelude::*;
 use futures::stream;
 use network_core::{
@@ -41,7 +43,7 @@ pub enum BlockConfig {}
 
 /// all the different channels the network may need to talk to
 pub struct Channels {
-    pub client_box: TaskMessageBox<ClientMsg>,
+    pub client_box: MessageBox<ClientMsg>,
     pub transaction_box: MessageBox<TransactionMsg>,
     pub block_box: MessageBox<BlockMsg>,
 }
@@ -62,6 +64,9 @@ pub struct GlobalState {
     pub topology: P2pTopology,
     pub node: topology::Node,
     pub peers: PeerMap,
+    /// the handshake done with the peers, identifying our network
+    pub handshake: Handshake,
+    pub stats_counter: StatsCounter,
     pub logger: Logger,
 }
 
@@ -69,7 +74,12 @@ type GlobalStateR = Arc<GlobalState>;
 
 impl GlobalState {
     /// the network global state
-    pub fn new(config: Configuration, logger: Logger) -> Self {
+    pub fn new(
+        config: Configuration,
+        block0_hash: HeaderHash,
+        stats_counter: StatsCounter,
+        logger: Logger,
+    ) -> Self {
         let node_id = config.public_id.unwrap_or(topology::NodeId::generate());
         info!(logger, "our node id: {}", node_id);
         let node_address = config
@@ -97,6 +107,8 @@ impl GlobalState {
             topology,
             node,
             peers: PeerMap::new(logger.clone()),
+            handshake: Handshake::new(block0_hash),
+            stats_counter,
             logger,
         }
     }
@@ -136,6 +148,8 @@ impl ConnectionState {
 
 pub fn run(
     config: Configuration,
+    block0_hash: HeaderHash,
+    stats_counter: StatsCounter,
     input: MessageQueue<NetworkMsg>,
     channels: Channels,
     logger: Logger,
@@ -143,7 +157,12 @@ pub fn run(
     // TODO: the node needs to be saved/loaded
     //
     // * the ID needs to be consistent between restart;
-    let global_state = Arc::new(GlobalState::new(config, logger.clone()));
+    let global_state = Arc::new(GlobalState::new(
+        config,
+        block0_hash,
+        stats_counter,
+        logger.clone(),
+    ));
 
     // open the port for listening/accepting other peers to connect too
     let listen = global_state.config.listen();
@@ -216,12 +235,28 @@ fn handle_network_input(
             state.peers.solicit_blocks(node_id, block_ids);
             Ok(())
         }
+        NetworkMsg::PullHeaders { node_id, from, to } => {
+            state.peers.pull_headers(node_id, from, to);
+            Ok(())
+        }
+        NetworkMsg::PeerMisbehaved(node_id, misbehaviour) => {
+            state.peers.report_misbehaviour(node_id, misbehaviour);
+            Ok(())
+        }
     })
 }
 
 fn handle_propagation_msg(msg: PropagateMsg, state: GlobalStateR, channels: Channels) {
     debug!(state.logger(), "to propagate: {:?}", &msg);
-    let nodes = state.topology.view().collect::<Vec<_>>();
+    let sender = match msg {
+        PropagateMsg::Message(_, sender) => sender,
+        PropagateMsg::Block(_) => None,
+    };
+    let nodes = state
+        .topology
+        .view()
+        .filter(|node| Some(node.id()) != sender)
+        .collect::<Vec<_>>();
     debug!(
         state.logger(),
         "will propagate to: {:?}",
@@ -229,7 +264,9 @@ fn handle_propagation_msg(msg: PropagateMsg, state: GlobalStateR, channels: Chan
     );
     let res = match msg {
         PropagateMsg::Block(ref header) => state.peers.propagate_block(nodes, header.clone()),
-        PropagateMsg::Message(ref message) => state.peers.propagate_message(nodes, message.clone()),
+        PropagateMsg::Message(ref message, _) => {
+            state.peers.propagate_message(nodes, message.clone())
+        }
     };
     // If any nodes selected for propagation are not in the
     // active subscriptions map, connect to them and deliver
@@ -245,7 +282,7 @@ fn handle_propagation_msg(msg: PropagateMsg, state: GlobalStateR, channels: Chan
                     PropagateMsg::Block(header) => handles
                         .try_send_block_announcement(header)
                         .map_err(|e| e.kind()),
-                    PropagateMsg::Message(message) => {
+                    PropagateMsg::Message(message, _) => {
                         handles.try_send_message(message).map_err(|e| e.kind())
                     }
                 },
@@ -286,6 +323,10 @@ fn connect_and_propagate_with<F>(
         }
     };
     let node_id = node.id();
+    if state.peers.is_banned(&node_id) {
+        debug!(state.logger(), "not connecting to banned peer {}", node_id);
+        return;
+    }
     let peer = Peer::new(addr, Protocol::Grpc);
     let conn_state = ConnectionState::new(state.clone(), &peer);
     debug!(conn_state.logger(), "connecting to node {}", node_id);
@@ -323,18 +364,38 @@ fn first_trusted_peer_address(config: &Configuration) -> Option<SocketAddr> {
         .next()
 }
 
-pub fn bootstrap(config: &Configuration, blockchain: BlockchainR, logger: &Logger) {
+/// synchronize the blockchain from the trusted peers, the blocks are
+/// downloaded from all of them in parallel.
+pub fn bootstrap(
+    config: &Configuration,
+    block0_hash: HeaderHash,
+    blockchain: BlockchainR,
+    progress: SyncProgress,
+    stats_counter: StatsCounter,
+    logger: &Logger,
+) {
     if config.protocol != Protocol::Grpc {
         unimplemented!()
     }
-    match first_trusted_peer_address(config) {
-        Some(address) => {
-            let peer = Peer::new(address, Protocol::Grpc);
-            grpc::bootstrap_from_peer(peer, blockchain, logger)
-        }
-        None => {
-            warn!(logger, "no gRPC peers specified, skipping bootstrap");
-        }
+    let peers = config
+        .trusted_peers
+        .iter()
+        .filter_map(|peer| peer.address.to_socketaddr())
+        .map(|address| Peer::new(address, Protocol::Grpc))
+        .collect::<Vec<_>>();
+    if peers.is_empty() {
+        warn!(logger, "no gRPC peers specified, skipping bootstrap");
+        progress.complete();
+    } else {
+        let handshake = Handshake::new(block0_hash);
+        grpc::bootstrap_from_peers(
+            peers,
+            handshake,
+            blockchain,
+            progress,
+            stats_counter,
+            logger,
+        )
     }
 }
 
@@ -1,4 +1,7 @@
-use super::topology;
+use super::{
+    misbehaviour::{Misbehaviour, Misbehaviours, Verdict},
+    topology,
+};
 use crate::blockcfg::{Block, Header, HeaderHash, Message};
 use futures::prelude::*;
 use futures::{stream, sync::mpsc};
@@ -18,6 +21,16 @@ use std::{
 // a gRPC subscription stream.
 const BUFFER_LEN: usize = 8;
 
+/// Request to pull the headers of a chain from a peer.
+#[derive(Clone, Debug)]
+pub struct ChainPullRequest {
+    /// the checkpoints, the peer starts the chain after the most
+    /// recent one it knows about
+    pub from: Vec<HeaderHash>,
+    /// the last header of the chain
+    pub to: HeaderHash,
+}
+
 #[derive(Debug)]
 pub struct PropagateError<T> {
     kind: ErrorKind,
@@ -136,6 +149,7 @@ enum SubscriptionState<T> {
 pub struct PeerComms {
     block_announcements: CommHandle<Header>,
     block_solicitations: CommHandle<Vec<HeaderHash>>,
+    chain_pulls: CommHandle<ChainPullRequest>,
     messages: CommHandle<Message>,
     gossip: CommHandle<Gossip<topology::Node>>,
 }
@@ -173,6 +187,10 @@ impl PeerComms {
         self.block_solicitations.subscribe()
     }
 
+    pub fn subscribe_to_chain_pulls(&mut self) -> Subscription<ChainPullRequest> {
+        self.chain_pulls.subscribe()
+    }
+
     pub fn subscribe_to_messages(&mut self) -> Subscription<Message> {
         self.messages.subscribe()
     }
@@ -186,8 +204,13 @@ impl PeerComms {
 ///
 /// This map object uses internal locking and is shared between
 /// all network connection tasks.
+///
+/// The map also keeps track of the peers' misbehaviours: the peers
+/// that get banned are removed from the map, which closes their
+/// connection, and cannot be added back until their ban expires.
 pub struct PeerMap {
     mutex: Mutex<HashMap<topology::NodeId, PeerComms>>,
+    misbehaviours: Mutex<Misbehaviours>,
     logger: Logger,
 }
 
@@ -202,18 +225,56 @@ impl PeerMap {
     pub fn new(logger: Logger) -> Self {
         PeerMap {
             mutex: Mutex::new(HashMap::new()),
+            misbehaviours: Mutex::new(Misbehaviours::new()),
             logger,
         }
     }
 
+    /// add the communication handles of a newly connected peer.
+    ///
+    /// The handles of a banned peer are dropped, closing the connection.
     pub fn insert_peer(&self, id: topology::NodeId, handles: PeerComms) {
+        if self.is_banned(&id) {
+            info!(self.logger, "refusing connection with banned peer {}", id);
+            return;
+        }
         let mut map = self.mutex.lock().unwrap();
         map.insert(id, handles);
     }
 
+    /// check if the given peer is currently banned
+    pub fn is_banned(&self, id: &topology::NodeId) -> bool {
+        self.misbehaviours.lock().unwrap().is_banned(id)
+    }
+
+    /// increase the misbehaviour score of the peer, the peer is
+    /// disconnected if it gets banned.
+    pub fn report_misbehaviour(&self, id: topology::NodeId, misbehaviour: Misbehaviour) {
+        let verdict = self.misbehaviours.lock().unwrap().report(id, misbehaviour);
+        match verdict {
+            Verdict::Penalized { score } => {
+                info!(
+                    self.logger,
+                    "peer {} misbehaved: {}", id, misbehaviour;
+                    "score" => score,
+                );
+            }
+            Verdict::Banned => {
+                warn!(
+                    self.logger,
+                    "peer {} misbehaved: {}, banning it", id, misbehaviour
+                );
+                // dropping the communication handles closes the
+                // connection with the peer
+                self.mutex.lock().unwrap().remove(&id);
+            }
+        }
+    }
+
     pub fn subscribe_to_block_events(&self, id: topology::NodeId) -> BlockEventSubscription {
         let mut map = self.mutex.lock().unwrap();
-        let handles = ensure_peer_comms(&mut map, id);
+        let mut detached = PeerComms::new();
+        let handles = self.peer_comms_or_detached(&mut map, id, &mut detached);
         let announce_events: BlockEventAnnounceStream = handles
             .block_announcements
             .subscribe()
@@ -227,7 +288,8 @@ impl PeerMap {
 
     pub fn subscribe_to_messages(&self, id: topology::NodeId) -> Subscription<Message> {
         let mut map = self.mutex.lock().unwrap();
-        let handles = ensure_peer_comms(&mut map, id);
+        let mut detached = PeerComms::new();
+        let handles = self.peer_comms_or_detached(&mut map, id, &mut detached);
         handles.messages.subscribe()
     }
 
@@ -236,10 +298,28 @@ impl PeerMap {
         id: topology::NodeId,
     ) -> Subscription<Gossip<topology::Node>> {
         let mut map = self.mutex.lock().unwrap();
-        let handles = ensure_peer_comms(&mut map, id);
+        let mut detached = PeerComms::new();
+        let handles = self.peer_comms_or_detached(&mut map, id, &mut detached);
         handles.gossip.subscribe()
     }
 
+    // Get the communication handles of the peer, or the given detached
+    // handles if the peer is banned: the subscriptions made on detached
+    // handles are terminated as soon as the handles are dropped.
+    fn peer_comms_or_detached<'a>(
+        &self,
+        map: &'a mut HashMap<topology::NodeId, PeerComms>,
+        id: topology::NodeId,
+        detached: &'a mut PeerComms,
+    ) -> &'a mut PeerComms {
+        if self.is_banned(&id) {
+            info!(self.logger, "refusing subscription from banned peer {}", id);
+            detached
+        } else {
+            ensure_peer_comms(map, id)
+        }
+    }
+
     fn propagate_with<T, F>(
         &self,
         nodes: Vec<topology::Node>,
@@ -346,4 +426,42 @@ impl PeerMap {
             }
         }
     }
+
+    /// ask a peer for the headers of the chain ending at `to`.
+    ///
+    /// If `node_id` is not given, or the peer is not connected anymore,
+    /// the request is sent to any of the connected peers.
+    pub fn pull_headers(
+        &self,
+        node_id: Option<topology::NodeId>,
+        from: Vec<HeaderHash>,
+        to: HeaderHash,
+    ) {
+        let mut map = self.mutex.lock().unwrap();
+        let node_id = match node_id {
+            Some(node_id) if map.contains_key(&node_id) => node_id,
+            _ => match map.keys().next() {
+                Some(node_id) => *node_id,
+                None => {
+                    warn!(
+                        self.logger,
+                        "no peer available to pull the headers to {} from", to
+                    );
+                    return;
+                }
+            },
+        };
+        let comms = map.get_mut(&node_id).unwrap();
+        comms
+            .chain_pulls
+            .try_send(ChainPullRequest { from, to })
+            .unwrap_or_else(|e| {
+                warn!(
+                    self.logger,
+                    "pulling headers from {} failed: {:?}",
+                    node_id,
+                    e.kind()
+                );
+            });
+    }
 }
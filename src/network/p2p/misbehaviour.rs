@@ -0,0 +1,241 @@
+//! Tracking of the peers' misbehaviours.
+//!
+//! Every time a peer sends us something invalid (a block failing the
+//! consensus verification, a block that does not connect to the
+//! blockchain...) its misbehaviour score is increased by the
+//! [`penalty`] of the misbehaviour. The score decreases with time so
+//! an occasional fault (a peer whose clock is slightly off, a block
+//! that crossed a rollback) is forgiven.
+//!
+//! Once the score of a peer reaches [`BAN_THRESHOLD`] the peer is
+//! disconnected and banned for [`BAN_DURATION`]: we neither accept
+//! its subscriptions nor connect to it until the ban expires.
+//!
+//! [`penalty`]: ./enum.Misbehaviour.html#method.penalty
+//! [`BAN_THRESHOLD`]: ./constant.BAN_THRESHOLD.html
+//! [`BAN_DURATION`]: ./constant.BAN_DURATION.html
+
+use super::topology::NodeId;
+use std::{
+    collections::HashMap,
+    fmt,
+    time::{Duration, Instant},
+};
+
+/// misbehaviour score at which a peer is banned
+pub const BAN_THRESHOLD: u32 = 100;
+/// how long a peer remains banned
+pub const BAN_DURATION: Duration = Duration::from_secs(600);
+/// the misbehaviour score of a peer decreases by one point every
+/// `SCORE_DECAY_INTERVAL`
+pub const SCORE_DECAY_INTERVAL: Duration = Duration::from_secs(6);
+
+/// the faults a peer can be penalized for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Misbehaviour {
+    /// the peer sent a block or a header that failed the verification
+    InvalidBlock,
+    /// the peer sent a block that is not connected to our blockchain
+    DisconnectedBlock,
+    /// the peer sent a block dated too far from the current time
+    ImplausibleDate,
+    /// the peer sent more unconnected blocks than it is allowed to
+    TooManyUnconnectedBlocks,
+}
+
+impl Misbehaviour {
+    /// the number of points added to the peer's misbehaviour score
+    pub fn penalty(self) -> u32 {
+        match self {
+            Misbehaviour::InvalidBlock => 50,
+            Misbehaviour::DisconnectedBlock => 5,
+            Misbehaviour::ImplausibleDate => 10,
+            Misbehaviour::TooManyUnconnectedBlocks => 20,
+        }
+    }
+}
+
+impl fmt::Display for Misbehaviour {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Misbehaviour::InvalidBlock => "invalid block",
+            Misbehaviour::DisconnectedBlock => "disconnected block",
+            Misbehaviour::ImplausibleDate => "implausible block date",
+            Misbehaviour::TooManyUnconnectedBlocks => "too many unconnected blocks",
+        };
+        f.write_str(s)
+    }
+}
+
+/// the outcome of reporting a misbehaviour
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    /// the peer's score has been increased, it is still below the
+    /// ban threshold
+    Penalized { score: u32 },
+    /// the peer's score reached the threshold, it is now banned
+    Banned,
+}
+
+struct Score {
+    points: u32,
+    updated_at: Instant,
+}
+
+impl Score {
+    fn decayed(&self, now: Instant) -> u32 {
+        let elapsed = now.duration_since(self.updated_at);
+        let forgiven = elapsed.as_secs() / SCORE_DECAY_INTERVAL.as_secs();
+        self.points.saturating_sub(forgiven as u32)
+    }
+}
+
+/// the misbehaviour scores and the bans of the peers
+#[derive(Default)]
+pub struct Misbehaviours {
+    scores: HashMap<NodeId, Score>,
+    bans: HashMap<NodeId, Instant>,
+}
+
+impl Misbehaviours {
+    pub fn new() -> Self {
+        Misbehaviours::default()
+    }
+
+    /// increase the score of the peer, banning it if the score
+    /// reaches `BAN_THRESHOLD`.
+    pub fn report(&mut self, node_id: NodeId, misbehaviour: Misbehaviour) -> Verdict {
+        self.report_at(node_id, misbehaviour, Instant::now())
+    }
+
+    fn report_at(&mut self, node_id: NodeId, misbehaviour: Misbehaviour, now: Instant) -> Verdict {
+        self.purge(now);
+
+        let points = self
+            .scores
+            .get(&node_id)
+            .map_or(0, |score| score.decayed(now))
+            + misbehaviour.penalty();
+
+        if points >= BAN_THRESHOLD {
+            self.scores.remove(&node_id);
+            self.bans.insert(node_id, now + BAN_DURATION);
+            Verdict::Banned
+        } else {
+            self.scores.insert(
+                node_id,
+                Score {
+                    points,
+                    updated_at: now,
+                },
+            );
+            Verdict::Penalized { score: points }
+        }
+    }
+
+    /// check if the given peer is currently banned
+    pub fn is_banned(&self, node_id: &NodeId) -> bool {
+        self.is_banned_at(node_id, Instant::now())
+    }
+
+    fn is_banned_at(&self, node_id: &NodeId, now: Instant) -> bool {
+        self.bans.get(node_id).map_or(false, |until| *until > now)
+    }
+
+    // forget the expired bans and the scores that have decayed to 0
+    fn purge(&mut self, now: Instant) {
+        self.bans.retain(|_, until| *until > now);
+        self.scores.retain(|_, score| score.decayed(now) > 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn after(now: Instant, decay_intervals: u32) -> Instant {
+        now + SCORE_DECAY_INTERVAL * decay_intervals
+    }
+
+    #[test]
+    fn penalties_add_up() {
+        let mut misbehaviours = Misbehaviours::new();
+        let node_id = NodeId::generate();
+        let now = Instant::now();
+
+        let verdict = misbehaviours.report_at(node_id, Misbehaviour::DisconnectedBlock, now);
+        assert_eq!(verdict, Verdict::Penalized { score: 5 });
+        let verdict = misbehaviours.report_at(node_id, Misbehaviour::ImplausibleDate, now);
+        assert_eq!(verdict, Verdict::Penalized { score: 15 });
+
+        // the other peers are not affected
+        let verdict =
+            misbehaviours.report_at(NodeId::generate(), Misbehaviour::ImplausibleDate, now);
+        assert_eq!(verdict, Verdict::Penalized { score: 10 });
+    }
+
+    #[test]
+    fn score_decays_with_time() {
+        let mut misbehaviours = Misbehaviours::new();
+        let node_id = NodeId::generate();
+        let now = Instant::now();
+
+        misbehaviours.report_at(node_id, Misbehaviour::TooManyUnconnectedBlocks, now);
+        let verdict =
+            misbehaviours.report_at(node_id, Misbehaviour::DisconnectedBlock, after(now, 12));
+        assert_eq!(verdict, Verdict::Penalized { score: 13 });
+
+        // a fully decayed score is forgotten
+        let verdict =
+            misbehaviours.report_at(node_id, Misbehaviour::DisconnectedBlock, after(now, 100));
+        assert_eq!(verdict, Verdict::Penalized { score: 5 });
+        misbehaviours.purge(after(now, 106));
+        assert!(misbehaviours.scores.is_empty());
+    }
+
+    #[test]
+    fn peer_is_banned_at_the_threshold() {
+        let mut misbehaviours = Misbehaviours::new();
+        let node_id = NodeId::generate();
+        let now = Instant::now();
+
+        let verdict = misbehaviours.report_at(node_id, Misbehaviour::InvalidBlock, now);
+        assert_eq!(verdict, Verdict::Penalized { score: 50 });
+        assert!(!misbehaviours.is_banned_at(&node_id, now));
+
+        let verdict = misbehaviours.report_at(node_id, Misbehaviour::InvalidBlock, now);
+        assert_eq!(verdict, Verdict::Banned);
+        assert!(misbehaviours.is_banned_at(&node_id, now));
+        assert!(!misbehaviours.is_banned_at(&NodeId::generate(), now));
+    }
+
+    #[test]
+    fn decay_delays_the_ban() {
+        let mut misbehaviours = Misbehaviours::new();
+        let node_id = NodeId::generate();
+        let now = Instant::now();
+
+        misbehaviours.report_at(node_id, Misbehaviour::InvalidBlock, now);
+        let verdict = misbehaviours.report_at(node_id, Misbehaviour::InvalidBlock, after(now, 1));
+        assert_eq!(verdict, Verdict::Penalized { score: 99 });
+        assert!(!misbehaviours.is_banned_at(&node_id, after(now, 1)));
+    }
+
+    #[test]
+    fn ban_expires() {
+        let mut misbehaviours = Misbehaviours::new();
+        let node_id = NodeId::generate();
+        let now = Instant::now();
+
+        misbehaviours.report_at(node_id, Misbehaviour::InvalidBlock, now);
+        misbehaviours.report_at(node_id, Misbehaviour::InvalidBlock, now);
+        assert!(misbehaviours.is_banned_at(&node_id, now + BAN_DURATION - Duration::from_secs(1)));
+        assert!(!misbehaviours.is_banned_at(&node_id, now + BAN_DURATION));
+
+        // the peer starts afresh once the ban is over
+        let verdict =
+            misbehaviours.report_at(node_id, Misbehaviour::DisconnectedBlock, now + BAN_DURATION);
+        assert_eq!(verdict, Verdict::Penalized { score: 5 });
+        assert!(misbehaviours.bans.is_empty());
+    }
+}
@@ -1,2 +1,3 @@
 pub mod comm;
+pub mod misbehaviour;
 pub mod topology;
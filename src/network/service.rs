@@ -1,4 +1,5 @@
 use super::{
+    handshake::{Handshake, HandshakeError},
     p2p::{
         comm::{BlockEventSubscription, Subscription},
         topology,
@@ -7,7 +8,7 @@ use super::{
 };
 use crate::blockcfg::{Block, BlockDate, Header, HeaderHash, Message, MessageId};
 use crate::intercom::{
-    self, stream_reply, unary_reply, BlockMsg, ClientMsg, ReplyFuture, ReplyStream,
+    self, stream_reply, unary_reply, BlockMsg, ClientMsg, ReplyFuture, ReplyStream, TransactionMsg,
 };
 use futures::future::{self, FutureResult};
 use futures::prelude::*;
@@ -22,20 +23,67 @@ use network_core::{
     },
 };
 use slog::Logger;
+use std::net::SocketAddr;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+};
 
+/// the node service serving the connection of a peer
 #[derive(Clone)]
 pub struct NodeService {
     channels: Channels,
     global_state: GlobalStateR,
+    /// the address the peer connects from, the fragments it sends are
+    /// recorded as coming from it
+    peer_addr: SocketAddr,
+    /// the node id the peer subscribed with, the blocks it uploads are
+    /// attributed to it. Shared by the clones serving the connection.
+    subscriber: Arc<Mutex<Option<topology::NodeId>>>,
+    /// whether the peer has done the handshake, the other requests are
+    /// refused until then. Shared by the clones serving the connection.
+    handshake_done: Arc<AtomicBool>,
     logger: Logger,
 }
 
 impl NodeService {
-    pub fn new(channels: Channels, global_state: GlobalStateR) -> Self {
+    pub fn new(channels: Channels, global_state: GlobalStateR, peer_addr: SocketAddr) -> Self {
         NodeService {
             channels,
-            logger: global_state.logger().new(o!(::log::KEY_TASK => "server")),
+            logger: global_state
+                .logger()
+                .new(o!(::log::KEY_TASK => "server", "peer_addr" => peer_addr)),
             global_state,
+            peer_addr,
+            subscriber: Arc::new(Mutex::new(None)),
+            handshake_done: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// refuse the request if the peer has not done the handshake, see
+    /// the [handshake module](../handshake/index.html).
+    fn check_handshake(&self) -> Result<(), intercom::Error> {
+        if self.handshake_done.load(Ordering::SeqCst) {
+            Ok(())
+        } else {
+            warn!(
+                self.logger(),
+                "refusing a request of a peer without handshake"
+            );
+            Err(intercom::Error::failed_precondition(
+                HandshakeError::NotDone,
+            ))
+        }
+    }
+
+    /// pass the query to the client task. If the task cannot take it,
+    /// the reply handle is dropped and the peer gets an error.
+    fn query_client(&mut self, msg: ClientMsg) {
+        if let Err(e) = self.channels.client_box.try_send(msg) {
+            warn!(
+                self.logger(),
+                "cannot pass the query to the client task: {}", e
+            );
         }
     }
 
@@ -54,8 +102,7 @@ impl Node for NodeService {
     }
 
     fn content_service(&mut self) -> Option<&mut Self::ContentService> {
-        // Not implemented yet
-        None
+        Some(self)
     }
 
     fn gossip_service(&mut self) -> Option<&mut Self::GossipService> {
@@ -97,60 +144,111 @@ impl BlockService for NodeService {
 
     fn tip(&mut self) -> Self::TipFuture {
         let (handle, future) = unary_reply(self.logger().clone());
-        self.channels
-            .client_box
-            .send_to(ClientMsg::GetBlockTip(handle));
+        if let Err(e) = self.check_handshake() {
+            handle.reply_error(e);
+            return future;
+        }
+        self.query_client(ClientMsg::GetBlockTip(handle));
         future
     }
 
     fn pull_blocks_to_tip(&mut self, from: &[Self::BlockId]) -> Self::PullBlocksFuture {
+        if let Err(e) = self.check_handshake() {
+            return future::err(e.into());
+        }
         let (handle, stream) = stream_reply(self.logger().clone());
-        self.channels
-            .client_box
-            .send_to(ClientMsg::PullBlocksToTip(from.into(), handle));
+        self.query_client(ClientMsg::PullBlocksToTip(from.into(), handle));
         future::ok(stream)
     }
 
     fn get_blocks(&mut self, ids: &[Self::BlockId]) -> Self::GetBlocksFuture {
+        if let Err(e) = self.check_handshake() {
+            return future::err(e.into());
+        }
         let (handle, stream) = stream_reply(self.logger().clone());
-        self.channels
-            .client_box
-            .send_to(ClientMsg::GetBlocks(ids.into(), handle));
+        self.query_client(ClientMsg::GetBlocks(ids.into(), handle));
         future::ok(stream)
     }
 
     fn get_headers(&mut self, ids: &[Self::BlockId]) -> Self::GetHeadersFuture {
+        if let Err(e) = self.check_handshake() {
+            return future::err(e.into());
+        }
         let (handle, stream) = stream_reply(self.logger().clone());
-        self.channels
-            .client_box
-            .send_to(ClientMsg::GetHeaders(ids.into(), handle));
+        self.query_client(ClientMsg::GetHeaders(ids.into(), handle));
         future::ok(stream)
     }
 
     fn pull_blocks_to(
         &mut self,
-        _from: &[Self::BlockId],
-        _to: &Self::BlockId,
+        from: &[Self::BlockId],
+        to: &Self::BlockId,
     ) -> Self::PullBlocksFuture {
-        unimplemented!()
+        if let Err(e) = self.check_handshake() {
+            return future::err(e.into());
+        }
+        let (handle, stream) = stream_reply(self.logger().clone());
+        self.query_client(ClientMsg::PullBlocks(from.into(), *to, handle));
+        future::ok(stream)
     }
 
     fn pull_headers_to(
         &mut self,
-        _from: &[Self::BlockId],
-        _to: &Self::BlockId,
+        from: &[Self::BlockId],
+        to: &Self::BlockId,
     ) -> Self::PullHeadersFuture {
-        unimplemented!()
+        let (handle, stream) = stream_reply(self.logger().clone());
+        if let Some(peer) = Handshake::from_request(from, to) {
+            if let Err(err) = self.global_state.handshake.check(&peer) {
+                warn!(self.logger(), "refusing inbound peer: {}", err;
+                    "version" => peer.version.to_string(),
+                    "min_version" => peer.min_version.to_string(),
+                    "features" => peer.features.to_string(),
+                );
+                self.global_state.stats_counter.add_peer_refused_cnt(1);
+                return future::err(core_error::Error::new(
+                    core_error::Code::FailedPrecondition,
+                    err,
+                ));
+            }
+            self.handshake_done.store(true, Ordering::SeqCst);
+            // answer the header of our block0
+            self.query_client(ClientMsg::PullHeaders(Vec::new(), *to, handle));
+            return future::ok(stream);
+        }
+        if let Err(e) = self.check_handshake() {
+            return future::err(e.into());
+        }
+        self.query_client(ClientMsg::PullHeaders(from.into(), *to, handle));
+        future::ok(stream)
     }
 
-    fn pull_headers_to_tip(&mut self, _from: &[Self::BlockId]) -> Self::PullHeadersFuture {
-        unimplemented!()
+    fn pull_headers_to_tip(&mut self, from: &[Self::BlockId]) -> Self::PullHeadersFuture {
+        if let Err(e) = self.check_handshake() {
+            return future::err(e.into());
+        }
+        let (handle, stream) = stream_reply(self.logger().clone());
+        self.query_client(ClientMsg::PullHeadersToTip(from.into(), handle));
+        future::ok(stream)
     }
 
     fn on_uploaded_block(&mut self, block: Block) -> Self::OnUploadedBlockFuture {
+        if let Err(e) = self.check_handshake() {
+            return future::err(e.into());
+        }
+        // the blocks are uploaded in answer to the solicitations sent
+        // through the peer's block subscription, so the peer is known
+        // unless it uploads blocks without having subscribed.
+        let node_id = *self.subscriber.lock().unwrap();
+        if node_id.is_none() {
+            debug!(
+                self.logger(),
+                "block uploaded by a peer without block subscription"
+            );
+        }
         self.channels
             .block_box
-            .try_send(BlockMsg::NetworkBlock(block))
+            .try_send(BlockMsg::NetworkBlock(block, node_id))
             .unwrap();
         future::ok(())
     }
@@ -163,6 +261,12 @@ impl BlockService for NodeService {
     where
         In: Stream<Item = Self::Header, Error = core_error::Error> + Send + 'static,
     {
+        if let Err(e) = self.check_handshake() {
+            self.global_state.stats_counter.add_peer_refused_cnt(1);
+            return future::err(e.into());
+        }
+        *self.subscriber.lock().unwrap() = Some(subscriber);
+
         subscription::process_block_announcements(
             inbound,
             subscriber,
@@ -184,30 +288,56 @@ impl ContentService for NodeService {
     type ProposeTransactionsFuture =
         ReplyFuture<ProposeTransactionsResponse<MessageId>, core_error::Error>;
     type GetMessagesStream = ReplyStream<Self::Message, core_error::Error>;
-    type GetMessagesFuture = ReplyFuture<Self::GetMessagesStream, core_error::Error>;
+    type GetMessagesFuture = FutureResult<Self::GetMessagesStream, core_error::Error>;
     type MessageSubscription = Subscription<Message>;
     type MessageSubscriptionFuture = FutureResult<Self::MessageSubscription, core_error::Error>;
 
-    fn propose_transactions(
-        &mut self,
-        _ids: &[Self::MessageId],
-    ) -> Self::ProposeTransactionsFuture {
-        unimplemented!()
+    fn propose_transactions(&mut self, ids: &[Self::MessageId]) -> Self::ProposeTransactionsFuture {
+        let (handle, future) = unary_reply(self.logger().clone());
+        if let Err(e) = self.check_handshake() {
+            handle.reply_error(e);
+            return future;
+        }
+        self.channels
+            .transaction_box
+            .try_send(TransactionMsg::ProposeTransaction(ids.into(), handle))
+            .unwrap();
+        future
     }
 
-    fn get_messages(&mut self, _ids: &[Self::MessageId]) -> Self::GetMessagesFuture {
-        unimplemented!()
+    fn get_messages(&mut self, ids: &[Self::MessageId]) -> Self::GetMessagesFuture {
+        if let Err(e) = self.check_handshake() {
+            return future::err(e.into());
+        }
+        let (handle, stream) = stream_reply(self.logger().clone());
+        self.channels
+            .transaction_box
+            .try_send(TransactionMsg::GetTransactions(ids.into(), handle))
+            .unwrap();
+        future::ok(stream)
     }
 
     fn message_subscription<S>(
         &mut self,
         subscriber: Self::NodeId,
-        _inbound: S,
+        inbound: S,
     ) -> Self::MessageSubscriptionFuture
     where
-        S: Stream<Item = Self::Message, Error = core_error::Error>,
+        S: Stream<Item = Self::Message, Error = core_error::Error> + Send + 'static,
     {
-        unimplemented!()
+        if let Err(e) = self.check_handshake() {
+            return future::err(e.into());
+        }
+        subscription::process_messages(
+            inbound,
+            subscriber,
+            self.peer_addr,
+            self.channels.transaction_box.clone(),
+            self.logger().clone(),
+        );
+
+        let subscription = self.global_state.peers.subscribe_to_messages(subscriber);
+        future::ok(subscription)
     }
 }
 
@@ -224,6 +354,9 @@ impl GossipService for NodeService {
     where
         In: Stream<Item = Gossip<Self::Node>, Error = core_error::Error> + Send + 'static,
     {
+        if let Err(e) = self.check_handshake() {
+            return future::err(e.into());
+        }
         subscription::process_gossip(inbound, self.global_state.clone(), self.logger().clone());
 
         let subscription = self.global_state.peers.subscribe_to_gossip(subscriber);
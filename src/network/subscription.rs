@@ -2,10 +2,16 @@ use super::{
     p2p::topology::{Node, NodeId},
     GlobalStateR,
 };
-use crate::{blockcfg::Header, intercom::BlockMsg, utils::async_msg::MessageBox};
+use crate::{
+    blockcfg::{Header, Message},
+    fragment::Origin,
+    intercom::{BlockMsg, TransactionMsg},
+    utils::async_msg::MessageBox,
+};
 use futures::prelude::*;
 use network_core::{error as core_error, gossip::Gossip};
 use slog::Logger;
+use std::net::SocketAddr;
 
 pub fn process_block_announcements<S>(
     inbound: S,
@@ -30,6 +36,34 @@ where
     )
 }
 
+pub fn process_messages<S>(
+    inbound: S,
+    node_id: NodeId,
+    addr: SocketAddr,
+    mut transaction_box: MessageBox<TransactionMsg>,
+    logger: Logger,
+) -> tokio::executor::Spawn
+where
+    S: Stream<Item = Message, Error = core_error::Error> + Send + 'static,
+{
+    tokio::spawn(
+        inbound
+            .for_each(move |message| {
+                transaction_box
+                    .try_send(TransactionMsg::SendTransaction(
+                        Origin::Network { node_id, addr },
+                        vec![message],
+                        None,
+                    ))
+                    .unwrap();
+                Ok(())
+            })
+            .map_err(move |err| {
+                info!(logger, "message subscription stream failure: {:?}", err);
+            }),
+    )
+}
+
 pub fn process_gossip<S>(inbound: S, state: GlobalStateR, logger: Logger) -> tokio::executor::Spawn
 where
     S: Stream<Item = Gossip<Node>, Error = core_error::Error> + Send + 'static,
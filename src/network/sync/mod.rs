@@ -0,0 +1,678 @@
+//! Header-first synchronization of the blockchain with several peers.
+//!
+//! The synchronization is done in rounds until our blockchain has
+//! caught up with the peers:
+//!
+//! 1. the chain of headers, from our most recent checkpoint known by
+//!    the peer up to the peer's tip, is pulled from one of the peers.
+//!    The headers are verified as they are received: every header must
+//!    be connected to the previous one and pass the leadership
+//!    verification. The leadership of an epoch is only known once the
+//!    blocks preceding it are applied, so the chain is cut at the first
+//!    header whose leadership cannot be built yet: the following headers
+//!    are pulled again in the next round;
+//! 2. the blocks of the chain are split in ranges that are downloaded
+//!    in parallel from the different peers. If a peer fails to provide
+//!    a range, the range is requested to the next peer;
+//! 3. the ranges are applied to the blockchain in the order of the
+//!    chain, as soon as they are received.
+//!
+//! Any peer failing to provide a valid header chain is skipped in favor
+//! of the next one.
+//!
+//! The progress of the synchronization is reported in a [`SyncProgress`].
+//!
+//! [`SyncProgress`]: ./struct.SyncProgress.html
+
+mod progress;
+
+pub use self::progress::{SyncProgress, SyncProgressReport, SyncStatus};
+
+use crate::{
+    blockcfg::{Block, Header, HeaderHash},
+    blockchain::{handle_block, Blockchain, BlockchainR, HandledBlock},
+};
+use chain_core::property::{Block as _, Header as _};
+use chain_impl_mockchain::leadership::{self, Verification};
+use futures::{
+    future::{self, Either, Loop},
+    prelude::*,
+    stream,
+};
+use network_core::client::block::BlockService;
+use slog::Logger;
+use std::{
+    fmt,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+/// number of blocks requested to a peer at once
+const BLOCKS_PER_RANGE: usize = 64;
+/// number of ranges being downloaded at the same time
+const PARALLEL_RANGES: usize = 4;
+
+custom_error! {pub HeaderChainError
+    NotConnected { parent: HeaderHash } = "the header chain starts from block {parent} which is unknown",
+    Broken { header: HeaderHash } = "header {header} does not follow the previous header of the chain",
+    Leadership { header: HeaderHash, source: leadership::Error } = "header {header} failed the leadership verification: {source}",
+    NoLeadership { header: HeaderHash } = "the leadership of header {header} cannot be built, its parent's state is not known",
+    Storage { source: chain_storage::error::Error } = "Error in the blockchain storage: {source}",
+}
+
+/// why a peer did not provide a valid header chain
+enum PullError<E> {
+    Request(E),
+    Chain(HeaderChainError),
+}
+
+/// a peer to synchronize the blockchain from
+pub struct SyncPeer<S> {
+    address: SocketAddr,
+    service: Arc<Mutex<S>>,
+}
+
+impl<S> Clone for SyncPeer<S> {
+    fn clone(&self) -> Self {
+        SyncPeer {
+            address: self.address,
+            service: self.service.clone(),
+        }
+    }
+}
+
+impl<S> SyncPeer<S> {
+    pub fn new(address: SocketAddr, service: S) -> Self {
+        SyncPeer {
+            address,
+            service: Arc::new(Mutex::new(service)),
+        }
+    }
+}
+
+/// synchronize the blockchain from the given peers, see the
+/// [module documentation](./index.html).
+///
+/// The future resolves once no peer has any block we don't know about,
+/// or fails if no peer could provide a valid chain or the blocks of it.
+/// In the latter case, the blocks applied so far are kept: calling this
+/// function again resumes the synchronization from there.
+pub fn synchronize<S>(
+    peers: Vec<SyncPeer<S>>,
+    blockchain: BlockchainR,
+    progress: SyncProgress,
+    logger: Logger,
+) -> impl Future<Item = (), Error = ()>
+where
+    S: BlockService<Block = Block, Header = Header>,
+{
+    let chain_length = match blockchain.lock_read().get_block_tip() {
+        Ok((tip, _)) => u32::from(tip.chain_length()),
+        Err(e) => {
+            error!(logger, "cannot read the blockchain's tip: {}", e);
+            return Either::A(future::err(()));
+        }
+    };
+    progress.start(chain_length);
+
+    let peers = Arc::new(peers);
+    Either::B(future::loop_fn((), move |()| {
+        let peers = peers.clone();
+        let blockchain = blockchain.clone();
+        let progress = progress.clone();
+        let logger = logger.clone();
+        pull_header_chain(peers.clone(), blockchain.clone(), logger.clone()).and_then(
+            move |headers| match headers.last().map(|tip| (tip.id(), tip.chain_length())) {
+                None => {
+                    info!(logger, "blockchain is synchronized with the peers");
+                    progress.complete();
+                    Either::A(future::ok(Loop::Break(())))
+                }
+                Some((tip, tip_chain_length)) => {
+                    info!(
+                        logger,
+                        "synchronizing {} blocks up to {}",
+                        headers.len(),
+                        tip
+                    );
+                    progress.set_target(tip, u32::from(tip_chain_length));
+                    Either::B(
+                        download_and_apply(peers, blockchain, headers, progress, logger)
+                            .map(|()| Loop::Continue(())),
+                    )
+                }
+            },
+        )
+    }))
+}
+
+/// pull the chain of headers we don't have yet, trying the peers in
+/// turn until one provides a valid chain.
+fn pull_header_chain<S>(
+    peers: Arc<Vec<SyncPeer<S>>>,
+    blockchain: BlockchainR,
+    logger: Logger,
+) -> impl Future<Item = Vec<Header>, Error = ()>
+where
+    S: BlockService<Block = Block, Header = Header>,
+{
+    let checkpoints = match blockchain.lock_read().get_checkpoints() {
+        Ok(checkpoints) => checkpoints,
+        Err(e) => {
+            error!(logger, "cannot compute the chain checkpoints: {}", e);
+            return Either::A(future::err(()));
+        }
+    };
+
+    Either::B(future::loop_fn(0, move |index| {
+        let peer = match peers.get(index) {
+            Some(peer) => peer.clone(),
+            None => {
+                error!(logger, "no peer provided a valid header chain");
+                return Either::A(future::err(()));
+            }
+        };
+        let blockchain = blockchain.clone();
+        let err_logger = logger.clone();
+        let pull = peer
+            .service
+            .lock()
+            .unwrap()
+            .pull_headers_to_tip(&checkpoints);
+        Either::B(
+            pull.map_err(PullError::Request)
+                .and_then(move |headers| check_header_stream(headers, blockchain))
+                .then(move |res| match res {
+                    Ok(headers) => Ok(Loop::Break(headers)),
+                    Err(PullError::Request(e)) => {
+                        warn!(
+                            err_logger,
+                            "PullHeadersToTip request to {} failed: {:?}", peer.address, e
+                        );
+                        Ok(Loop::Continue(index + 1))
+                    }
+                    Err(PullError::Chain(e)) => {
+                        warn!(
+                            err_logger,
+                            "invalid header chain received from {}: {}", peer.address, e
+                        );
+                        Ok(Loop::Continue(index + 1))
+                    }
+                }),
+        )
+    }))
+}
+
+/// verify the headers as they are received, and resolve with the ones
+/// whose block is not in the storage yet. The stream is dropped as soon
+/// as a header is invalid or cannot be verified yet.
+fn check_header_stream<St>(
+    headers: St,
+    blockchain: BlockchainR,
+) -> impl Future<Item = Vec<Header>, Error = PullError<St::Error>>
+where
+    St: Stream<Item = Header>,
+{
+    future::loop_fn(
+        (headers, HeaderChain::new()),
+        move |(headers, mut chain)| {
+            let blockchain = blockchain.clone();
+            headers
+                .into_future()
+                .map_err(|(e, _)| PullError::Request(e))
+                .and_then(move |(header, headers)| {
+                    let header = match header {
+                        None => return Ok(Loop::Break(chain.into_missing())),
+                        Some(header) => header,
+                    };
+                    let verified = chain
+                        .push(&blockchain.lock_read(), header)
+                        .map_err(PullError::Chain)?;
+                    if verified {
+                        Ok(Loop::Continue((headers, chain)))
+                    } else {
+                        Ok(Loop::Break(chain.into_missing()))
+                    }
+                })
+        },
+    )
+}
+
+/// a chain of headers being verified
+struct HeaderChain {
+    previous: Option<Header>,
+    /// the verified headers whose block is not in the storage yet
+    missing: Vec<Header>,
+}
+
+impl HeaderChain {
+    fn new() -> Self {
+        HeaderChain {
+            previous: None,
+            missing: Vec::new(),
+        }
+    }
+
+    /// verify the next header of the chain. Returns `false` if the
+    /// leadership of the header's epoch cannot be built yet: the header
+    /// is not added and the chain ends with the previous header.
+    fn push(&mut self, blockchain: &Blockchain, header: Header) -> Result<bool, HeaderChainError> {
+        match self.previous {
+            None => {
+                let parent = header.parent_id();
+                if !blockchain.block_exists(&parent)? {
+                    return Err(HeaderChainError::NotConnected { parent });
+                }
+            }
+            Some(ref previous) => {
+                let is_next = header.parent_id() == previous.id()
+                    && u32::from(header.chain_length()) == u32::from(previous.chain_length()) + 1;
+                if !is_next {
+                    return Err(HeaderChainError::Broken {
+                        header: header.id(),
+                    });
+                }
+            }
+        }
+
+        match blockchain.get_leadership_or_build(header.date().epoch, &header.parent_id()) {
+            Some(leadership) => {
+                if let Verification::Failure(source) = leadership.verify(&header) {
+                    return Err(HeaderChainError::Leadership {
+                        header: header.id(),
+                        source,
+                    });
+                }
+            }
+            // the parent is one of the headers we have not applied yet
+            None if self.previous.is_some() => return Ok(false),
+            None => {
+                return Err(HeaderChainError::NoLeadership {
+                    header: header.id(),
+                })
+            }
+        }
+
+        if !blockchain.block_exists(&header.id())? {
+            self.missing.push(header.clone());
+        }
+        self.previous = Some(header);
+        Ok(true)
+    }
+
+    fn into_missing(self) -> Vec<Header> {
+        self.missing
+    }
+}
+
+/// download the blocks of the header chain in parallel ranges, and
+/// apply the ranges in order
+fn download_and_apply<S>(
+    peers: Arc<Vec<SyncPeer<S>>>,
+    blockchain: BlockchainR,
+    headers: Vec<Header>,
+    progress: SyncProgress,
+    logger: Logger,
+) -> impl Future<Item = (), Error = ()>
+where
+    S: BlockService<Block = Block, Header = Header>,
+{
+    let ranges = headers
+        .chunks(BLOCKS_PER_RANGE)
+        .map(|range| range.iter().map(|header| header.id()).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    let fetch_logger = logger.clone();
+
+    stream::iter_ok(ranges.into_iter().enumerate())
+        .map(move |(index, ids)| fetch_range(peers.clone(), index, ids, fetch_logger.clone()))
+        .buffered(PARALLEL_RANGES)
+        .for_each(move |blocks| apply_range(&blockchain, blocks, &progress, &logger))
+}
+
+/// fetch the given blocks, starting with the peer at `index` and
+/// failing over to the next peers.
+fn fetch_range<S>(
+    peers: Arc<Vec<SyncPeer<S>>>,
+    index: usize,
+    ids: Vec<HeaderHash>,
+    logger: Logger,
+) -> impl Future<Item = Vec<Block>, Error = ()>
+where
+    S: BlockService<Block = Block, Header = Header>,
+{
+    fetch_with_failover(peers, index, ids, logger, |peer, ids| {
+        let get = peer.service.lock().unwrap().get_blocks(ids);
+        get.and_then(|stream| stream.collect())
+    })
+}
+
+/// the failover logic of `fetch_range`, the blocks are requested to a
+/// peer with the given function.
+fn fetch_with_failover<S, F, Fut>(
+    peers: Arc<Vec<SyncPeer<S>>>,
+    index: usize,
+    ids: Vec<HeaderHash>,
+    logger: Logger,
+    get_blocks: F,
+) -> impl Future<Item = Vec<Block>, Error = ()>
+where
+    F: Fn(&SyncPeer<S>, &[HeaderHash]) -> Fut,
+    Fut: Future<Item = Vec<Block>>,
+    Fut::Error: fmt::Debug,
+{
+    future::loop_fn(0, move |attempt| {
+        if attempt >= peers.len() {
+            error!(
+                logger,
+                "no peer provided the blocks {} to {}",
+                ids[0],
+                ids[ids.len() - 1]
+            );
+            return Either::A(future::err(()));
+        }
+        let peer = peers[(index + attempt) % peers.len()].clone();
+        let expected = ids.clone();
+        let err_logger = logger.clone();
+        Either::B(get_blocks(&peer, &ids).then(move |res| {
+            let blocks = match res {
+                Ok(blocks) => blocks,
+                Err(e) => {
+                    warn!(
+                        err_logger,
+                        "GetBlocks request to {} failed: {:?}", peer.address, e
+                    );
+                    return Ok(Loop::Continue(attempt + 1));
+                }
+            };
+            if blocks.iter().map(|block| block.id()).eq(expected) {
+                Ok(Loop::Break(blocks))
+            } else {
+                warn!(
+                    err_logger,
+                    "{} did not send the requested blocks", peer.address
+                );
+                Ok(Loop::Continue(attempt + 1))
+            }
+        }))
+    })
+}
+
+fn apply_range(
+    blockchain: &BlockchainR,
+    blocks: Vec<Block>,
+    progress: &SyncProgress,
+    logger: &Logger,
+) -> Result<(), ()> {
+    let mut blockchain = blockchain.lock_write();
+    for block in blocks {
+        let id = block.id();
+        match handle_block(&mut blockchain, block, None, true) {
+            Ok(HandledBlock::Acquired { header }) => {
+                progress.set_chain_length(u32::from(header.chain_length()));
+            }
+            Ok(HandledBlock::Rejected { reason }) => {
+                error!(logger, "block {} rejected: {}", id, reason);
+                return Err(());
+            }
+            Ok(HandledBlock::MissingBranchToBlock { to }) => {
+                error!(logger, "block {} is not connected, missing {}", id, to);
+                return Err(());
+            }
+            Err(e) => {
+                error!(logger, "error processing block {}: {}", id, e);
+                return Err(());
+            }
+        }
+    }
+    debug!(
+        logger,
+        "blockchain tip is now {}",
+        blockchain.get_tip().unwrap()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        blockcfg::{BlockBuilder, BlockDate},
+        blockchain::UnconnectedBlocksLimits,
+        start_up::NodeStorage,
+    };
+    use chain_addr::Discrimination;
+    use chain_core::property::{Block as _, Header as _};
+    use chain_crypto::{Ed25519Extended, KeyPair};
+    use chain_impl_mockchain::{
+        block::ConsensusVersion,
+        config::{Block0Date, ConfigParam},
+        leadership::bft::LeaderId,
+        message::{ConfigParams, Message},
+        milli::Milli,
+    };
+    use chain_storage::memory::MemoryBlockStore;
+    use rand::SeedableRng;
+    use rand_chacha::ChaChaRng;
+    use std::time::Duration;
+
+    fn leader() -> KeyPair<Ed25519Extended> {
+        KeyPair::generate(&mut ChaChaRng::from_seed([1; 32]))
+    }
+
+    fn block0() -> Block {
+        let mut params = ConfigParams::new();
+        params.push(ConfigParam::Block0Date(Block0Date(0)));
+        params.push(ConfigParam::Discrimination(Discrimination::Test));
+        params.push(ConfigParam::ConsensusVersion(ConsensusVersion::Bft));
+        params.push(ConfigParam::SlotDuration(10));
+        params.push(ConfigParam::SlotsPerEpoch(10));
+        params.push(ConfigParam::EpochStabilityDepth(10));
+        params.push(ConfigParam::AddBftLeader(LeaderId::from(
+            leader().public_key().clone(),
+        )));
+        params.push(ConfigParam::ConsensusGenesisPraosActiveSlotsCoeff(
+            Milli::from_millis(500),
+        ));
+        params.push(ConfigParam::KESUpdateSpeed(3600));
+        let mut builder = BlockBuilder::new();
+        builder.message(Message::Initial(params));
+        builder.make_genesis_block()
+    }
+
+    /// a BFT blockchain made of the block0 only
+    fn setup() -> (Blockchain, Block) {
+        let block0 = block0();
+        let storage: NodeStorage = Box::new(MemoryBlockStore::new());
+        let (epoch_event, _) = tokio::sync::mpsc::channel(1);
+        let limits = UnconnectedBlocksLimits {
+            max_age: Duration::from_secs(600),
+            max_blocks: 16,
+            max_blocks_per_peer: 16,
+        };
+        let blockchain = Blockchain::load(
+            block0.clone(),
+            storage,
+            None,
+            Duration::from_secs(10),
+            limits,
+            None,
+            epoch_event,
+            &Logger::root(slog::Discard, o!()),
+        )
+        .unwrap();
+        (blockchain, block0)
+    }
+
+    fn builder(parent: &Block, date: BlockDate) -> BlockBuilder {
+        let mut builder = BlockBuilder::new();
+        builder
+            .date(date)
+            .parent(parent.id())
+            .chain_length(parent.chain_length().next());
+        builder
+    }
+
+    /// a block of the first epoch signed by the BFT leader
+    fn child(parent: &Block, slot_id: u32) -> Block {
+        builder(parent, BlockDate { epoch: 0, slot_id }).make_bft_block(&leader().private_key())
+    }
+
+    fn push_all(
+        blockchain: &Blockchain,
+        blocks: &[&Block],
+    ) -> Result<(bool, Vec<HeaderHash>), HeaderChainError> {
+        let mut chain = HeaderChain::new();
+        let mut verified = true;
+        for block in blocks {
+            verified = chain.push(blockchain, block.header())?;
+            if !verified {
+                break;
+            }
+        }
+        let missing = chain.into_missing().iter().map(|h| h.id()).collect();
+        Ok((verified, missing))
+    }
+
+    #[test]
+    fn header_chain_is_missing_its_blocks() {
+        let (blockchain, block0) = setup();
+        let a1 = child(&block0, 1);
+        let a2 = child(&a1, 2);
+        let (verified, missing) = push_all(&blockchain, &[&a1, &a2]).unwrap();
+        assert!(verified);
+        assert_eq!(missing, vec![a1.id(), a2.id()]);
+    }
+
+    #[test]
+    fn header_chain_not_connected() {
+        let (blockchain, block0) = setup();
+        let a1 = child(&block0, 1);
+        let a2 = child(&a1, 2);
+        match push_all(&blockchain, &[&a2]) {
+            Err(HeaderChainError::NotConnected { parent }) => assert_eq!(parent, a1.id()),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(res) => panic!("unexpected success: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn header_chain_broken() {
+        let (blockchain, block0) = setup();
+        let a1 = child(&block0, 1);
+        let f1 = child(&block0, 2);
+        match push_all(&blockchain, &[&a1, &f1]) {
+            Err(HeaderChainError::Broken { header }) => assert_eq!(header, f1.id()),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(res) => panic!("unexpected success: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn header_chain_leadership_failure() {
+        let (blockchain, block0) = setup();
+        let a1 = child(&block0, 1);
+        // not signed by the BFT leader
+        let a2 = builder(
+            &a1,
+            BlockDate {
+                epoch: 0,
+                slot_id: 2,
+            },
+        )
+        .make_genesis_block();
+        match push_all(&blockchain, &[&a1, &a2]) {
+            Err(HeaderChainError::Leadership { header, .. }) => assert_eq!(header, a2.id()),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(res) => panic!("unexpected success: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn header_chain_is_cut_at_an_unknown_epoch() {
+        let (blockchain, block0) = setup();
+        let a1 = child(&block0, 1);
+        // the leadership of the epoch 1 needs the state of `a1`
+        let a2 = builder(
+            &a1,
+            BlockDate {
+                epoch: 1,
+                slot_id: 0,
+            },
+        )
+        .make_bft_block(&leader().private_key());
+        let (verified, missing) = push_all(&blockchain, &[&a1, &a2]).unwrap();
+        assert!(!verified);
+        assert_eq!(missing, vec![a1.id()]);
+    }
+
+    type FakeService = Result<Vec<Block>, &'static str>;
+
+    fn peer(port: u16, answer: FakeService) -> SyncPeer<FakeService> {
+        SyncPeer::new(SocketAddr::from(([127, 0, 0, 1], port)), answer)
+    }
+
+    fn fetch(
+        peers: Vec<SyncPeer<FakeService>>,
+        index: usize,
+        ids: Vec<HeaderHash>,
+    ) -> Result<Vec<Block>, ()> {
+        let logger = Logger::root(slog::Discard, o!());
+        fetch_with_failover(Arc::new(peers), index, ids, logger, |peer, _| {
+            future::result(peer.service.lock().unwrap().clone())
+        })
+        .wait()
+    }
+
+    fn ids(blocks: &[Block]) -> Vec<HeaderHash> {
+        blocks.iter().map(|block| block.id()).collect()
+    }
+
+    fn range() -> Vec<Block> {
+        let block0 = block0();
+        let a1 = child(&block0, 1);
+        let a2 = child(&a1, 2);
+        vec![a1, a2]
+    }
+
+    #[test]
+    fn fetch_range_from_the_first_peer() {
+        let blocks = range();
+        let peers = vec![peer(1, Ok(blocks.clone())), peer(2, Err("unreachable"))];
+        let fetched = fetch(peers, 0, ids(&blocks)).unwrap();
+        assert_eq!(ids(&fetched), ids(&blocks));
+    }
+
+    #[test]
+    fn fetch_range_fails_over_a_failed_request() {
+        let blocks = range();
+        let peers = vec![peer(1, Err("request failed")), peer(2, Ok(blocks.clone()))];
+        let fetched = fetch(peers, 0, ids(&blocks)).unwrap();
+        assert_eq!(ids(&fetched), ids(&blocks));
+    }
+
+    #[test]
+    fn fetch_range_fails_over_unexpected_blocks() {
+        let blocks = range();
+        let peers = vec![
+            peer(1, Ok(blocks[..1].to_vec())),
+            peer(2, Ok(blocks.clone())),
+        ];
+        let fetched = fetch(peers, 0, ids(&blocks)).unwrap();
+        assert_eq!(ids(&fetched), ids(&blocks));
+    }
+
+    #[test]
+    fn fetch_range_wraps_around_the_peers() {
+        let blocks = range();
+        let peers = vec![peer(1, Ok(blocks.clone())), peer(2, Err("request failed"))];
+        let fetched = fetch(peers, 1, ids(&blocks)).unwrap();
+        assert_eq!(ids(&fetched), ids(&blocks));
+    }
+
+    #[test]
+    fn fetch_range_fails_when_no_peer_has_the_blocks() {
+        let blocks = range();
+        let peers = vec![peer(1, Err("request failed")), peer(2, Ok(Vec::new()))];
+        assert!(fetch(peers, 0, ids(&blocks)).is_err());
+    }
+}
@@ -0,0 +1,222 @@
+//! Progress of the synchronization, reported through the REST API so
+//! the operators can follow the bootstrap of the node.
+
+use crate::blockcfg::HeaderHash;
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum SyncStatus {
+    /// the synchronization has not started yet
+    NotStarted,
+    /// the blocks are being downloaded and applied
+    Syncing,
+    /// the blockchain has caught up with the peers
+    Complete,
+    /// the synchronization has been abandoned, the node carries on
+    /// with the blocks it has
+    Failed,
+}
+
+/// a snapshot of the synchronization progress
+#[derive(Clone, Debug)]
+pub struct SyncProgressReport {
+    pub status: SyncStatus,
+    /// chain length of the most recent block applied
+    pub chain_length: u32,
+    /// the tip announced by the peers, if already known
+    pub target_tip: Option<HeaderHash>,
+    pub target_chain_length: Option<u32>,
+    /// average number of blocks applied per second since the
+    /// synchronization started
+    pub blocks_per_sec: f64,
+    /// estimated time before reaching the target tip
+    pub eta: Option<Duration>,
+}
+
+/// shared handle to the synchronization progress, updated by the
+/// synchronization and read by the REST API.
+#[derive(Clone, Default)]
+pub struct SyncProgress {
+    state: Arc<Mutex<ProgressState>>,
+}
+
+struct ProgressState {
+    status: SyncStatus,
+    started: Option<(Instant, u32)>,
+    chain_length: u32,
+    target: Option<(HeaderHash, u32)>,
+}
+
+impl Default for ProgressState {
+    fn default() -> Self {
+        ProgressState {
+            status: SyncStatus::NotStarted,
+            started: None,
+            chain_length: 0,
+            target: None,
+        }
+    }
+}
+
+impl SyncProgress {
+    /// mark the synchronization as started (or resumed) from the block
+    /// of the given chain length
+    pub fn start(&self, chain_length: u32) {
+        let mut state = self.state.lock().unwrap();
+        state.status = SyncStatus::Syncing;
+        state.chain_length = chain_length;
+        if state.started.is_none() {
+            state.started = Some((Instant::now(), chain_length));
+        }
+    }
+
+    pub fn set_target(&self, tip: HeaderHash, chain_length: u32) {
+        self.state.lock().unwrap().target = Some((tip, chain_length));
+    }
+
+    pub fn set_chain_length(&self, chain_length: u32) {
+        self.state.lock().unwrap().chain_length = chain_length;
+    }
+
+    pub fn complete(&self) {
+        self.state.lock().unwrap().status = SyncStatus::Complete;
+    }
+
+    pub fn fail(&self) {
+        self.state.lock().unwrap().status = SyncStatus::Failed;
+    }
+
+    pub fn report(&self) -> SyncProgressReport {
+        self.report_at(Instant::now())
+    }
+
+    fn report_at(&self, now: Instant) -> SyncProgressReport {
+        let state = self.state.lock().unwrap();
+
+        let blocks_per_sec = match state.started {
+            None => 0.0,
+            Some((started_at, from)) => {
+                let elapsed = now.duration_since(started_at);
+                let elapsed =
+                    elapsed.as_secs() as f64 + f64::from(elapsed.subsec_millis()) / 1000.0;
+                if elapsed > 0.0 {
+                    f64::from(state.chain_length.saturating_sub(from)) / elapsed
+                } else {
+                    0.0
+                }
+            }
+        };
+
+        let eta = match state.target {
+            Some((_, target)) if state.status == SyncStatus::Syncing && blocks_per_sec > 0.0 => {
+                let remaining = f64::from(target.saturating_sub(state.chain_length));
+                Some(Duration::from_secs((remaining / blocks_per_sec) as u64))
+            }
+            _ => None,
+        };
+
+        SyncProgressReport {
+            status: state.status,
+            chain_length: state.chain_length,
+            target_tip: state.target.map(|(tip, _)| tip),
+            target_chain_length: state.target.map(|(_, chain_length)| chain_length),
+            blocks_per_sec,
+            eta,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_rate(report: &SyncProgressReport, blocks_per_sec: f64) {
+        assert!((report.blocks_per_sec - blocks_per_sec).abs() < 1e-9);
+    }
+
+    fn started_at(progress: &SyncProgress) -> Instant {
+        progress.state.lock().unwrap().started.unwrap().0
+    }
+
+    #[test]
+    fn status_follows_the_synchronization() {
+        let progress = SyncProgress::default();
+        assert_eq!(progress.report().status, SyncStatus::NotStarted);
+        progress.start(0);
+        assert_eq!(progress.report().status, SyncStatus::Syncing);
+        progress.complete();
+        assert_eq!(progress.report().status, SyncStatus::Complete);
+        progress.start(0);
+        progress.fail();
+        assert_eq!(progress.report().status, SyncStatus::Failed);
+    }
+
+    #[test]
+    fn report_before_the_start() {
+        let report = SyncProgress::default().report();
+        assert_eq!(report.chain_length, 0);
+        assert_eq!(report.target_tip, None);
+        assert_eq!(report.target_chain_length, None);
+        assert_rate(&report, 0.0);
+        assert_eq!(report.eta, None);
+    }
+
+    #[test]
+    fn rate_and_eta_of_the_synchronization() {
+        let progress = SyncProgress::default();
+        let tip = HeaderHash::hash_bytes(&[1]);
+        progress.start(100);
+        progress.set_target(tip, 1100);
+        progress.set_chain_length(300);
+
+        let report = progress.report_at(started_at(&progress) + Duration::from_secs(10));
+        assert_eq!(report.chain_length, 300);
+        assert_eq!(report.target_tip, Some(tip));
+        assert_eq!(report.target_chain_length, Some(1100));
+        assert_rate(&report, 20.0);
+        assert_eq!(report.eta, Some(Duration::from_secs(40)));
+    }
+
+    #[test]
+    fn rate_is_measured_from_the_first_start() {
+        let progress = SyncProgress::default();
+        progress.start(100);
+        let started = started_at(&progress);
+        progress.fail();
+        // resuming does not reset the measure
+        progress.start(200);
+        assert_eq!(started_at(&progress), started);
+
+        let report = progress.report_at(started + Duration::from_secs(50));
+        assert_rate(&report, 2.0);
+    }
+
+    #[test]
+    fn no_eta_once_the_synchronization_is_over() {
+        let progress = SyncProgress::default();
+        progress.start(0);
+        progress.set_target(HeaderHash::hash_bytes(&[1]), 1000);
+        progress.set_chain_length(500);
+        let later = started_at(&progress) + Duration::from_secs(10);
+        assert!(progress.report_at(later).eta.is_some());
+
+        progress.complete();
+        assert_eq!(progress.report_at(later).eta, None);
+        progress.fail();
+        assert_eq!(progress.report_at(later).eta, None);
+    }
+
+    #[test]
+    fn no_eta_without_a_target() {
+        let progress = SyncProgress::default();
+        progress.start(0);
+        progress.set_chain_length(500);
+        let report = progress.report_at(started_at(&progress) + Duration::from_secs(10));
+        assert_rate(&report, 50.0);
+        assert_eq!(report.eta, None);
+    }
+}
@@ -8,7 +8,9 @@ pub use self::server::{Error, Server};
 
 use crate::blockchain::BlockchainR;
 use crate::fragment::Logs;
+use crate::network::sync::SyncProgress;
 use crate::settings::start::{Error as ConfigError, Rest};
+use slog::Logger;
 use std::sync::{Arc, Mutex};
 
 pub struct Context {
@@ -16,6 +18,8 @@ pub struct Context {
     pub blockchain: BlockchainR,
     pub transaction_task: v0::message::post::Task,
     pub logs: Logs,
+    pub sync_progress: SyncProgress,
+    pub logger: Logger,
 }
 
 pub fn start_rest_server(config: &Rest, context: Context) -> Result<Server, ConfigError> {
@@ -27,9 +31,13 @@ pub fn start_rest_server(config: &Rest, context: Context) -> Result<Server, Conf
     Server::builder(config.pkcs12.clone(), config.listen.clone(), prefix)
         .add_handler(v0::account::create_handler(context.blockchain.clone()))
         .add_handler(v0::block::create_handler(context.blockchain.clone()))
+        .add_handler(v0::node::bootstrap::create_handler(context.sync_progress))
         .add_handler(v0::node::stats::create_handler(context.stats_counter))
         .add_handler(v0::tip::create_handler(context.blockchain.clone()))
-        .add_handler(v0::message::post::create_handler(context.transaction_task))
+        .add_handler(v0::message::post::create_handler(
+            context.transaction_task,
+            context.logger,
+        ))
         .add_handler(v0::message::logs::create_handler(Arc::new(Mutex::new(
             context.logs,
         ))))
@@ -1,39 +1,82 @@
 use crate::fragment;
-use crate::intercom::TransactionMsg;
+use crate::intercom::{self, unary_reply, ReplyFuture, TransactionMsg};
 use crate::utils::async_msg::MessageBox;
-use actix_web::error::ErrorBadRequest;
+use actix_web::error::{
+    ErrorBadRequest, ErrorConflict, ErrorInternalServerError, ErrorServiceUnavailable,
+    ErrorTooManyRequests,
+};
 use actix_web::{App, Error as ActixError, HttpMessage, HttpRequest, Responder};
 use bytes::IntoBuf;
 use chain_core::property::Deserialize;
 use chain_impl_mockchain::message::Message;
 use futures::Future;
+use network_core::error::Code;
+use slog::Logger;
 use std::sync::{Arc, Mutex};
 
 pub type Task = Arc<Mutex<MessageBox<TransactionMsg>>>;
 
+#[derive(Clone)]
+pub struct Context {
+    transaction_task: Task,
+    logger: Logger,
+}
+
 pub fn create_handler(
     transaction_task: Task,
-) -> impl Fn(&str) -> App<Task> + Send + Sync + Clone + 'static {
+    logger: Logger,
+) -> impl Fn(&str) -> App<Context> + Send + Sync + Clone + 'static {
+    let context = Context {
+        transaction_task,
+        logger,
+    };
     move |prefix: &str| {
         let app_prefix = format!("{}/v0/message", prefix);
-        App::with_state(transaction_task.clone())
+        App::with_state(context.clone())
             .prefix(app_prefix)
             .resource("", |r| r.post().a(handle_request))
     }
 }
 
 fn handle_request(
-    request: &HttpRequest<Task>,
+    request: &HttpRequest<Context>,
 ) -> impl Future<Item = impl Responder + 'static, Error = impl Into<ActixError> + 'static> + 'static
 {
-    let sender = request.state().clone();
-    request.body().map(move |message| -> Result<_, ActixError> {
-        let msg = Message::deserialize(message.into_buf()).map_err(|e| {
-            println!("{}", e);
-            ErrorBadRequest(e)
-        })?;
-        let msg = TransactionMsg::SendTransaction(fragment::Origin::Rest, vec![msg]);
-        sender.lock().unwrap().try_send(msg).unwrap();
-        Ok("")
-    })
+    let context = request.state().clone();
+    let origin = fragment::Origin::Rest {
+        addr: request.peer_addr(),
+    };
+    request
+        .body()
+        .map_err(ActixError::from)
+        .and_then(move |message| -> Result<_, ActixError> {
+            let msg = Message::deserialize(message.into_buf()).map_err(ErrorBadRequest)?;
+            let (reply_handle, reply_future) = unary_reply(context.logger.clone());
+            let msg = TransactionMsg::SendTransaction(origin, vec![msg], Some(reply_handle));
+            context
+                .transaction_task
+                .lock()
+                .unwrap()
+                .try_send(msg)
+                .map_err(|error| {
+                    if error.is_full() {
+                        // the fragment task is busy, the client may retry
+                        ErrorServiceUnavailable("the node is busy, try again later")
+                    } else {
+                        ErrorInternalServerError("the fragment task is not running")
+                    }
+                })?;
+            Ok(reply_future)
+        })
+        .and_then(|reply_future: ReplyFuture<(), intercom::Error>| {
+            reply_future.map_err(|error| match error.code() {
+                // the fragment conflicts with a pending fragment
+                Code::FailedPrecondition => ErrorConflict(error),
+                // the client sends fragments too fast
+                Code::ResourceExhausted => ErrorTooManyRequests(error),
+                // the fragment could not be added to the full pool
+                _ => ErrorServiceUnavailable(error),
+            })
+        })
+        .map(|()| "")
 }
@@ -0,0 +1,25 @@
+use crate::network::sync::SyncProgress;
+use actix_web::{App, Json, Responder, State};
+
+pub fn create_handler(
+    progress: SyncProgress,
+) -> impl Fn(&str) -> App<SyncProgress> + Send + Sync + Clone + 'static {
+    move |prefix: &str| {
+        let app_prefix = format!("{}/v0/node/bootstrap", prefix);
+        App::with_state(progress.clone())
+            .prefix(app_prefix)
+            .resource("", |r| r.get().with(handle_request))
+    }
+}
+
+fn handle_request(progress: State<SyncProgress>) -> impl Responder {
+    let report = progress.report();
+    Json(json!({
+        "status": report.status,
+        "chainLength": report.chain_length,
+        "targetTip": report.target_tip.map(|tip| tip.to_string()),
+        "targetChainLength": report.target_chain_length,
+        "blocksPerSec": report.blocks_per_sec,
+        "etaSec": report.eta.map(|eta| eta.as_secs()),
+    }))
+}
@@ -19,6 +19,12 @@ fn handle_request(stats: State<StatsCounter>) -> impl Responder {
     Json(json!({
         "txRecvCnt": stats.get_tx_recv_cnt(),
         "blockRecvCnt": stats.get_block_recv_cnt(),
+        "unconnectedBlockCnt": stats.get_unconnected_block_cnt(),
+        "unconnectedBlockExpiredCnt": stats.get_unconnected_block_expired_cnt(),
+        "unconnectedBlockEvictedCnt": stats.get_unconnected_block_evicted_cnt(),
+        "unconnectedBlockRejectedCnt": stats.get_unconnected_block_rejected_cnt(),
+        "unconnectedBlockConnectedCnt": stats.get_unconnected_block_connected_cnt(),
+        "peerRefusedCnt": stats.get_peer_refused_cnt(),
         "uptime": stats.get_uptime_sec(),
     }))
 }
@@ -1,3 +1,4 @@
+use crate::blockchain::UnconnectedBlocksStats;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::Instant;
@@ -11,6 +12,12 @@ pub struct StatsCounter {
 struct StatsCounterImpl {
     tx_recv_cnt: AtomicUsize,
     block_recv_cnt: AtomicUsize,
+    unconnected_block_cnt: AtomicUsize,
+    unconnected_block_expired_cnt: AtomicUsize,
+    unconnected_block_evicted_cnt: AtomicUsize,
+    unconnected_block_rejected_cnt: AtomicUsize,
+    unconnected_block_connected_cnt: AtomicUsize,
+    peer_refused_cnt: AtomicUsize,
     start_time: Instant,
 }
 
@@ -19,6 +26,12 @@ impl Default for StatsCounterImpl {
         Self {
             tx_recv_cnt: AtomicUsize::default(),
             block_recv_cnt: AtomicUsize::default(),
+            unconnected_block_cnt: AtomicUsize::default(),
+            unconnected_block_expired_cnt: AtomicUsize::default(),
+            unconnected_block_evicted_cnt: AtomicUsize::default(),
+            unconnected_block_rejected_cnt: AtomicUsize::default(),
+            unconnected_block_connected_cnt: AtomicUsize::default(),
+            peer_refused_cnt: AtomicUsize::default(),
             start_time: Instant::now(),
         }
     }
@@ -43,6 +56,62 @@ impl StatsCounter {
         self.stats.block_recv_cnt.load(Ordering::Relaxed) as u64
     }
 
+    pub fn set_unconnected_blocks(&self, stats: UnconnectedBlocksStats) {
+        self.stats
+            .unconnected_block_cnt
+            .store(stats.blocks, Ordering::Relaxed);
+        self.stats
+            .unconnected_block_expired_cnt
+            .store(stats.expired as usize, Ordering::Relaxed);
+        self.stats
+            .unconnected_block_evicted_cnt
+            .store(stats.evicted as usize, Ordering::Relaxed);
+        self.stats
+            .unconnected_block_rejected_cnt
+            .store(stats.rejected as usize, Ordering::Relaxed);
+        self.stats
+            .unconnected_block_connected_cnt
+            .store(stats.connected as usize, Ordering::Relaxed);
+    }
+
+    pub fn get_unconnected_block_cnt(&self) -> u64 {
+        self.stats.unconnected_block_cnt.load(Ordering::Relaxed) as u64
+    }
+
+    pub fn get_unconnected_block_expired_cnt(&self) -> u64 {
+        self.stats
+            .unconnected_block_expired_cnt
+            .load(Ordering::Relaxed) as u64
+    }
+
+    pub fn get_unconnected_block_evicted_cnt(&self) -> u64 {
+        self.stats
+            .unconnected_block_evicted_cnt
+            .load(Ordering::Relaxed) as u64
+    }
+
+    pub fn get_unconnected_block_rejected_cnt(&self) -> u64 {
+        self.stats
+            .unconnected_block_rejected_cnt
+            .load(Ordering::Relaxed) as u64
+    }
+
+    pub fn get_unconnected_block_connected_cnt(&self) -> u64 {
+        self.stats
+            .unconnected_block_connected_cnt
+            .load(Ordering::Relaxed) as u64
+    }
+
+    pub fn add_peer_refused_cnt(&self, count: usize) {
+        self.stats
+            .peer_refused_cnt
+            .fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn get_peer_refused_cnt(&self) -> u64 {
+        self.stats.peer_refused_cnt.load(Ordering::Relaxed) as u64
+    }
+
     pub fn get_uptime_sec(&self) -> u64 {
         self.stats.start_time.elapsed().as_secs()
     }
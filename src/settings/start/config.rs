@@ -1,8 +1,10 @@
 use crate::{
+    fragment::{selection::SelectionAlgorithm, ConflictPolicy, RateLimit},
     network::p2p::topology::{NodeId, NEW_BLOCKS_TOPIC, NEW_MESSAGES_TOPIC},
     settings::logging::{LogFormat, LogOutput},
 };
 
+use jormungandr_utils::serde::SerdeAsString;
 use poldercast;
 use serde::{de::Visitor, Deserialize, Deserializer, Serialize, Serializer};
 
@@ -17,6 +19,64 @@ pub struct Config {
     pub logger: Option<ConfigLogSettings>,
     pub rest: Option<Rest>,
     pub peer_2_peer: P2pConfig,
+    pub blockchain: Option<BlockchainConfig>,
+    pub mempool: Option<MempoolConfig>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlockchainConfig {
+    /// how far in the future (compared to the local clock) a block
+    /// may be dated before it is rejected. This gives some leeway to
+    /// the peers whose clocks are not exactly synchronized with ours.
+    pub clock_skew_tolerance: Option<SerdeAsString<humantime::Duration>>,
+    /// how long a block whose parent is not known is kept, waiting for
+    /// the parent to arrive
+    pub unconnected_blocks_ttl: Option<SerdeAsString<humantime::Duration>>,
+    /// the maximum number of blocks whose parent is not known kept in
+    /// memory
+    pub max_unconnected_blocks: Option<usize>,
+    /// the maximum number of blocks whose parent is not known kept in
+    /// memory for the same peer
+    pub max_unconnected_blocks_per_peer: Option<usize>,
+    /// how many blocks behind the tip a block may be before it is
+    /// rejected, the `epoch_stability_depth` of the block0 if not set
+    pub stability_depth: Option<u32>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MempoolConfig {
+    /// the algorithm selecting the fragments of the blocks created
+    /// by the node
+    pub selection_algorithm: Option<SelectionAlgorithm>,
+    /// the maximum size, in bytes, of the fragments of a block created
+    /// by the node
+    pub max_block_size: Option<usize>,
+    /// the maximum number of fragments in the pool
+    pub max_entries: Option<usize>,
+    /// the maximum total size, in bytes, of the fragments in the pool
+    pub max_size: Option<usize>,
+    /// the maximum number of fragments in the pool received from the
+    /// same origin
+    pub max_entries_per_origin: Option<usize>,
+    /// what to do with a fragment spending inputs already spent by
+    /// pending fragments
+    pub conflict_policy: Option<ConflictPolicy>,
+    /// how long a fragment stays in the pool before it is dropped
+    pub fragment_ttl: Option<SerdeAsString<humantime::Duration>>,
+    /// how long the log of a fragment is kept after its last update
+    pub log_ttl: Option<SerdeAsString<humantime::Duration>>,
+    /// the interval between two removals of the expired fragments and
+    /// logs
+    pub garbage_collection_interval: Option<SerdeAsString<humantime::Duration>>,
+    /// the number of fragment messages waiting to be processed before
+    /// the senders are told to wait
+    pub fragment_queue_len: Option<usize>,
+    /// the number of messages to the network task waiting to be
+    /// processed before the senders are told to wait
+    pub network_queue_len: Option<usize>,
+    /// the rate at which each sender (a peer or a REST client) may
+    /// submit fragments
+    pub rate_limit: Option<RateLimit>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -4,12 +4,56 @@ pub mod network;
 pub use self::config::Rest;
 use self::config::{Config, ConfigLogSettings};
 use self::network::Protocol;
+use crate::blockchain::UnconnectedBlocksLimits;
+use crate::fragment::{selection::SelectionAlgorithm, ConflictPolicy, RateLimit};
 use crate::rest::Error as RestError;
 use crate::settings::logging::{self, LogSettings};
 use crate::settings::{command_arguments::*, Block0Info};
 use slog::Logger;
 
-use std::{collections::BTreeMap, fs::File, path::PathBuf};
+use std::{collections::BTreeMap, fs::File, path::PathBuf, time::Duration};
+
+/// default tolerance to the blocks dated in the future, see
+/// [`Settings::clock_skew_tolerance`](./struct.Settings.html#structfield.clock_skew_tolerance)
+const DEFAULT_CLOCK_SKEW_TOLERANCE: Duration = Duration::from_secs(10);
+
+/// default capacity of the pool of the blocks whose parent is not
+/// known, see
+/// [`Settings::unconnected_blocks`](./struct.Settings.html#structfield.unconnected_blocks)
+const DEFAULT_UNCONNECTED_BLOCKS_LIMITS: UnconnectedBlocksLimits = UnconnectedBlocksLimits {
+    max_age: Duration::from_secs(600),
+    max_blocks: 1024,
+    max_blocks_per_peer: 128,
+};
+
+/// default maximum size of the fragments of a block, see
+/// [`Mempool::max_block_size`](./struct.Mempool.html#structfield.max_block_size)
+const DEFAULT_MAX_BLOCK_SIZE: usize = 256 * 1024;
+
+/// default capacity of the fragment pool, see
+/// [`Mempool`](./struct.Mempool.html)
+const DEFAULT_MAX_ENTRIES: usize = 10_000;
+const DEFAULT_MAX_SIZE: usize = 32 * 1024 * 1024;
+const DEFAULT_MAX_ENTRIES_PER_ORIGIN: usize = 5_000;
+
+/// default lifetimes of the fragments in the pool and of their logs,
+/// and interval between two garbage collections, see
+/// [`Mempool`](./struct.Mempool.html)
+const DEFAULT_FRAGMENT_TTL: Duration = Duration::from_secs(3600);
+const DEFAULT_LOG_TTL: Duration = Duration::from_secs(3600 * 2);
+const DEFAULT_GARBAGE_COLLECTION_INTERVAL: Duration = Duration::from_secs(3600 / 4);
+
+/// default lengths of the message queues of the fragment and network
+/// tasks, see [`Mempool`](./struct.Mempool.html)
+const DEFAULT_FRAGMENT_QUEUE_LEN: usize = 1024;
+const DEFAULT_NETWORK_QUEUE_LEN: usize = 32;
+
+/// default rate at which a sender may submit fragments, see
+/// [`Mempool::rate_limit`](./struct.Mempool.html#structfield.rate_limit)
+const DEFAULT_RATE_LIMIT: RateLimit = RateLimit {
+    fragments_per_second: 10,
+    burst: 100,
+};
 
 custom_error! {pub Error
    ConfigIo { source: std::io::Error } = "Cannot read the node configuration file: {source}",
@@ -17,6 +61,9 @@ custom_error! {pub Error
    Rest { source: RestError } = "The Rest configuration is invalid: {source}",
    ExpectedBlock0Info = "Cannot start the node without the information to retrieve the genesis block",
    TooMuchBlock0Info = "Use only `--genesis-block-hash' or `--genesis-block'",
+   BlockchainZero { field: &'static str } = "The blockchain setting `{field}' must be greater than zero",
+   MempoolZero { field: &'static str } = "The mempool setting `{field}' must be greater than zero",
+   MempoolLogTtl = "The mempool setting `log_ttl' must not be shorter than `fragment_ttl'",
 }
 
 /// Overall Settings for node
@@ -26,6 +73,87 @@ pub struct Settings {
     pub block_0: Block0Info,
     pub leadership: Vec<PathBuf>,
     pub rest: Option<Rest>,
+    /// blocks dated further in the future than the local time plus
+    /// this tolerance are rejected
+    pub clock_skew_tolerance: Duration,
+    /// the capacity of the pool of the blocks whose parent is not known
+    pub unconnected_blocks: UnconnectedBlocksLimits,
+    /// how many blocks behind the tip a block may be before it is
+    /// rejected, `None` to use the `epoch_stability_depth` of the block0
+    pub stability_depth: Option<u32>,
+    pub mempool: Mempool,
+}
+
+/// settings of the fragment pool
+#[derive(Debug, Clone)]
+pub struct Mempool {
+    /// the algorithm selecting the fragments of the blocks created
+    /// by the node
+    pub selection_algorithm: SelectionAlgorithm,
+    /// the maximum size, in bytes, of the fragments of a block created
+    /// by the node. The maximum number of fragments is set by the
+    /// blockchain parameters.
+    pub max_block_size: usize,
+    /// the maximum number of fragments in the pool
+    pub max_entries: usize,
+    /// the maximum total size, in bytes, of the fragments in the pool
+    pub max_size: usize,
+    /// the maximum number of fragments in the pool received from the
+    /// same origin, so a single source cannot fill the pool
+    pub max_entries_per_origin: usize,
+    /// what to do with a fragment spending inputs already spent by
+    /// pending fragments
+    pub conflict_policy: ConflictPolicy,
+    /// how long a fragment stays in the pool before it is dropped
+    pub fragment_ttl: Duration,
+    /// how long the log of a fragment is kept after its last update,
+    /// at least as long as the fragment stays in the pool
+    pub log_ttl: Duration,
+    /// the interval between two removals of the expired fragments and
+    /// logs, the pool is saved at the same interval
+    pub garbage_collection_interval: Duration,
+    /// the length of the message queue of the fragment task
+    pub fragment_queue_len: usize,
+    /// the length of the message queue of the network task
+    pub network_queue_len: usize,
+    /// the rate at which each sender may submit fragments, the
+    /// fragments exceeding it are dropped
+    pub rate_limit: RateLimit,
+}
+
+impl Mempool {
+    fn validate(&self) -> Result<(), Error> {
+        let zero = Duration::from_secs(0);
+        let checks = [
+            ("max_block_size", self.max_block_size == 0),
+            ("max_entries", self.max_entries == 0),
+            ("max_size", self.max_size == 0),
+            ("max_entries_per_origin", self.max_entries_per_origin == 0),
+            ("fragment_ttl", self.fragment_ttl == zero),
+            ("log_ttl", self.log_ttl == zero),
+            (
+                "garbage_collection_interval",
+                self.garbage_collection_interval == zero,
+            ),
+            ("fragment_queue_len", self.fragment_queue_len == 0),
+            ("network_queue_len", self.network_queue_len == 0),
+            (
+                "rate_limit.fragments_per_second",
+                self.rate_limit.fragments_per_second == 0,
+            ),
+            ("rate_limit.burst", self.rate_limit.burst == 0),
+        ];
+        if let Some((field, _)) = checks.iter().find(|(_, is_zero)| *is_zero) {
+            return Err(Error::MempoolZero { field: *field });
+        }
+
+        // the status of a pending fragment must be known for as long
+        // as the fragment is in the pool
+        if self.log_ttl < self.fragment_ttl {
+            return Err(Error::MempoolLogTtl);
+        }
+        Ok(())
+    }
 }
 
 pub struct RawSettings {
@@ -108,16 +236,103 @@ impl RawSettings {
             (None, Some(hash)) => Block0Info::Hash(hash.clone()),
         };
 
+        let (clock_skew_tolerance, unconnected_blocks, stability_depth) = match config.blockchain {
+            None => (
+                DEFAULT_CLOCK_SKEW_TOLERANCE,
+                DEFAULT_UNCONNECTED_BLOCKS_LIMITS,
+                None,
+            ),
+            Some(blockchain) => {
+                let default = DEFAULT_UNCONNECTED_BLOCKS_LIMITS;
+                let limits = UnconnectedBlocksLimits {
+                    max_age: blockchain
+                        .unconnected_blocks_ttl
+                        .map(|ttl| *ttl.0)
+                        .unwrap_or(default.max_age),
+                    max_blocks: blockchain
+                        .max_unconnected_blocks
+                        .unwrap_or(default.max_blocks),
+                    max_blocks_per_peer: blockchain
+                        .max_unconnected_blocks_per_peer
+                        .unwrap_or(default.max_blocks_per_peer),
+                };
+                let tolerance = blockchain
+                    .clock_skew_tolerance
+                    .map(|tolerance| *tolerance.0)
+                    .unwrap_or(DEFAULT_CLOCK_SKEW_TOLERANCE);
+                (tolerance, limits, blockchain.stability_depth)
+            }
+        };
+        validate_unconnected_blocks(&unconnected_blocks)?;
+        if stability_depth == Some(0) {
+            return Err(Error::BlockchainZero {
+                field: "stability_depth",
+            });
+        }
+
+        let mempool = config.mempool.unwrap_or_default();
+        let mempool = Mempool {
+            selection_algorithm: mempool
+                .selection_algorithm
+                .unwrap_or(SelectionAlgorithm::OldestFirst),
+            max_block_size: mempool.max_block_size.unwrap_or(DEFAULT_MAX_BLOCK_SIZE),
+            max_entries: mempool.max_entries.unwrap_or(DEFAULT_MAX_ENTRIES),
+            max_size: mempool.max_size.unwrap_or(DEFAULT_MAX_SIZE),
+            max_entries_per_origin: mempool
+                .max_entries_per_origin
+                .unwrap_or(DEFAULT_MAX_ENTRIES_PER_ORIGIN),
+            conflict_policy: mempool.conflict_policy.unwrap_or(ConflictPolicy::Reject),
+            fragment_ttl: mempool
+                .fragment_ttl
+                .map(|ttl| *ttl.0)
+                .unwrap_or(DEFAULT_FRAGMENT_TTL),
+            log_ttl: mempool.log_ttl.map(|ttl| *ttl.0).unwrap_or(DEFAULT_LOG_TTL),
+            garbage_collection_interval: mempool
+                .garbage_collection_interval
+                .map(|interval| *interval.0)
+                .unwrap_or(DEFAULT_GARBAGE_COLLECTION_INTERVAL),
+            fragment_queue_len: mempool
+                .fragment_queue_len
+                .unwrap_or(DEFAULT_FRAGMENT_QUEUE_LEN),
+            network_queue_len: mempool
+                .network_queue_len
+                .unwrap_or(DEFAULT_NETWORK_QUEUE_LEN),
+            rate_limit: mempool.rate_limit.unwrap_or(DEFAULT_RATE_LIMIT),
+        };
+        mempool.validate()?;
+
         Ok(Settings {
             storage: storage,
             block_0: block0_info,
             network: network,
             leadership,
             rest: config.rest,
+            clock_skew_tolerance,
+            unconnected_blocks,
+            stability_depth,
+            mempool,
         })
     }
 }
 
+fn validate_unconnected_blocks(limits: &UnconnectedBlocksLimits) -> Result<(), Error> {
+    let checks = [
+        (
+            "unconnected_blocks_ttl",
+            limits.max_age == Duration::from_secs(0),
+        ),
+        ("max_unconnected_blocks", limits.max_blocks == 0),
+        (
+            "max_unconnected_blocks_per_peer",
+            limits.max_blocks_per_peer == 0,
+        ),
+    ];
+    match checks.iter().find(|(_, is_zero)| *is_zero) {
+        Some((field, _)) => Err(Error::BlockchainZero { field: *field }),
+        None => Ok(()),
+    }
+}
+
 fn generate_network(
     _command_arguments: &StartArguments,
     config: &Config,
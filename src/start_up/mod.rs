@@ -3,7 +3,7 @@ mod error;
 pub use self::error::{Error, ErrorKind};
 use crate::{
     blockcfg::Block,
-    blockchain::{Blockchain, BlockchainR},
+    blockchain::{Blockchain, BlockchainR, Snapshots},
     leadership::EpochParameters,
     network,
     settings::start::Settings,
@@ -82,13 +82,27 @@ pub fn prepare_block_0(
     }
 }
 
+/// load the blockchain state from the storage, if the node has a storage
+/// directory, the ledger snapshots saved in it are used to speed up the
+/// restoration of the state.
 pub fn load_blockchain(
+    settings: &Settings,
     block0: Block,
     storage: NodeStorage,
     epoch_event: mpsc::Sender<EpochParameters>,
     logger: &Logger,
 ) -> Result<BlockchainR, Error> {
-    let mut blockchain_data = Blockchain::load(block0, storage, epoch_event, logger)?;
+    let snapshots = settings.storage.as_ref().map(Snapshots::new);
+    let mut blockchain_data = Blockchain::load(
+        block0,
+        storage,
+        snapshots,
+        settings.clock_skew_tolerance,
+        settings.unconnected_blocks,
+        settings.stability_depth,
+        epoch_event,
+        logger,
+    )?;
     blockchain_data.initial()?;
     Ok(blockchain_data.into())
 }
@@ -2,7 +2,7 @@
 //! asynchronous reading.
 
 use futures::prelude::*;
-use futures::sync::mpsc::{self, Receiver, Sender, TrySendError};
+use futures::sync::mpsc::{self, Receiver, SendError, Sender, TrySendError};
 
 /// The output end of an in-memory FIFO channel.
 pub struct MessageBox<Msg>(Sender<Msg>);
@@ -35,6 +35,21 @@ impl<Msg> MessageBox<Msg> {
     }
 }
 
+/// Sending through the `Sink` interface waits for room in the channel
+/// instead of failing when it is full.
+impl<Msg> Sink for MessageBox<Msg> {
+    type SinkItem = Msg;
+    type SinkError = SendError<Msg>;
+
+    fn start_send(&mut self, a: Msg) -> StartSend<Msg, SendError<Msg>> {
+        self.0.start_send(a)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), SendError<Msg>> {
+        self.0.poll_complete()
+    }
+}
+
 impl<Msg> Stream for MessageQueue<Msg> {
     type Item = Msg;
     type Error = ();
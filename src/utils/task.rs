@@ -217,6 +217,20 @@ impl Services {
         msg_box
     }
 
+    /// stop the services running in a tokio runtime, their futures are
+    /// dropped. This function blocks until the runtimes are shut down.
+    ///
+    /// The services running in a thread are not waited for, they stop
+    /// with the process.
+    ///
+    pub fn shutdown(self) {
+        for service in self.services {
+            if let Inner::Tokio { runtime } = service.inner {
+                runtime.shutdown_now().wait().unwrap()
+            }
+        }
+    }
+
     /// join on all the started services. this function will block
     /// until all services return
     ///
@@ -455,6 +455,12 @@ impl<T: Clone> Clone for SerdeAsString<T> {
     }
 }
 
+impl<T: fmt::Debug> fmt::Debug for SerdeAsString<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
 #[derive(Deserialize, Serialize)]
 #[serde(
     bound(deserialize = "T: Bech32 + SerdeExpected", serialize = "T: Bech32"),
@@ -545,6 +551,10 @@ pub trait SerdeExpected {
     const EXPECTED: &'static str;
 }
 
+impl SerdeExpected for humantime::Duration {
+    const EXPECTED: &'static str = "duration (example: \"30s\" or \"1h 30min\")";
+}
+
 impl SerdeExpected for chain_impl_mockchain::milli::Milli {
     const EXPECTED: &'static str = "floating point number in decimal form";
 }